@@ -0,0 +1,171 @@
+//! Every protocol over a range of set sizes and overlaps, so we have numbers to choose one by.
+//!
+//! Before timing anything it prints a table of what one session costs besides time: messages, bytes,
+//! hashes and comparisons, both sides together. Comparisons per element is where `challenge` shows
+//! its follower scanning every hash it has for each query, it grows with the set size where a
+//! lookup table would keep it flat. Criterion then times whole sessions in memory, no transport.
+//!
+//! `cargo bench --bench protocols`, or `cargo bench --bench protocols -- challenge/50%` for some of
+//! them. `challenge` stops at `CHALLENGE_MAX` elements, past that a single session takes minutes
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+use treehopper::challenge::{self, NodeType};
+use treehopper::negotiate::{Hello, ProtocolKind};
+use treehopper::protocol::{LocalRun, Work, run_local};
+use treehopper::simple;
+
+const SIZES: [usize; 5] = [100, 1_000, 10_000, 100_000, 1_000_000];
+/// percent of each side's elements the other also holds
+const OVERLAPS: [usize; 5] = [0, 25, 50, 75, 100];
+/// the follower's lookup is O(n·m), 10^5 would be 10^10 comparisons a session
+const CHALLENGE_MAX: usize = 10_000;
+
+/// Both sides' sets, `size` elements each with the first `overlap` percent shared. `simple` only
+/// matches by position so shared numbers sit at the same place on both sides, `challenge` doesn't
+/// care so its shared strings go in reverse on the follower
+struct Sets {
+    strings: (Vec<String>, Vec<String>),
+    numbers: (Vec<u32>, Vec<u32>),
+}
+
+impl Sets {
+    fn new(size: usize, overlap: usize) -> Sets {
+        let shared = size * overlap / 100;
+        let strings = (
+            (0..size).map(|i| format!("element {i}")).collect(),
+            (0..size)
+                .rev()
+                .map(|i| match i < shared {
+                    true => format!("element {i}"),
+                    false => format!("other {i}"),
+                })
+                .collect(),
+        );
+        let numbers = (
+            (0..size as u32).collect(),
+            (0..size as u32)
+                .map(|i| match (i as usize) < shared {
+                    true => i,
+                    false => i + size as u32,
+                })
+                .collect(),
+        );
+        Sets { strings, numbers }
+    }
+}
+
+/// What one session cost, for the table
+struct Cost {
+    common: usize,
+    messages: usize,
+    bytes: usize,
+    work: Work,
+}
+
+impl Cost {
+    fn of<O: IntoIterator>(run: LocalRun<O>) -> Cost {
+        Cost {
+            common: run.leader.map_or(0, |common| common.into_iter().count()),
+            messages: run.stats.messages,
+            bytes: run.stats.bytes,
+            work: run.work,
+        }
+    }
+}
+
+/// Whether a session of `protocol` at `size` finishes in reasonable time
+fn feasible(protocol: ProtocolKind, size: usize) -> bool {
+    match protocol {
+        ProtocolKind::Simple => true,
+        ProtocolKind::Challenge => size <= CHALLENGE_MAX,
+    }
+}
+
+/// One whole session of `protocol`
+fn session(protocol: ProtocolKind, sets: &Sets) -> Cost {
+    match protocol {
+        ProtocolKind::Simple => {
+            let (ours, theirs) = &sets.numbers;
+            let run = run_local(
+                &mut simple::NodeState::new(ours),
+                &mut simple::NodeState::new(theirs),
+            );
+            Cost::of(run)
+        }
+        ProtocolKind::Challenge => {
+            let (ours, theirs) = &sets.strings;
+            let run = run_local(
+                &mut challenge::Node::new(ours, NodeType::Leader),
+                &mut challenge::Node::new(theirs, NodeType::Follower),
+            );
+            Cost::of(run)
+        }
+    }
+}
+
+fn table() {
+    println!(
+        "{:<10} {:>9} {:>8} {:>9} {:>9} {:>11} {:>9} {:>13} {:>12}",
+        "protocol",
+        "size",
+        "overlap",
+        "common",
+        "messages",
+        "bytes",
+        "hashes",
+        "comparisons",
+        "per element"
+    );
+    for protocol in Hello::supported().protocols {
+        for size in SIZES {
+            for overlap in OVERLAPS {
+                if !feasible(protocol, size) {
+                    println!(
+                        "{:<10} {size:>9} {overlap:>7}% skipped, too slow at this size",
+                        protocol.name()
+                    );
+                    continue;
+                }
+                let cost = session(protocol, &Sets::new(size, overlap));
+                println!(
+                    "{:<10} {size:>9} {overlap:>7}% {:>9} {:>9} {:>11} {:>9} {:>13} {:>12.1}",
+                    protocol.name(),
+                    cost.common,
+                    cost.messages,
+                    cost.bytes,
+                    cost.work.hashes,
+                    cost.work.comparisons,
+                    cost.work.comparisons as f64 / size as f64
+                );
+            }
+        }
+    }
+    println!();
+}
+
+fn protocols(c: &mut Criterion) {
+    table();
+    for protocol in Hello::supported().protocols {
+        let mut group = c.benchmark_group(protocol.name());
+        // a session of a million elements takes a while, ten of them is plenty
+        group.sample_size(10);
+        for size in SIZES.into_iter().filter(|size| feasible(protocol, *size)) {
+            for overlap in OVERLAPS {
+                let sets = Sets::new(size, overlap);
+                group.throughput(Throughput::Elements(size as u64));
+                group.bench_with_input(
+                    BenchmarkId::new(format!("{overlap}%"), size),
+                    &sets,
+                    |b, sets| b.iter(|| black_box(session(protocol, sets))),
+                );
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, protocols);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+//! A fresh challenge node of either role fed whatever messages the fuzzer makes up. Besides not
+//! panicking it has to only ever match elements it holds, send replies the peer can decode, and
+//! stay put once it's finished
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use treehopper::challenge::{Node, NodeMessage, NodeType};
+use treehopper::error::ProtocolError;
+use treehopper::protocol::Message;
+use treehopper::wire::Wire;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    data: Vec<String>,
+    node_type: NodeType,
+    seed: u64,
+    messages: Vec<NodeMessage>,
+}
+
+fn check_reply(reply: &NodeMessage) {
+    assert_eq!(NodeMessage::decode(&reply.encode()).ok().as_ref(), Some(reply));
+}
+
+fuzz_target!(|input: Input| {
+    let mut node = Node::seeded(&input.data, input.node_type, input.seed);
+    if input.node_type == NodeType::Leader {
+        check_reply(&node.start());
+    }
+    for message in input.messages {
+        let hung_up = message.is_terminal();
+        let before = node
+            .outcome()
+            .map(|outcome| outcome.cloned().map_err(Clone::clone));
+        let reply = node.recieve_message(message);
+
+        if let Some(before) = before {
+            assert_eq!(reply, NodeMessage::Fail(ProtocolError::SessionClosed));
+            let after = node.outcome().unwrap();
+            assert_eq!(after.cloned().map_err(Clone::clone), before);
+        }
+        // the peer doesn't read a reply to its own `Done` or `Fail`
+        if !hung_up {
+            check_reply(&reply);
+        }
+        if reply.is_terminal() {
+            assert!(node.is_finished());
+        }
+        assert!(node.common().iter().all(|element| input.data.contains(element)));
+    }
+});
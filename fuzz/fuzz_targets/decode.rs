@@ -0,0 +1,29 @@
+//! Arbitrary bytes into every decoder. Decoding must never panic, and anything that does decode
+//! has to encode back to something that decodes to the same value
+
+#![no_main]
+
+use std::fmt::Debug;
+
+use libfuzzer_sys::fuzz_target;
+use treehopper::challenge::{self, Checkpoint};
+use treehopper::negotiate::{Hello, HelloAck};
+use treehopper::simple;
+use treehopper::transport::Envelope;
+use treehopper::wire::Wire;
+
+fn round_trip<M: Wire + PartialEq + Debug>(bytes: &[u8]) {
+    if let Ok(message) = M::decode(bytes) {
+        assert_eq!(M::decode(&message.encode()).ok(), Some(message));
+    }
+}
+
+fuzz_target!(|bytes: &[u8]| {
+    round_trip::<challenge::NodeMessage>(bytes);
+    round_trip::<simple::NodeMessage>(bytes);
+    round_trip::<Envelope<challenge::NodeMessage>>(bytes);
+    round_trip::<Envelope<simple::NodeMessage>>(bytes);
+    round_trip::<Hello>(bytes);
+    round_trip::<HelloAck>(bytes);
+    round_trip::<Checkpoint>(bytes);
+});
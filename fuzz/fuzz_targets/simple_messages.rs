@@ -0,0 +1,52 @@
+//! Same as `challenge_messages` for a simple node: no panics, replies that decode, nothing matched
+//! that isn't ours, matches bounded by our data, and nothing changes once it's finished
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use treehopper::error::ProtocolError;
+use treehopper::protocol::Message;
+use treehopper::simple::{NodeMessage, NodeState};
+use treehopper::wire::Wire;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    data: Vec<u32>,
+    leads: bool,
+    messages: Vec<NodeMessage>,
+}
+
+fn check_reply(reply: &NodeMessage) {
+    assert_eq!(NodeMessage::decode(&reply.encode()).ok().as_ref(), Some(reply));
+}
+
+fuzz_target!(|input: Input| {
+    let mut node = NodeState::new(&input.data);
+    if input.leads {
+        check_reply(&node.start());
+    }
+    for message in input.messages {
+        let hung_up = message.is_terminal();
+        let before = node
+            .outcome()
+            .map(|outcome| outcome.map(<[u32]>::to_vec).map_err(Clone::clone));
+        let reply = node.receive(message);
+
+        if let Some(before) = before {
+            assert_eq!(reply, NodeMessage::Fail(ProtocolError::SessionClosed));
+            let after = node.outcome().unwrap();
+            assert_eq!(after.map(<[u32]>::to_vec).map_err(Clone::clone), before);
+        }
+        if !hung_up {
+            check_reply(&reply);
+        }
+        if reply.is_terminal() {
+            assert!(node.is_finished());
+        }
+        // simple doesn't hold to a role, so a location can match once answering a query and once
+        // more from a response, but never more than that however much the peer sends
+        assert!(node.common.len() <= 2 * input.data.len());
+        assert!(node.common.iter().all(|value| input.data.contains(value)));
+    }
+});
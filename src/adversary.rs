@@ -0,0 +1,376 @@
+//! Peers that don't play by the rules, for testing that honest nodes hold up against them.
+//!
+//! An evil peer starts from the messages it would send in an ordinary session (`script`, recorded
+//! against a copy of the honest node, so honest nodes have to be deterministic, e.g.
+//! `challenge::Node::seeded`), then a `Tactic` tampers with them at one point: replaying an earlier
+//! message, swapping two, dropping one, forging one, or flooding. `attack` sends the result to
+//! the honest node without ever adapting to its replies, and `assault` tries every tactic at every
+//! point.
+//!
+//! Whatever it's sent an honest node has to end up finished once the evil peer hangs up, if it goes
+//! quiet instead a real transport times out. Once finished its outcome can't change, and it
+//! never claims an element the evil peer doesn't hold. `simple` believes whatever it's told, so
+//! for it `genuine` can only check the element came from its own data
+
+use std::fmt;
+
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::IndexedRandom};
+
+use crate::challenge::{self, ChallengeReponsePair};
+use crate::error::ProtocolError;
+use crate::protocol::{Message, Protocol};
+use crate::simple;
+use crate::transport::Role;
+
+/// How the evil peer tampers with its script at one point
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Tactic {
+    /// send a message from earlier again right after this one
+    Replay,
+    /// swap this message with the next
+    Reorder,
+    /// never send this one
+    Drop,
+    /// send a forgery instead
+    Forge,
+    /// follow this one with this many copies and forgeries of it
+    Flood(usize),
+}
+
+impl Tactic {
+    pub const ALL: [Tactic; 5] = [
+        Tactic::Replay,
+        Tactic::Reorder,
+        Tactic::Drop,
+        Tactic::Forge,
+        Tactic::Flood(16),
+    ];
+}
+
+/// Messages an attacker can make up from one it would have sent
+pub trait Forge: Message {
+    /// Something to send instead of `self`. `script` is everything the attacker sends in an honest
+    /// session, to splice genuine pieces from
+    fn forge(&self, script: &[Self], rng: &mut StdRng) -> Self;
+}
+
+/// How the honest node came out of an attack
+#[derive(Debug)]
+pub struct Attacked<O> {
+    /// `None` if the evil peer went quiet before the node finished
+    pub outcome: Option<Result<O, ProtocolError>>,
+    /// messages the node handled before it finished
+    pub delivered: usize,
+}
+
+/// Every message `evil` sends in an ordinary session with `honest`
+pub fn script<P: Protocol>(honest: &mut P, evil: &mut P, honest_role: Role) -> Vec<P::Message> {
+    let mut script = vec![];
+    let mut to_evil = match honest_role {
+        Role::Leader => honest.start(),
+        Role::Follower => {
+            let first = evil.start();
+            script.push(first.clone());
+            if first.is_terminal() {
+                return script;
+            }
+            honest.receive(first)
+        }
+    };
+    while !to_evil.is_terminal() {
+        let reply = evil.receive(to_evil);
+        script.push(reply.clone());
+        if reply.is_terminal() {
+            break;
+        }
+        to_evil = honest.receive(reply);
+    }
+    script
+}
+
+/// `script` with `tactic` applied at message `at`, or as it is if the script is shorter
+pub fn tamper<M: Forge>(script: &[M], tactic: Tactic, at: usize, rng: &mut StdRng) -> Vec<M> {
+    let mut sent = script.to_vec();
+    if at >= sent.len() {
+        return sent;
+    }
+    match tactic {
+        Tactic::Replay => {
+            let earlier = script[rng.random_range(0..=at)].clone();
+            sent.insert(at + 1, earlier);
+        }
+        Tactic::Reorder if at + 1 < sent.len() => sent.swap(at, at + 1),
+        Tactic::Reorder => {}
+        Tactic::Drop => {
+            sent.remove(at);
+        }
+        Tactic::Forge => sent[at] = script[at].forge(script, rng),
+        Tactic::Flood(count) => {
+            let flood: Vec<M> = (0..count)
+                .map(|i| match i % 2 {
+                    0 => script[at].forge(script, rng),
+                    _ => script[at].clone(),
+                })
+                .collect();
+            sent.splice(at + 1..at + 1, flood);
+        }
+    }
+    sent
+}
+
+/// Send `sent` to `honest` one message at a time, whatever it answers
+pub fn attack<P: Protocol>(
+    honest: &mut P,
+    honest_role: Role,
+    sent: Vec<P::Message>,
+) -> Attacked<P::Output>
+where
+    P::Output: PartialEq + fmt::Debug,
+{
+    if honest_role == Role::Leader {
+        honest.start();
+    }
+    let mut delivered = 0;
+    for message in sent {
+        if honest.is_finished() {
+            // anything after the end is turned away without touching the outcome
+            let before = honest.outcome();
+            honest.receive(message);
+            assert_eq!(
+                honest.outcome(),
+                before,
+                "finished node changed its outcome"
+            );
+        } else {
+            honest.receive(message);
+            delivered += 1;
+        }
+    }
+    Attacked {
+        outcome: honest.outcome(),
+        delivered,
+    }
+}
+
+/// Try every tactic at every point of a session between the nodes `new_honest` and `new_evil`
+/// make, checking each element the honest node ends up with is `genuine`
+pub fn assault<P, O>(
+    new_honest: impl Fn() -> P,
+    new_evil: impl Fn() -> P,
+    honest_role: Role,
+    genuine: impl Fn(&O::Item) -> bool,
+) where
+    P: Protocol<Output = O>,
+    P::Message: Forge,
+    O: IntoIterator + PartialEq + fmt::Debug,
+    O::Item: fmt::Debug,
+{
+    let script = script(&mut new_honest(), &mut new_evil(), honest_role);
+    let mut rng = StdRng::seed_from_u64(0);
+    for tactic in Tactic::ALL {
+        for at in 0..script.len() {
+            let sent = tamper(&script, tactic, at, &mut rng);
+            let attacked = attack(&mut new_honest(), honest_role, sent.clone());
+            let context = format!("{tactic:?} at {at}, sent {sent:#?}");
+            match attacked.outcome {
+                // fine as long as the evil peer didn't hang up, we'd time out waiting on it
+                None => assert!(
+                    !sent.last().is_some_and(Message::is_terminal),
+                    "still running after the peer hung up, {context}"
+                ),
+                Some(Ok(common)) => {
+                    for element in common {
+                        assert!(genuine(&element), "false match {element:?}, {context}");
+                    }
+                }
+                Some(Err(_)) => {}
+            }
+        }
+    }
+}
+
+fn random_hash(rng: &mut StdRng) -> Vec<u8> {
+    rng.random::<[u8; 32]>().to_vec()
+}
+
+impl Forge for challenge::NodeMessage {
+    fn forge(&self, script: &[Self], rng: &mut StdRng) -> Self {
+        use challenge::NodeMessage::*;
+        let made_up = ChallengeReponsePair {
+            salt: rng.random(),
+            hash: random_hash(rng),
+        };
+        match self {
+            // a different nonce is just a different session, unless it's sent at the wrong time
+            Start { .. } | Initialize { .. } if rng.random() => Initialize {
+                nonce: rng.random(),
+            },
+            Start { .. } => Start {
+                nonce: rng.random(),
+            },
+            Initialize { .. } => Start {
+                nonce: rng.random(),
+            },
+            ChallengeQuery { index, hash } => match rng.random_range(0..3) {
+                0 => ChallengeQuery {
+                    index: *index,
+                    hash: random_hash(rng),
+                },
+                1 => ChallengeQuery {
+                    index: index + 1,
+                    hash: hash.clone(),
+                },
+                _ => ChallengeReponse {
+                    index: *index,
+                    proof: None,
+                },
+            },
+            ChallengeReponse { index, proof } => {
+                // a real proof, but for some other element
+                let borrowed: Vec<&ChallengeReponsePair> = script
+                    .iter()
+                    .filter_map(|message| match message {
+                        ChallengeReponse {
+                            index: other,
+                            proof: Some(proof),
+                        } if other != index => Some(proof),
+                        _ => None,
+                    })
+                    .collect();
+                let proof = match (rng.random_range(0..4), proof, borrowed.choose(rng)) {
+                    // the right hash but the wrong salt
+                    (0, Some(proof), _) => Some(ChallengeReponsePair {
+                        salt: rng.random(),
+                        hash: proof.hash.clone(),
+                    }),
+                    (1, _, Some(borrowed)) => Some((*borrowed).clone()),
+                    (2, proof, _) => {
+                        return ChallengeReponse {
+                            index: index + 1,
+                            proof: proof.clone(),
+                        };
+                    }
+                    // claim a match with nothing to back it up
+                    _ => Some(made_up),
+                };
+                ChallengeReponse {
+                    index: *index,
+                    proof,
+                }
+            }
+            Resume {
+                session_id,
+                position,
+                ..
+            } => Resume {
+                session_id: *session_id,
+                position: *position,
+                transcript: rng.random(),
+            },
+            // carry on past the end, claiming a match
+            Fail(_) | Done => ChallengeReponse {
+                index: rng.random_range(0..4),
+                proof: Some(made_up),
+            },
+        }
+    }
+}
+
+impl Forge for simple::NodeMessage {
+    fn forge(&self, _script: &[Self], rng: &mut StdRng) -> Self {
+        use simple::NodeMessage::*;
+        match self {
+            HasQuery { location, values } => match rng.random() {
+                true => HasQuery {
+                    location: *location,
+                    values: values
+                        .iter()
+                        .map(|value| value.wrapping_add(rng.random_range(1..4)))
+                        .collect(),
+                },
+                false => HasQuery {
+                    location: location + 1,
+                    values: values.clone(),
+                },
+            },
+            HasResponse { location, has } => match rng.random() {
+                true => HasResponse {
+                    location: *location,
+                    has: has.iter().map(|has| !has).collect(),
+                },
+                false => HasResponse {
+                    location: location + 1,
+                    has: has.clone(),
+                },
+            },
+            Fail(_) | End => HasResponse {
+                location: rng.random_range(0..4),
+                has: vec![true],
+            },
+        }
+    }
+}
+
+// tests
+
+#[test]
+fn tampers_at_one_point() {
+    use simple::NodeMessage::*;
+    let script = vec![
+        HasResponse {
+            location: 0,
+            has: vec![true],
+        },
+        HasResponse {
+            location: 1,
+            has: vec![false],
+        },
+        End,
+    ];
+    let mut rng = StdRng::seed_from_u64(0);
+
+    assert_eq!(
+        tamper(&script, Tactic::Drop, 1, &mut rng),
+        [script[0].clone(), End]
+    );
+    assert_eq!(
+        tamper(&script, Tactic::Reorder, 1, &mut rng),
+        [script[0].clone(), End, script[1].clone()]
+    );
+    // reordering the last message has nothing to swap with
+    assert_eq!(tamper(&script, Tactic::Reorder, 2, &mut rng), script);
+    assert_eq!(tamper(&script, Tactic::Drop, 3, &mut rng), script);
+
+    let replayed = tamper(&script, Tactic::Replay, 1, &mut rng);
+    assert_eq!(replayed.len(), 4);
+    assert!(script[..2].contains(&replayed[2]));
+
+    let forged = tamper(&script, Tactic::Forge, 0, &mut rng);
+    assert_ne!(forged[0], script[0]);
+    assert_eq!(forged[1..], script[1..]);
+    assert_eq!(tamper(&script, Tactic::Flood(6), 0, &mut rng).len(), 9);
+}
+
+#[test]
+fn script_is_what_the_evil_side_sends() {
+    // the honest leader hangs up itself, so the follower never sends `End`
+    let (ours, theirs) = (vec![1, 2], vec![1, 3]);
+    let script = script(
+        &mut simple::NodeState::new(&ours),
+        &mut simple::NodeState::new(&theirs),
+        Role::Leader,
+    );
+    assert_eq!(
+        script,
+        [
+            simple::NodeMessage::HasResponse {
+                location: 0,
+                has: vec![true]
+            },
+            simple::NodeMessage::HasResponse {
+                location: 1,
+                has: vec![false]
+            },
+        ]
+    );
+}
@@ -2,173 +2,577 @@
 //!
 //! Construct a node given the secret `data` and whether it is the protocol `Leader` (a) or `Follower` (b)
 //!
+//! Salts come from the node's own rng. `Node::new` uses the thread rng, `Node::with_rng` takes any
+//! `CryptoRng` and `Node::seeded` gives a deterministic node so transcripts can be recorded and replayed
+//!
 //! Nodes communicate by sending each other `NodeMessage`
 //!
 //! ## The Protocol
-//! - a sends b `Start`
-//! - b generates a salt value, hashes its data and shares the salt with a via `Initialize`
-//! - a hashes its data with the same salt value
-//! - a iterates each data, sending its hashed value to b via `ChallengeQuery`
+//! - a generates a random nonce and sends it to b via `Start`
+//! - b generates its own nonce and shares it with a via `Initialize`
+//! - both sides derive the session salt as `H(binding || nonce_a || nonce_b)` so neither one alone picks it, and hash their data with it
+//!   - `binding` is empty unless the node was given one with `bind_channel`, e.g. a secure channel's handshake hash
+//! - a iterates each data, sending its index and hashed value to b via `ChallengeQuery`
+//!   - both sides check the index, b only accepts the next query in order and a only the response to
+//!     the query it just sent, anything repeated or skipped is a `Fail` with `OutOfSequence`
 //!   - if b doesn't have the matching hashed data it knows it's not in the set
-//!     - b returns `ChallengeReponse(None)`
+//!     - b returns `ChallengeReponse { index, proof: None }`
 //!   - if b has the same value, it knows they share the same element and makes a note
-//!     - b returns `ChallengeReponse(Some(new salt, new hashed data))` as a challenge for a
+//!     - b returns `ChallengeReponse { index, proof: Some(new random salt, new hashed data) }` as a challenge for a
 //!     - a computes its own local version of (data + new salt) to check if b really has original data, making note
-//!       - in the case that a cannot derive the same new hash, it sends `Fail` and should quit
+//!       - in the case that a cannot derive the same new hash, it sends `Fail` with `VerificationFailed` and should quit
 //! - a sends `Done` when it runs out of elements
 //! - in the case that either side recieves `Done` or `Fail` it should close the network connection and finish
-use std::collections::HashSet;
+//!
+//! Each node tracks an explicit `State` and rejects any message that arrives out of order with `Fail`.
+//! Once a node is `Done` or `Failed` it stays there, answering anything else with `SessionClosed`,
+//! and `outcome` gives either the common data or why it failed
+//!
+//! ## Resuming
+//! While querying either node can give a `Checkpoint`, and `Node::resume` picks up from one after
+//! the connection dropped. Both sides keep a transcript hash over every query and response so far.
+//! - the resumed leader sends `Resume { session_id, position, transcript }`, position being the query it's waiting on
+//! - if the follower is at the same point with the same transcript it echoes the `Resume` and the leader re-sends that query
+//! - if the follower already answered that query it sends the same answer again, the leader just never got it
+//! - anything else is a `Fail` with `ResumeMismatch`
 use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 
-use rand::{Rng, distr::Alphanumeric};
+use crate::error::ProtocolError;
+use crate::protocol::Work;
+use crate::third::SessionSalt;
+use crate::wire::Wire;
+use rand::{
+    CryptoRng, RngCore, SeedableRng,
+    rngs::{StdRng, ThreadRng},
+};
 
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct ChallengeReponsePair {
-    pub salt: String,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub salt: SessionSalt,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
     pub hash: Vec<u8>,
 }
 
+/// Random contribution from each peer towards the session salt
+pub type Nonce = [u8; 32];
+
+/// Running hash of every query and response in a session
+pub type Transcript = [u8; 32];
+
 // a is the initiator, b is the responder.
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum NodeMessage {
-    Start,                                          // a starts
-    Initialize { salt: String }, // b agrees and chooses a salt for the initial state
-    ChallengeQuery { hash: Vec<u8> }, // a queries with the salted hash of a particular value
-    ChallengeReponse(Option<ChallengeReponsePair>), // response of either None or Some with new proof for a that b has the unhashed value
-    Fail { reason: String },                        //
-    Done,                                           // a or b should be able to hang up anytime
+    // a starts with its half of the session salt
+    Start {
+        #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+        nonce: Nonce,
+    },
+    // b agrees and sends its half of the session salt
+    Initialize {
+        #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+        nonce: Nonce,
+    },
+    // a queries with the salted hash of the value at index
+    ChallengeQuery {
+        index: usize,
+        #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+        hash: Vec<u8>,
+    },
+    // response to the query at index, either None or Some with new proof for a that b has the unhashed value
+    ChallengeReponse {
+        index: usize,
+        proof: Option<ChallengeReponsePair>,
+    },
+    // a restored from a checkpoint asks b to pick up at the query at position, b echoes it back if it agrees
+    Resume {
+        #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+        session_id: [u8; 32],
+        position: usize,
+        #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+        transcript: Transcript,
+    },
+    Fail(ProtocolError), // either side gives up, saying why
+    Done,                // a or b should be able to hang up anytime
+}
+
+impl NodeMessage {
+    /// Name of the message without its contents, for errors
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NodeMessage::Start { .. } => "Start",
+            NodeMessage::Initialize { .. } => "Initialize",
+            NodeMessage::ChallengeQuery { .. } => "ChallengeQuery",
+            NodeMessage::ChallengeReponse { .. } => "ChallengeReponse",
+            NodeMessage::Resume { .. } => "Resume",
+            NodeMessage::Fail(_) => "Fail",
+            NodeMessage::Done => "Done",
+        }
+    }
 }
 
 // simple state machine
 #[allow(unused)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum NodeType {
     Leader,
     Follower,
 }
 
-pub struct Node<'a> {
+impl NodeType {
+    fn name(&self) -> &'static str {
+        match self {
+            NodeType::Leader => "Leader",
+            NodeType::Follower => "Follower",
+        }
+    }
+}
+
+/// Where a node is in the protocol, every message is only accepted in the states listed in
+/// `Node::recieve_message`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum State {
+    /// nothing sent or received yet
+    Idle,
+    /// leader sent `Start` and waits for the follower's nonce
+    AwaitingInit,
+    /// salt agreed, leader is sending queries and follower answering them
+    Querying,
+    /// restored from a checkpoint, waiting to agree with the peer where to pick up
+    Resuming,
+    Done,
+    Failed,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Idle => "Idle",
+            State::AwaitingInit => "AwaitingInit",
+            State::Querying => "Querying",
+            State::Resuming => "Resuming",
+            State::Done => "Done",
+            State::Failed => "Failed",
+        }
+    }
+}
+
+/// Everything a node needs to carry on a session after the connection dropped, see `Node::resume`
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    pub node_type: NodeType,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub salt: SessionSalt,
+    pub data_index: usize,
+    /// sorted so the same state always gives the same checkpoint
+    pub data_common: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub transcript: Transcript,
+    /// follower only, our answer to the query before `data_index` in case it never reached the
+    /// leader, and the transcript from before it
+    pub last_response: Option<NodeMessage>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub previous_transcript: Transcript,
+    /// hash of our salted data, so a checkpoint can't be resumed with different data
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub data_digest: [u8; 32],
+}
+
+impl Checkpoint {
+    /// Both sides of a session derive the same id from its salt
+    #[allow(unused)]
+    pub fn session_id(&self) -> [u8; 32] {
+        session_id(&self.salt)
+    }
+}
+
+#[derive(Clone)]
+pub struct Node<'a, R = ThreadRng> {
     node_type: NodeType,
+    state: State,
+    rng: R,
     data: &'a [String],
     data_index: usize,
-    first_challenge: bool,
-    salt: Option<String>,
+    /// our own contribution to the session salt, only the leader needs to remember it
+    nonce: Option<Nonce>,
+    /// mixed into the session salt, ties it to whatever channel we're running over
+    binding: Vec<u8>,
+    salt: Option<SessionSalt>,
     data_hashed: Vec<Vec<u8>>,
     /// data we have in common with the peer
     data_common: HashSet<String>,
+    transcript: Transcript,
+    /// follower's last answer and the transcript before it, for a leader that resumes without it
+    last_response: Option<NodeMessage>,
+    previous_transcript: Transcript,
+    /// why we ended up `Failed`
+    failure: Option<ProtocolError>,
+    work: Work,
 }
 
 impl Node<'_> {
     #[allow(unused)]
-    pub fn new(data: &[String], node_type: NodeType) -> Node {
+    pub fn new(data: &[String], node_type: NodeType) -> Node<'_> {
+        Node::with_rng(data, node_type, rand::rng())
+    }
+
+    #[allow(unused)]
+    pub fn resume<'a>(
+        data: &'a [String],
+        checkpoint: &Checkpoint,
+    ) -> Result<Node<'a>, ProtocolError> {
+        Node::resume_with_rng(data, checkpoint, rand::rng())
+    }
+}
+
+impl<'a> Node<'a, StdRng> {
+    /// Deterministic node, the same seed and data always produce the same messages.
+    /// Only for tests and replaying failures, the salts are predictable to anyone who knows the seed
+    #[allow(unused)]
+    pub fn seeded(data: &'a [String], node_type: NodeType, seed: u64) -> Self {
+        Node::with_rng(data, node_type, StdRng::seed_from_u64(seed))
+    }
+}
+
+impl<'a, R: RngCore + CryptoRng> Node<'a, R> {
+    pub fn with_rng(data: &'a [String], node_type: NodeType, rng: R) -> Self {
         Node {
             node_type,
+            state: State::Idle,
+            rng,
             data,
             data_index: 0,
-            first_challenge: true,
+            nonce: None,
+            binding: vec![],
             salt: None,
             data_hashed: vec![],
             data_common: HashSet::new(),
+            transcript: [0; 32],
+            last_response: None,
+            previous_transcript: [0; 32],
+            failure: None,
+            work: Work::default(),
+        }
+    }
+
+    /// Restore a node from `checkpoint` in the `Resuming` state. A leader sends `Resume` from
+    /// `start`, a follower waits for it. `data` has to be what the checkpoint was taken with
+    #[allow(unused)]
+    pub fn resume_with_rng(
+        data: &'a [String],
+        checkpoint: &Checkpoint,
+        rng: R,
+    ) -> Result<Self, ProtocolError> {
+        let mut node = Node::with_rng(data, checkpoint.node_type, rng);
+        node.salt = Some(checkpoint.salt);
+        node.hash_data();
+        // a leader has always got a query left to send, a follower may have answered the last one
+        let in_range = match checkpoint.node_type {
+            NodeType::Leader => checkpoint.data_index < data.len(),
+            NodeType::Follower => checkpoint.data_index <= data.len(),
+        };
+        if node.data_digest() != checkpoint.data_digest || !in_range {
+            return Err(ProtocolError::ResumeMismatch);
+        }
+        node.data_index = checkpoint.data_index;
+        node.data_common = checkpoint.data_common.iter().cloned().collect();
+        node.transcript = checkpoint.transcript;
+        node.last_response = checkpoint.last_response.clone();
+        node.previous_transcript = checkpoint.previous_transcript;
+        node.state = State::Resuming;
+        Ok(node)
+    }
+
+    /// Where we are in the session, only while querying or still resuming
+    #[allow(unused)]
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        if !matches!(self.state, State::Querying | State::Resuming) {
+            return None;
         }
+        let mut data_common: Vec<String> = self.data_common.iter().cloned().collect();
+        data_common.sort();
+        Some(Checkpoint {
+            node_type: self.node_type,
+            salt: self.salt?,
+            data_index: self.data_index,
+            data_common,
+            transcript: self.transcript,
+            last_response: self.last_response.clone(),
+            previous_transcript: self.previous_transcript,
+            data_digest: self.data_digest(),
+        })
+    }
+
+    #[allow(unused)]
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, State::Done | State::Failed)
+    }
+
+    /// `None` while the protocol is still running, then the data we share with the peer or the
+    /// reason we gave up
+    #[allow(unused)]
+    pub fn outcome(&self) -> Option<Result<&HashSet<String>, &ProtocolError>> {
+        match (self.state, &self.failure) {
+            (State::Done, _) => Some(Ok(&self.data_common)),
+            (State::Failed, Some(failure)) => Some(Err(failure)),
+            _ => None,
+        }
+    }
+
+    /// Hashes and lookups so far. The follower scans every hash it has for each query, so its
+    /// comparisons grow with both set sizes
+    #[allow(unused)]
+    pub fn work(&self) -> Work {
+        self.work
+    }
+
+    /// What we've matched with the peer so far, only final once there's an `outcome`
+    #[allow(unused)]
+    pub fn common(&self) -> &HashSet<String> {
+        &self.data_common
+    }
+
+    /// Bind the session salt to something both sides share, like a secure channel's handshake hash.
+    /// Must happen before the salt is derived, so before `start` or receiving `Start`
+    #[allow(unused)]
+    pub fn bind_channel(&mut self, binding: &[u8]) {
+        self.binding = binding.to_vec();
     }
 
     pub fn start(&mut self) -> NodeMessage {
-        match self.node_type {
-            NodeType::Leader => NodeMessage::Start,
-            NodeType::Follower => NodeMessage::Fail {
-                reason: "Cannot call start on follower node".to_string(),
+        if self.is_finished() {
+            return NodeMessage::Fail(ProtocolError::SessionClosed);
+        }
+        match (&self.node_type, self.state) {
+            (NodeType::Leader, State::Idle) => {
+                let nonce = generate_salt(&mut self.rng);
+                self.nonce = Some(nonce);
+                self.state = State::AwaitingInit;
+                NodeMessage::Start { nonce }
+            }
+            (NodeType::Leader, State::Resuming) => NodeMessage::Resume {
+                session_id: session_id(&self.salt.expect("resumed nodes have a salt")),
+                position: self.data_index,
+                transcript: self.transcript,
             },
+            (NodeType::Leader, state) => {
+                NodeMessage::Fail(ProtocolError::unexpected(state.name(), "Start"))
+            }
+            (NodeType::Follower, _) => {
+                NodeMessage::Fail(ProtocolError::role(self.node_type.name(), "start"))
+            }
         }
     }
 
+    /// The whole transition table, anything not matched here is out of order and fails the session
     pub fn recieve_message(&mut self, message: NodeMessage) -> NodeMessage {
-        match self.node_type {
-            NodeType::Leader => match message {
-                NodeMessage::Initialize { salt } => match self.salt {
-                    Some(_) => NodeMessage::Fail {
-                        reason: "Node recieved initialize when already initialized".to_string(),
-                    },
+        if self.is_finished() {
+            return NodeMessage::Fail(ProtocolError::SessionClosed);
+        }
+        // only needed for the transcript, but the message is gone once it's matched
+        let received = message.encode();
+        let reply = match (&self.node_type, self.state, message) {
+            (NodeType::Leader, State::AwaitingInit, NodeMessage::Initialize { nonce }) => {
+                let own_nonce = self
+                    .nonce
+                    .expect("leader sent its nonce before awaiting init");
+                self.salt = Some(derive_session_salt(&self.binding, &own_nonce, &nonce));
+                self.hash_data();
+                self.state = State::Querying;
+                self.next_challenge()
+            }
+            (
+                NodeType::Leader,
+                State::Querying | State::Resuming,
+                NodeMessage::ChallengeReponse { index, proof },
+            ) => {
+                if index != self.data_index {
+                    return self.finish_on(out_of_sequence(self.data_index, index));
+                }
+                // resuming and the follower sent the answer we missed, carry on from it
+                self.state = State::Querying;
+                let query = self.next_challenge().encode();
+                self.extend_transcript(&query, &received);
+                match proof {
                     None => {
-                        self.salt = Some(salt);
-                        self.hash_data();
+                        self.data_index += 1;
                         self.next_challenge()
                     }
-                },
-                NodeMessage::ChallengeReponse(response) => match response {
-                    None => self.next_challenge(),
-                    Some(response2) => match self.data.get(self.data_index) {
-                        Some(original_data) => {
-                            let new_hash = hash_value(original_data, &response2.salt);
-                            if new_hash == response2.hash {
-                                self.data_common.insert(original_data.clone());
-                            }
-                            self.next_challenge()
-                        }
-                        None => NodeMessage::Fail {
-                            reason:
-                                "Protocol responder gave bad new salt challenge for current data"
-                                    .to_string(),
-                        },
-                    },
-                },
-                NodeMessage::Done => NodeMessage::Done,
-                NodeMessage::Fail { reason } => NodeMessage::Fail {
-                    reason: format!("Protocol responder failed: {}", reason),
-                },
-                _ => NodeMessage::Fail {
-                    reason: format!("Unsupported message for this node state: {:?}", message),
-                },
-            },
-            NodeType::Follower => match message {
-                NodeMessage::Start => {
-                    if self.salt.is_some() {
-                        return NodeMessage::Fail {
-                            reason: "Recieved start when already initialized".to_string(),
-                        };
-                    }
-                    let salt = generate_salt();
-                    self.salt = Some(salt.clone());
-                    self.hash_data();
-                    NodeMessage::Initialize { salt }
+                    Some(response2) => self.verify_challenge(response2),
                 }
-                NodeMessage::Initialize { salt: _ } => NodeMessage::Fail {
-                    reason: "Node recieved initialize when already initialized".to_string(),
+            }
+            (
+                NodeType::Leader,
+                State::Resuming,
+                NodeMessage::Resume {
+                    session_id: id,
+                    position,
+                    transcript,
                 },
-                NodeMessage::ChallengeQuery { hash } => {
-                    let found = self.data_hashed.iter().position(|h| *h == hash);
-                    match found {
-                        None => NodeMessage::ChallengeReponse(None),
-                        Some(index) => {
-                            let original_data = self.data[index].clone();
-                            let new_salt = generate_salt();
-                            let new_hash = hash_value(&original_data, &new_salt);
-                            self.data_common.insert(original_data);
-                            NodeMessage::ChallengeReponse(Some(ChallengeReponsePair {
-                                salt: new_salt,
-                                hash: new_hash,
-                            }))
-                        }
-                    }
+            ) => {
+                let ours = self.salt.map(|salt| session_id(&salt));
+                if ours != Some(id) || position != self.data_index || transcript != self.transcript
+                {
+                    NodeMessage::Fail(ProtocolError::ResumeMismatch)
+                } else {
+                    self.state = State::Querying;
+                    self.next_challenge()
                 }
-                NodeMessage::Done => NodeMessage::Done,
-                NodeMessage::Fail { reason: _ } => NodeMessage::Fail {
-                    reason: "Protocol leader failed".to_owned(),
+            }
+            (
+                NodeType::Follower,
+                State::Idle,
+                NodeMessage::Start {
+                    nonce: leader_nonce,
                 },
-                NodeMessage::ChallengeReponse(_) => NodeMessage::Fail {
-                    reason: "Received challenge response".to_owned(),
+            ) => {
+                let nonce = generate_salt(&mut self.rng);
+                self.salt = Some(derive_session_salt(&self.binding, &leader_nonce, &nonce));
+                self.hash_data();
+                self.state = State::Querying;
+                NodeMessage::Initialize { nonce }
+            }
+            (NodeType::Follower, State::Querying, NodeMessage::ChallengeQuery { index, hash }) => {
+                if index != self.data_index {
+                    out_of_sequence(self.data_index, index)
+                } else {
+                    self.data_index += 1;
+                    let response = self.answer_challenge(index, hash);
+                    self.previous_transcript = self.transcript;
+                    self.extend_transcript(&received, &response.encode());
+                    self.last_response = Some(response.clone());
+                    response
+                }
+            }
+            (
+                NodeType::Follower,
+                State::Resuming,
+                NodeMessage::Resume {
+                    session_id,
+                    position,
+                    transcript,
                 },
-            },
+            ) => self.resume_follower(session_id, position, transcript),
+            (_, _, NodeMessage::Done) => NodeMessage::Done,
+            (_, _, NodeMessage::Fail(cause)) => NodeMessage::Fail(ProtocolError::peer(cause)),
+            (_, state, message) => {
+                NodeMessage::Fail(ProtocolError::unexpected(state.name(), message.kind()))
+            }
+        };
+        self.finish_on(reply)
+    }
+
+    /// Move to `Done` or `Failed` if that's what we're about to send
+    fn finish_on(&mut self, reply: NodeMessage) -> NodeMessage {
+        match &reply {
+            NodeMessage::Done => self.state = State::Done,
+            NodeMessage::Fail(error) => {
+                self.state = State::Failed;
+                self.failure = Some(error.clone());
+            }
+            _ => {}
         }
+        reply
     }
 
-    fn next_challenge(&mut self) -> NodeMessage {
-        if self.first_challenge {
-            self.first_challenge = false;
-        } else {
-            self.data_index += 1;
+    /// Follower agrees to pick up at `position` if we're there too, or one further along and the
+    /// leader just missed our last answer
+    fn resume_follower(
+        &mut self,
+        id: [u8; 32],
+        position: usize,
+        transcript: Transcript,
+    ) -> NodeMessage {
+        let ours = session_id(&self.salt.expect("resumed nodes have a salt"));
+        let reply = match &self.last_response {
+            _ if id != ours => return NodeMessage::Fail(ProtocolError::ResumeMismatch),
+            _ if position == self.data_index && transcript == self.transcript => {
+                NodeMessage::Resume {
+                    session_id: id,
+                    position,
+                    transcript,
+                }
+            }
+            Some(response)
+                if position.checked_add(1) == Some(self.data_index)
+                    && transcript == self.previous_transcript =>
+            {
+                response.clone()
+            }
+            _ => return NodeMessage::Fail(ProtocolError::ResumeMismatch),
+        };
+        self.state = State::Querying;
+        reply
+    }
+
+    /// Chain one query and its response onto the transcript, both sides do this for every pair
+    fn extend_transcript(&mut self, query: &[u8], response: &[u8]) {
+        self.transcript = Sha256::new()
+            .chain_update(self.transcript)
+            .chain_update(query)
+            .chain_update(response)
+            .finalize()
+            .into();
+    }
+
+    fn data_digest(&self) -> [u8; 32] {
+        self.data_hashed
+            .iter()
+            .fold(Sha256::new(), |digest, hash| digest.chain_update(hash))
+            .finalize()
+            .into()
+    }
+
+    /// Leader checks the follower really holds the element we just queried
+    fn verify_challenge(&mut self, response: ChallengeReponsePair) -> NodeMessage {
+        let original_data = &self.data[self.data_index];
+        self.work.hashes += 1;
+        if hash_value(original_data, &response.salt) != response.hash {
+            return NodeMessage::Fail(ProtocolError::VerificationFailed {
+                index: self.data_index,
+            });
         }
+        self.data_common.insert(original_data.clone());
+        self.data_index += 1;
+        self.next_challenge()
+    }
+
+    /// Follower looks up the leader's hash and proves it has the element if found
+    fn answer_challenge(&mut self, index: usize, hash: Vec<u8>) -> NodeMessage {
+        let found = self.data_hashed.iter().position(|h| *h == hash);
+        self.work.comparisons += found.map_or(self.data_hashed.len(), |position| position + 1);
+        let proof = found.map(|position| {
+            self.work.hashes += 1;
+            let original_data = self.data[position].clone();
+            let new_salt = generate_salt(&mut self.rng);
+            let new_hash = hash_value(&original_data, &new_salt);
+            self.data_common.insert(original_data);
+            ChallengeReponsePair {
+                salt: new_salt,
+                hash: new_hash,
+            }
+        });
+        NodeMessage::ChallengeReponse { index, proof }
+    }
+
+    /// Query for the element at `data_index`, or `Done` once we've been through all of them
+    fn next_challenge(&mut self) -> NodeMessage {
         match self.data_hashed.get(self.data_index) {
             None => NodeMessage::Done,
             Some(next_hashed_data) => NodeMessage::ChallengeQuery {
+                index: self.data_index,
                 hash: next_hashed_data.clone(),
             },
         }
@@ -180,34 +584,68 @@ impl Node<'_> {
             None => {}
             Some(salt) => {
                 self.data_hashed = self.data.iter().map(|val| hash_value(val, salt)).collect();
+                self.work.hashes += self.data.len();
             }
         }
     }
 }
 
-#[allow(unused)]
-fn generate_salt() -> String {
-    rand::rng()
-        .sample_iter(&Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect()
+/// A query or response for the wrong index, most likely repeated or reordered by the network
+fn out_of_sequence(expected: usize, received: usize) -> NodeMessage {
+    NodeMessage::Fail(ProtocolError::OutOfSequence { expected, received })
+}
+
+/// 256 bits from the node's rng, used both for nonces and challenge salts
+fn generate_salt<R: RngCore + CryptoRng>(rng: &mut R) -> SessionSalt {
+    let mut salt = [0; 32];
+    rng.fill_bytes(&mut salt);
+    salt
+}
+
+/// The leader's nonce always goes first so both sides end up with the same salt
+fn derive_session_salt(
+    binding: &[u8],
+    leader_nonce: &Nonce,
+    follower_nonce: &Nonce,
+) -> SessionSalt {
+    Sha256::new()
+        .chain_update(binding)
+        .chain_update(leader_nonce)
+        .chain_update(follower_nonce)
+        .finalize()
+        .into()
+}
+
+fn session_id(salt: &SessionSalt) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"treehopper session")
+        .chain_update(salt)
+        .finalize()
+        .into()
 }
 
 #[allow(unused)]
-fn hash_value(value: &str, salt: &str) -> Vec<u8> {
-    Sha256::digest(value.to_owned() + salt).to_vec()
+fn hash_value(value: &str, salt: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update(value.as_bytes())
+        .chain_update(salt)
+        .finalize()
+        .to_vec()
 }
 
 /// Phony protocol, in reality we'd only have one side of this but the logic would be the same.
 /// In the case that we're the follower we'd know because we'd start by receiving the `Start` message
 /// In both cases we'd close our connection once we recieved Done or Fail
 #[allow(unused)]
-fn protocol(leader: &mut Node, follower: &mut Node) -> NodeMessage {
+fn protocol<A, B>(leader: &mut Node<A>, follower: &mut Node<B>) -> NodeMessage
+where
+    A: RngCore + CryptoRng,
+    B: RngCore + CryptoRng,
+{
     let mut message = leader.start();
     loop {
         let reply = follower.recieve_message(message.clone());
-        if matches!(&message, NodeMessage::Fail { reason: _ }) {
+        if matches!(&message, NodeMessage::Fail(_)) {
             break;
         }
         if matches!(&message, NodeMessage::Done) {
@@ -218,19 +656,41 @@ fn protocol(leader: &mut Node, follower: &mut Node) -> NodeMessage {
     message
 }
 
+/// Same as `protocol` but records every message sent by either side, in order
+#[allow(unused)]
+fn transcript<A, B>(leader: &mut Node<A>, follower: &mut Node<B>) -> Vec<NodeMessage>
+where
+    A: RngCore + CryptoRng,
+    B: RngCore + CryptoRng,
+{
+    let mut messages = vec![];
+    let mut message = leader.start();
+    loop {
+        messages.push(message.clone());
+        let reply = follower.recieve_message(message.clone());
+        messages.push(reply.clone());
+        if matches!(&message, NodeMessage::Fail(_) | NodeMessage::Done) {
+            break;
+        }
+        message = leader.recieve_message(reply);
+    }
+    messages
+}
+
 // tests
 
+#[cfg(test)]
+use crate::{adversary, explore, protocol::run_local, transport::Role};
+#[cfg(test)]
+use proptest::{collection::vec, prelude::*};
+
 #[test]
 fn initialization() {
     let data = fix_array(vec!["a", "b", "c"]);
     let mut n = Node::new(&data, NodeType::Follower);
 
-    let response = n.recieve_message(NodeMessage::Start);
-    let result = match response {
-        NodeMessage::Initialize { salt: _ } => true,
-        _ => false,
-    };
-    assert!(result);
+    let response = n.recieve_message(NodeMessage::Start { nonce: [0; 32] });
+    assert!(matches!(response, NodeMessage::Initialize { nonce: _ }));
 }
 
 #[test]
@@ -275,6 +735,26 @@ fn protocol_no_common() {
     assert_eq!(n2.data_common.len(), 0);
 }
 
+#[test]
+fn counts_work() {
+    let data = fix_array(vec!["1", "b", "c"]);
+    let data2 = fix_array(vec!["c", "b", "x"]);
+    let run = run_local(
+        &mut Node::new(&data, NodeType::Leader),
+        &mut Node::new(&data2, NodeType::Follower),
+    );
+
+    // both hash everything, then a proof and its check for each match. The follower scans its
+    // hashes for every query, all of them for "1" which it doesn't have
+    assert_eq!(
+        run.work,
+        Work {
+            hashes: 3 + 3 + 2 + 2,
+            comparisons: 3 + 2 + 1,
+        }
+    );
+}
+
 #[test]
 fn protocol_misconfigured_peer() {
     let data = fix_array(vec!["1", "b", "c"]);
@@ -286,16 +766,724 @@ fn protocol_misconfigured_peer() {
     // we expect a failure from n2 to be passed back through n1 so we know our protocol was a fail
     assert_eq!(
         message,
-        NodeMessage::Fail {
-            reason: "Protocol responder failed: Unsupported message for this node state: Start"
-                .to_owned()
+        NodeMessage::Fail(ProtocolError::peer(ProtocolError::unexpected(
+            "Idle", "Start"
+        )))
+    );
+}
+
+#[test]
+fn seeded_transcripts_replay() {
+    let data = fix_array(vec!["1", "b", "c"]);
+    let data2 = fix_array(vec!["b", "2", "1"]);
+
+    let first = transcript(
+        &mut Node::seeded(&data, NodeType::Leader, 1),
+        &mut Node::seeded(&data2, NodeType::Follower, 2),
+    );
+    let second = transcript(
+        &mut Node::seeded(&data, NodeType::Leader, 1),
+        &mut Node::seeded(&data2, NodeType::Follower, 2),
+    );
+    assert_eq!(first, second);
+
+    // a different follower seed changes the session salt and so every hash after it
+    let third = transcript(
+        &mut Node::seeded(&data, NodeType::Leader, 1),
+        &mut Node::seeded(&data2, NodeType::Follower, 3),
+    );
+    assert_eq!(first.len(), third.len());
+    assert_ne!(first[1], third[1]);
+    assert_ne!(first[2], third[2]);
+}
+
+#[test]
+fn seeded_salt_is_deterministic() {
+    let data = fix_array(vec!["a"]);
+    let mut n1 = Node::seeded(&data, NodeType::Follower, 42);
+    let mut n2 = Node::seeded(&data, NodeType::Follower, 42);
+
+    let message = n1.recieve_message(NodeMessage::Start { nonce: [7; 32] });
+    assert!(matches!(&message, NodeMessage::Initialize { nonce } if *nonce != [0; 32]));
+    assert_eq!(
+        message,
+        n2.recieve_message(NodeMessage::Start { nonce: [7; 32] })
+    );
+}
+
+#[test]
+fn session_salt_from_both_nonces() {
+    let data = fix_array(vec!["1", "b", "c"]);
+    let mut n1 = Node::new(&data, NodeType::Leader);
+    let mut n2 = Node::new(&data, NodeType::Follower);
+
+    protocol(&mut n1, &mut n2);
+
+    assert!(n1.salt.is_some());
+    assert_eq!(n1.salt, n2.salt);
+
+    // the follower can't pick the salt on its own, the same follower nonce with a different
+    // leader nonce gives a different salt
+    let follower_nonce = [1; 32];
+    assert_ne!(
+        derive_session_salt(&[], &[2; 32], &follower_nonce),
+        derive_session_salt(&[], &[3; 32], &follower_nonce)
+    );
+    // and the order matters, so both sides have to agree on who led
+    assert_ne!(
+        derive_session_salt(&[], &[2; 32], &[3; 32]),
+        derive_session_salt(&[], &[3; 32], &[2; 32])
+    );
+}
+
+#[test]
+fn session_salt_bound_to_channel() {
+    let data = fix_array(vec!["a", "b"]);
+    let run = |leader_binding: &[u8], follower_binding: &[u8]| {
+        let mut n1 = Node::seeded(&data, NodeType::Leader, 1);
+        let mut n2 = Node::seeded(&data, NodeType::Follower, 2);
+        n1.bind_channel(leader_binding);
+        n2.bind_channel(follower_binding);
+        protocol(&mut n1, &mut n2);
+        (n1.salt, n2.salt, n1.data_common)
+    };
+
+    let (unbound, _, _) = run(&[], &[]);
+    let (salt1, salt2, common) = run(b"channel", b"channel");
+    assert_eq!(salt1, salt2);
+    assert_ne!(salt1, unbound);
+    assert_eq!(common.len(), 2);
+
+    // a relay running two separate channels can't line the sessions up, nothing matches
+    let (salt1, salt2, common) = run(b"leader side", b"follower side");
+    assert_ne!(salt1, salt2);
+    assert!(common.is_empty());
+}
+
+#[test]
+fn leader_initialize_before_start() {
+    let data = fix_array(vec!["a"]);
+    let mut n = Node::new(&data, NodeType::Leader);
+
+    let response = n.recieve_message(NodeMessage::Initialize { nonce: [0; 32] });
+    assert_eq!(
+        response,
+        NodeMessage::Fail(ProtocolError::unexpected("Idle", "Initialize"))
+    );
+}
+
+#[test]
+fn follower_cannot_start() {
+    let data = fix_array(vec!["a"]);
+    let mut n = Node::new(&data, NodeType::Follower);
+
+    assert_eq!(
+        n.start(),
+        NodeMessage::Fail(ProtocolError::role("Follower", "start"))
+    );
+}
+
+#[test]
+fn leader_rejects_forged_challenge() {
+    let data = fix_array(vec!["a", "b"]);
+    let mut n = Node::new(&data, NodeType::Leader);
+
+    n.start();
+    n.recieve_message(NodeMessage::Initialize { nonce: [0; 32] });
+    n.recieve_message(NodeMessage::ChallengeReponse {
+        index: 0,
+        proof: None,
+    });
+
+    // claiming to hold "b" without knowing it
+    let response = n.recieve_message(NodeMessage::ChallengeReponse {
+        index: 1,
+        proof: Some(ChallengeReponsePair {
+            salt: [1; 32],
+            hash: vec![0; 32],
+        }),
+    });
+
+    assert_eq!(
+        response,
+        NodeMessage::Fail(ProtocolError::VerificationFailed { index: 1 })
+    );
+    assert!(n.data_common.is_empty());
+}
+
+#[test]
+fn leader_rejects_response_before_init() {
+    let data = fix_array(vec!["a"]);
+    let mut n = Node::new(&data, NodeType::Leader);
+
+    n.start();
+    let response = n.recieve_message(NodeMessage::ChallengeReponse {
+        index: 0,
+        proof: None,
+    });
+
+    assert_eq!(
+        response,
+        NodeMessage::Fail(ProtocolError::unexpected(
+            "AwaitingInit",
+            "ChallengeReponse"
+        ))
+    );
+    assert_eq!(n.state(), State::Failed);
+}
+
+#[test]
+fn repeated_messages_are_out_of_sequence() {
+    let data = fix_array(vec!["a", "b", "c"]);
+    let mut leader = Node::new(&data, NodeType::Leader);
+    let mut follower = Node::new(&data, NodeType::Follower);
+
+    let init = follower.recieve_message(leader.start());
+    let query = leader.recieve_message(init);
+    let response = follower.recieve_message(query.clone());
+
+    // the network delivers the same query twice, the follower won't answer it again
+    assert_eq!(
+        follower.recieve_message(query),
+        NodeMessage::Fail(ProtocolError::OutOfSequence {
+            expected: 1,
+            received: 0
+        })
+    );
+
+    // or the same response twice, which the leader can't take as the answer to its next query
+    let next = leader.recieve_message(response.clone());
+    assert!(matches!(next, NodeMessage::ChallengeQuery { index: 1, .. }));
+    assert_eq!(
+        leader.recieve_message(response),
+        NodeMessage::Fail(ProtocolError::OutOfSequence {
+            expected: 1,
+            received: 0
+        })
+    );
+    assert_eq!(leader.state(), State::Failed);
+}
+
+/// Drive a fresh node into `state` through the public api only
+#[allow(unused)]
+fn node_in_state<'a>(data: &'a [String], node_type: NodeType, state: State) -> Node<'a> {
+    let leader = matches!(node_type, NodeType::Leader);
+    let mut node = Node::new(data, node_type);
+    match (leader, state) {
+        (_, State::Idle) => {}
+        (true, State::AwaitingInit) => {
+            node.start();
+        }
+        (true, State::Querying) => {
+            node.start();
+            node.recieve_message(NodeMessage::Initialize { nonce: [0; 32] });
+        }
+        (false, State::Querying) => {
+            node.recieve_message(NodeMessage::Start { nonce: [0; 32] });
+        }
+        (_, State::Done) => {
+            node.recieve_message(NodeMessage::Done);
+        }
+        (_, State::Failed) => {
+            node.recieve_message(NodeMessage::Fail(ProtocolError::unexpected("Idle", "Done")));
+        }
+        (_, State::Resuming) => {
+            let querying = node_in_state(data, node_type, State::Querying);
+            node = Node::resume(data, &querying.checkpoint().unwrap()).unwrap();
+        }
+        (false, State::AwaitingInit) => panic!("followers never await init"),
+    }
+    assert_eq!(node.state(), state);
+    node
+}
+
+#[test]
+fn every_state_and_message() {
+    let data = fix_array(vec!["a", "b"]);
+    let messages = [
+        NodeMessage::Start { nonce: [1; 32] },
+        NodeMessage::Initialize { nonce: [1; 32] },
+        NodeMessage::ChallengeQuery {
+            index: 0,
+            hash: vec![0; 32],
+        },
+        NodeMessage::ChallengeReponse {
+            index: 0,
+            proof: None,
+        },
+        NodeMessage::Resume {
+            session_id: [2; 32],
+            position: 0,
+            transcript: [0; 32],
+        },
+        NodeMessage::Fail(ProtocolError::VerificationFailed { index: 0 }),
+        NodeMessage::Done,
+    ];
+
+    // (leader, state, message kind) => (reply kind, next state), anything missing must be
+    // rejected as an unexpected message
+    let allowed = [
+        (
+            true,
+            State::AwaitingInit,
+            "Initialize",
+            "ChallengeQuery",
+            State::Querying,
+        ),
+        (
+            true,
+            State::Querying,
+            "ChallengeReponse",
+            "ChallengeQuery",
+            State::Querying,
+        ),
+        (
+            true,
+            State::Resuming,
+            "ChallengeReponse",
+            "ChallengeQuery",
+            State::Querying,
+        ),
+        (false, State::Idle, "Start", "Initialize", State::Querying),
+        (
+            false,
+            State::Querying,
+            "ChallengeQuery",
+            "ChallengeReponse",
+            State::Querying,
+        ),
+    ];
+
+    let roles = [
+        (
+            true,
+            vec![
+                State::Idle,
+                State::AwaitingInit,
+                State::Querying,
+                State::Resuming,
+                State::Done,
+                State::Failed,
+            ],
+        ),
+        (
+            false,
+            vec![
+                State::Idle,
+                State::Querying,
+                State::Resuming,
+                State::Done,
+                State::Failed,
+            ],
+        ),
+    ];
+    for (leader, states) in roles {
+        for state in states {
+            for message in &messages {
+                let node_type = if leader {
+                    NodeType::Leader
+                } else {
+                    NodeType::Follower
+                };
+                let mut node = node_in_state(&data, node_type, state);
+                let reply = node.recieve_message(message.clone());
+
+                if matches!(state, State::Done | State::Failed) {
+                    // finished nodes are latched and refuse everything
+                    assert_eq!(reply, NodeMessage::Fail(ProtocolError::SessionClosed));
+                    assert_eq!(node.state(), state);
+                    continue;
+                }
+                let expected = allowed
+                    .iter()
+                    .find(|(l, s, m, _, _)| *l == leader && *s == state && *m == message.kind())
+                    .map(|(_, _, _, reply, next)| (*reply, *next));
+                match (expected, message) {
+                    (Some((reply_kind, next)), _) => {
+                        assert_eq!(reply.kind(), reply_kind, "{leader} {state:?} {message:?}");
+                        assert_eq!(node.state(), next, "{leader} {state:?} {message:?}");
+                    }
+                    (None, NodeMessage::Done) => {
+                        assert_eq!(reply, NodeMessage::Done);
+                        assert_eq!(node.state(), State::Done);
+                    }
+                    (None, NodeMessage::Resume { .. }) if state == State::Resuming => {
+                        // understood, but it's not a session we were part of
+                        assert_eq!(reply, NodeMessage::Fail(ProtocolError::ResumeMismatch));
+                        assert_eq!(node.state(), State::Failed);
+                    }
+                    (None, NodeMessage::Fail(cause)) => {
+                        assert_eq!(reply, NodeMessage::Fail(ProtocolError::peer(cause.clone())));
+                        assert_eq!(node.state(), State::Failed);
+                    }
+                    (None, _) => {
+                        assert_eq!(
+                            reply,
+                            NodeMessage::Fail(ProtocolError::unexpected(
+                                state.name(),
+                                message.kind()
+                            )),
+                            "{leader} {state:?} {message:?}"
+                        );
+                        assert_eq!(node.state(), State::Failed);
+                    }
+                }
+            }
         }
+    }
+}
+
+#[test]
+fn finished_nodes_stay_closed() {
+    let data = fix_array(vec!["1", "b", "c"]);
+    let data2 = fix_array(vec!["b", "c"]);
+    let mut n1 = Node::new(&data, NodeType::Leader);
+    let mut n2 = Node::new(&data2, NodeType::Follower);
+
+    assert!(!n1.is_finished());
+    assert_eq!(n1.outcome(), None);
+
+    protocol(&mut n1, &mut n2);
+
+    assert!(n1.is_finished());
+    assert!(n2.is_finished());
+    let common = HashSet::from_iter(data2.iter().cloned());
+    assert_eq!(n1.outcome(), Some(Ok(&common)));
+    assert_eq!(n2.outcome(), Some(Ok(&common)));
+
+    // a follower that's done won't answer queries anymore, even valid looking ones
+    let query = NodeMessage::ChallengeQuery {
+        index: 0,
+        hash: n2.data_hashed[0].clone(),
+    };
+    assert_eq!(
+        n2.recieve_message(query),
+        NodeMessage::Fail(ProtocolError::SessionClosed)
     );
+    assert_eq!(n1.start(), NodeMessage::Fail(ProtocolError::SessionClosed));
+    assert_eq!(n2.state(), State::Done);
+    assert_eq!(n2.outcome(), Some(Ok(&common)));
 }
 
-// TODO add further tests with 'evil' peers who send messages at wrong times?
+#[test]
+fn failed_outcome_has_cause() {
+    let data = fix_array(vec!["1", "b", "c"]);
+    let mut n1 = Node::new(&data, NodeType::Leader);
+    let mut n2 = Node::new(&data, NodeType::Leader);
+
+    protocol(&mut n1, &mut n2);
+
+    let cause = ProtocolError::unexpected("Idle", "Start");
+    assert_eq!(n2.outcome(), Some(Err(&cause)));
+    assert_eq!(n1.outcome(), Some(Err(&ProtocolError::peer(cause))));
+    assert_eq!(
+        n1.recieve_message(NodeMessage::Done),
+        NodeMessage::Fail(ProtocolError::SessionClosed)
+    );
+    assert_eq!(n1.state(), State::Failed);
+}
+
+/// Leader and follower part way through, the leader's query for index 1 is in `query`
+#[allow(unused)]
+fn interrupted<'a>(data: &'a [String], data2: &'a [String]) -> (Node<'a>, Node<'a>, NodeMessage) {
+    let mut leader = Node::new(data, NodeType::Leader);
+    let mut follower = Node::new(data2, NodeType::Follower);
+    let init = follower.recieve_message(leader.start());
+    let first = leader.recieve_message(init);
+    let query = leader.recieve_message(follower.recieve_message(first));
+    assert!(matches!(
+        query,
+        NodeMessage::ChallengeQuery { index: 1, .. }
+    ));
+    (leader, follower, query)
+}
+
+#[test]
+fn resume_after_lost_query() {
+    let data = fix_array(vec!["a", "b", "c", "d"]);
+    let data2 = fix_array(vec!["b", "c", "x"]);
+    let (leader, follower, _lost) = interrupted(&data, &data2);
+
+    let leader_cp = leader.checkpoint().unwrap();
+    let follower_cp = follower.checkpoint().unwrap();
+    assert_eq!(leader_cp.session_id(), follower_cp.session_id());
+    assert_eq!(leader_cp.transcript, follower_cp.transcript);
+    let mut leader = Node::resume(&data, &leader_cp).unwrap();
+    let mut follower = Node::resume(&data2, &follower_cp).unwrap();
+    assert_eq!(leader.state(), State::Resuming);
+
+    // the follower echoes the resume and the leader asks again
+    let resume = leader.start();
+    assert_eq!(follower.recieve_message(resume.clone()), resume);
+    assert!(matches!(
+        leader.recieve_message(resume),
+        NodeMessage::ChallengeQuery { index: 1, .. }
+    ));
+
+    let mut leader = Node::resume(&data, &leader_cp).unwrap();
+    let mut follower = Node::resume(&data2, &follower_cp).unwrap();
+    assert_eq!(protocol(&mut leader, &mut follower), NodeMessage::Done);
+    let common = HashSet::from_iter(fix_array(vec!["b", "c"]));
+    assert_eq!(leader.outcome(), Some(Ok(&common)));
+    assert_eq!(follower.outcome(), Some(Ok(&common)));
+}
+
+#[test]
+fn resume_after_lost_response() {
+    let data = fix_array(vec!["a", "b", "c", "d"]);
+    let data2 = fix_array(vec!["b", "c", "x"]);
+    let (leader, mut follower, query) = interrupted(&data, &data2);
+    // the follower answered but the leader never heard
+    let lost = follower.recieve_message(query);
+
+    let mut leader = Node::resume(&data, &leader.checkpoint().unwrap()).unwrap();
+    let mut follower = Node::resume(&data2, &follower.checkpoint().unwrap()).unwrap();
+    let resent = follower.recieve_message(leader.start());
+    assert_eq!(resent, lost);
+    assert_eq!(follower.state(), State::Querying);
+
+    let mut message = leader.recieve_message(resent);
+    while !matches!(message, NodeMessage::Done | NodeMessage::Fail(_)) {
+        message = leader.recieve_message(follower.recieve_message(message));
+    }
+    follower.recieve_message(message);
+    let common = HashSet::from_iter(fix_array(vec!["b", "c"]));
+    assert_eq!(leader.outcome(), Some(Ok(&common)));
+    assert_eq!(follower.outcome(), Some(Ok(&common)));
+}
+
+#[test]
+fn resume_mismatch() {
+    let data = fix_array(vec!["a", "b", "c", "d"]);
+    let data2 = fix_array(vec!["b", "c", "x"]);
+    let (leader, follower, _) = interrupted(&data, &data2);
+    let leader_cp = leader.checkpoint().unwrap();
+    let follower_cp = follower.checkpoint().unwrap();
+
+    // different data than the checkpoint was taken with
+    assert!(matches!(
+        Node::resume(&data2, &leader_cp),
+        Err(ProtocolError::ResumeMismatch)
+    ));
+
+    // the leader claims to be further along than the follower
+    let mut ahead = leader_cp.clone();
+    ahead.data_index = 3;
+    let mut leader = Node::resume(&data, &ahead).unwrap();
+    let mut follower = Node::resume(&data2, &follower_cp).unwrap();
+    assert_eq!(
+        protocol(&mut leader, &mut follower),
+        NodeMessage::Fail(ProtocolError::peer(ProtocolError::ResumeMismatch))
+    );
+    assert_eq!(
+        follower.outcome(),
+        Some(Err(&ProtocolError::ResumeMismatch))
+    );
+
+    // same place, different history
+    let mut forked = leader_cp.clone();
+    forked.transcript = [9; 32];
+    let mut leader = Node::resume(&data, &forked).unwrap();
+    let mut follower = Node::resume(&data2, &follower_cp).unwrap();
+    protocol(&mut leader, &mut follower);
+    assert_eq!(
+        follower.outcome(),
+        Some(Err(&ProtocolError::ResumeMismatch))
+    );
+
+    // a different session entirely
+    let (other, _, _) = interrupted(&data, &data2);
+    let mut leader = Node::resume(&data, &other.checkpoint().unwrap()).unwrap();
+    let mut follower = Node::resume(&data2, &follower_cp).unwrap();
+    protocol(&mut leader, &mut follower);
+    assert_eq!(
+        follower.outcome(),
+        Some(Err(&ProtocolError::ResumeMismatch))
+    );
+}
+
+#[test]
+fn resume_from_hostile_position() {
+    let data = fix_array(vec!["a", "b", "c", "d"]);
+    let data2 = fix_array(vec!["b", "c", "x"]);
+    let (_, mut follower, query) = interrupted(&data, &data2);
+    // the follower has an answer to resend, so it checks the position against the one before
+    follower.recieve_message(query);
+    let checkpoint = follower.checkpoint().unwrap();
+    let mut follower = Node::resume(&data2, &checkpoint).unwrap();
+
+    let reply = follower.recieve_message(NodeMessage::Resume {
+        session_id: checkpoint.session_id(),
+        position: usize::MAX,
+        transcript: checkpoint.transcript,
+    });
+    assert_eq!(reply, NodeMessage::Fail(ProtocolError::ResumeMismatch));
+}
+
+#[test]
+fn resume_empty_leader() {
+    let empty = vec![];
+    let mut follower = Node::new(&empty, NodeType::Follower);
+    follower.recieve_message(NodeMessage::Start { nonce: [0; 32] });
+    // a leader with nothing to query is done before it can checkpoint, so forge one from the
+    // follower's, the data digest is the same for any two empty sets
+    let mut checkpoint = follower.checkpoint().unwrap();
+    checkpoint.node_type = NodeType::Leader;
+    assert!(matches!(
+        Node::resume(&empty, &checkpoint),
+        Err(ProtocolError::ResumeMismatch)
+    ));
+
+    // the last element is as far as a leader can be, and a proof for it still checks out
+    let data = fix_array(vec!["a"]);
+    let (mut leader, mut follower) = (
+        Node::new(&data, NodeType::Leader),
+        Node::new(&data, NodeType::Follower),
+    );
+    let init = follower.recieve_message(leader.start());
+    let query = leader.recieve_message(init);
+    let mut checkpoint = leader.checkpoint().unwrap();
+    let mut resumed = Node::resume(&data, &checkpoint).unwrap();
+    let proof = follower.recieve_message(query);
+    assert_eq!(resumed.recieve_message(proof), NodeMessage::Done);
+    checkpoint.data_index = 1;
+    assert!(matches!(
+        Node::resume(&data, &checkpoint),
+        Err(ProtocolError::ResumeMismatch)
+    ));
+}
+
+#[test]
+fn checkpoint_only_while_querying() {
+    let data = fix_array(vec!["a", "b"]);
+    let mut leader = Node::new(&data, NodeType::Leader);
+    let mut follower = Node::new(&data, NodeType::Follower);
+    assert_eq!(leader.checkpoint(), None);
+    let init = follower.recieve_message(leader.start());
+    assert_eq!(leader.checkpoint(), None);
+    assert!(follower.checkpoint().is_some());
+    leader.recieve_message(init);
+    let checkpoint = leader.checkpoint().unwrap();
+
+    // a checkpoint of a resumed node is the one it came from
+    let resumed = Node::resume(&data, &checkpoint).unwrap();
+    assert_eq!(resumed.checkpoint(), Some(checkpoint));
+
+    protocol(
+        &mut Node::resume(&data, &leader.checkpoint().unwrap()).unwrap(),
+        &mut follower,
+    );
+    assert_eq!(follower.checkpoint(), None);
+}
+
+#[test]
+fn evil_peers() {
+    let ours = fix_array(vec!["a", "b", "c", "d"]);
+    for theirs in [
+        vec!["b", "d", "x"],
+        vec!["a", "b", "c", "d"],
+        vec!["x", "y"],
+        vec![],
+    ] {
+        let theirs = fix_array(theirs);
+        let genuine = |element: &String| theirs.contains(element) && ours.contains(element);
+        for (role, ours_type, theirs_type) in [
+            (Role::Leader, NodeType::Leader, NodeType::Follower),
+            (Role::Follower, NodeType::Follower, NodeType::Leader),
+        ] {
+            adversary::assault(
+                || Node::seeded(&ours, ours_type, 1),
+                || Node::seeded(&theirs, theirs_type, 2),
+                role,
+                genuine,
+            );
+        }
+    }
+}
+
+#[test]
+fn every_delivery_order() {
+    let sets = [vec![], vec!["a"], vec!["a", "b"], vec!["b", "c"]];
+    for (a, b) in sets.iter().flat_map(|a| sets.iter().map(move |b| (a, b))) {
+        let (a, b) = (fix_array(a.clone()), fix_array(b.clone()));
+        let expected: HashSet<String> = a.iter().filter(|x| b.contains(x)).cloned().collect();
+        let explored = explore::explore(
+            Node::seeded(&a, NodeType::Leader, 1),
+            Node::seeded(&b, NodeType::Follower, 2),
+            explore::Limits::default(),
+            |_, common| *common == expected,
+        );
+        assert!(explored.states > 0);
+        assert!(
+            explored.violations.is_empty(),
+            "{a:?} and {b:?}: {:#?}",
+            explored.violations
+        );
+    }
+}
+
+#[test]
+fn messages_at_wrong_times() {
+    let data = fix_array(vec!["a", "b"]);
+    let query = NodeMessage::ChallengeQuery {
+        index: 0,
+        hash: vec![0; 32],
+    };
+    // a follower that hasn't agreed a salt yet
+    let attacked = adversary::attack(
+        &mut Node::new(&data, NodeType::Follower),
+        Role::Follower,
+        vec![query.clone(), NodeMessage::Done],
+    );
+    assert_eq!(
+        attacked.outcome,
+        Some(Err(ProtocolError::unexpected("Idle", "ChallengeQuery")))
+    );
+    assert_eq!(attacked.delivered, 1);
+
+    // a leader sent the queries it should be asking
+    let attacked = adversary::attack(
+        &mut Node::new(&data, NodeType::Leader),
+        Role::Leader,
+        vec![query],
+    );
+    assert_eq!(
+        attacked.outcome,
+        Some(Err(ProtocolError::unexpected(
+            "AwaitingInit",
+            "ChallengeQuery"
+        )))
+    );
+}
 
 #[allow(unused)]
 fn fix_array(input: Vec<&str>) -> Vec<String> {
     input.into_iter().map(String::from).collect::<Vec<String>>()
 }
+
+/// Sets to run the protocol on, a small alphabet so they overlap often and any unicode for the rest
+#[cfg(test)]
+fn elements() -> impl Strategy<Value = Vec<String>> {
+    vec(prop_oneof!["[a-d]{0,2}", "\\PC{0,6}"], 0..24)
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn matches_hashset(a in elements(), b in elements(), seed in any::<u64>()) {
+        let run = run_local(
+            &mut Node::seeded(&a, NodeType::Leader, seed),
+            &mut Node::seeded(&b, NodeType::Follower, seed.wrapping_add(1)),
+        );
+        let a: HashSet<String> = a.into_iter().collect();
+        let b: HashSet<String> = b.into_iter().collect();
+        let expected: HashSet<String> = a.intersection(&b).cloned().collect();
+        for (ours, theirs, common) in [(&a, &b, run.leader), (&b, &a, run.follower)] {
+            let common = common.unwrap();
+            prop_assert_eq!(&common, &expected);
+            // and so what each side is left with only it has
+            let only_ours: HashSet<&String> = ours.difference(&common).collect();
+            prop_assert_eq!(only_ours, ours.difference(theirs).collect());
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#[allow(clippy::module_inception)]
 mod challenge;
 
-pub use challenge::*;
\ No newline at end of file
+#[allow(unused)]
+pub use challenge::*;
@@ -0,0 +1,1189 @@
+//! The `treehopper` binary. One side serves and the other connects, each reading its set from a file
+//! (one element per line unless `--format` says otherwise, see `input`), then both print the
+//! intersection one element per line.
+//!
+//! `serve` picks the protocol and only offers that one, `connect` offers everything its input can
+//! run and negotiation settles it. `simple` needs every element to be a `u32`.
+//!
+//! Both run over TCP unless `--unix <path>` puts them on a Unix socket, or `--stdio` on stdin and
+//! stdout so two processes can be wired together with a pipe or `socat`. Stdout carries the session
+//! then, so the intersection goes to stderr instead, and there's no timeout since a pipe can't have
+//! one.
+//!
+//! `compare` skips the network and runs protocols on two local files, checking each against a
+//! reference answer and printing what it cost. `challenge` should match the plain set intersection,
+//! `simple` only compares position by position so it's checked against that instead.
+//!
+//! Exit codes say who to blame: 0 worked, 1 our own input, 2 bad arguments, 3 the protocol failed
+//! or the peers couldn't agree (or `compare` got the wrong answer), 4 the connection itself.
+//!
+//! With `--output json` each run prints a `Report` on one line instead, including runs that failed,
+//! `compare` prints one for each protocol from `a`'s side
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::challenge::{self, NodeType};
+use crate::error::ProtocolError;
+use crate::input::{Input, InputError, InputOptions};
+use crate::negotiate::{DEFAULT_MAX_BATCH, Hello, ProtocolKind, negotiate};
+use crate::normalize::Normalizer;
+#[cfg(test)]
+use crate::protocol::Work;
+use crate::protocol::{LocalRun, Tally, run_local};
+use crate::report::{Failure, Parameters, Report};
+use crate::simple;
+use crate::transport::{Pipe, Role, TransportError, drive_counted};
+
+pub const EXIT_INPUT: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_PEER: u8 = 3;
+pub const EXIT_TRANSPORT: u8 = 4;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "treehopper",
+    about = "Find the elements two peers have in common"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Wait for one peer to connect and run the protocol with it
+    Serve {
+        #[arg(long, default_value = "challenge")]
+        protocol: ProtocolKind,
+        #[arg(long)]
+        input: PathBuf,
+        #[command(flatten)]
+        format: InputOptions,
+        #[arg(long, default_value = "127.0.0.1:9000")]
+        listen: SocketAddr,
+        /// listen on a Unix socket at this path instead
+        #[arg(long, conflicts_with = "listen")]
+        unix: Option<PathBuf>,
+        /// run over stdin and stdout instead, the intersection goes to stderr
+        #[arg(long, conflicts_with_all = ["listen", "unix"])]
+        stdio: bool,
+        /// seconds to wait on the peer before giving up
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        #[arg(long, value_enum, default_value_t)]
+        output: Output,
+    },
+    /// Connect to a serving peer and run whichever protocol it picked
+    Connect {
+        #[arg(long)]
+        input: PathBuf,
+        #[command(flatten)]
+        format: InputOptions,
+        #[arg(required_unless_present_any = ["unix", "stdio"])]
+        address: Option<SocketAddr>,
+        /// connect to a Unix socket at this path instead
+        #[arg(long, conflicts_with = "address")]
+        unix: Option<PathBuf>,
+        /// run over stdin and stdout instead, the intersection goes to stderr
+        #[arg(long, conflicts_with_all = ["address", "unix"])]
+        stdio: bool,
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        #[arg(long, value_enum, default_value_t)]
+        output: Output,
+    },
+    /// Run protocols in this process between two local files and check their answers
+    Compare {
+        a: PathBuf,
+        b: PathBuf,
+        /// how to read both files
+        #[command(flatten)]
+        format: InputOptions,
+        #[arg(long, default_value = "all")]
+        protocol: ProtocolChoice,
+        #[arg(long, value_enum, default_value_t)]
+        output: Output,
+    },
+}
+
+impl Command {
+    fn output(&self) -> Output {
+        match self {
+            Command::Serve { output, .. }
+            | Command::Connect { output, .. }
+            | Command::Compare { output, .. } => *output,
+        }
+    }
+
+    /// Whether stdout is taken by the session
+    fn stdio(&self) -> bool {
+        match self {
+            Command::Serve { stdio, .. } | Command::Connect { stdio, .. } => *stdio,
+            Command::Compare { .. } => false,
+        }
+    }
+}
+
+#[derive(ValueEnum, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Output {
+    /// the intersection one element per line, or `compare`'s table
+    #[default]
+    Text,
+    /// a `Report` per run, one per line
+    Json,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ProtocolChoice {
+    All,
+    Only(ProtocolKind),
+}
+
+impl ProtocolChoice {
+    fn protocols(&self) -> Vec<ProtocolKind> {
+        match self {
+            ProtocolChoice::All => Hello::supported().protocols,
+            ProtocolChoice::Only(protocol) => vec![*protocol],
+        }
+    }
+}
+
+impl FromStr for ProtocolChoice {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "all" => Ok(ProtocolChoice::All),
+            name => name.parse().map(ProtocolChoice::Only),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    /// couldn't read our input file
+    Read(PathBuf, InputError),
+    /// read it fine but it can't run what was picked
+    Input(InputError),
+    Transport(TransportError),
+    /// the protocol was picked and started but didn't finish, with how far it got
+    Failed(Box<Report>, TransportError),
+    /// `compare` found protocols that disagree with the reference answer
+    Mismatch(Vec<ProtocolKind>),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Read(path, error) => write!(f, "can't read {}: {error}", path.display()),
+            CliError::Input(error) => write!(f, "{error}"),
+            CliError::Transport(error) | CliError::Failed(_, error) => write!(f, "{error}"),
+            CliError::Mismatch(protocols) => {
+                let names: Vec<_> = protocols.iter().map(ProtocolKind::name).collect();
+                write!(f, "wrong answer from {}", names.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<TransportError> for CliError {
+    fn from(error: TransportError) -> Self {
+        CliError::Transport(error)
+    }
+}
+
+impl From<InputError> for CliError {
+    fn from(error: InputError) -> Self {
+        CliError::Input(error)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Transport(error.into())
+    }
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Read(..) | CliError::Input(_) => EXIT_INPUT,
+            CliError::Transport(error) | CliError::Failed(_, error) => transport_exit_code(error),
+            CliError::Mismatch(_) => EXIT_PEER,
+        }
+    }
+
+    fn failure(&self) -> Failure {
+        failure(self.exit_code(), self)
+    }
+}
+
+fn transport_exit_code(error: &TransportError) -> u8 {
+    match error {
+        TransportError::Protocol(_) | TransportError::Negotiation(_) => EXIT_PEER,
+        _ => EXIT_TRANSPORT,
+    }
+}
+
+/// Name who to blame the same way the exit code does
+fn failure(exit_code: u8, error: &impl fmt::Display) -> Failure {
+    let kind = match exit_code {
+        EXIT_INPUT => "input",
+        EXIT_PEER => "peer",
+        _ => "transport",
+    };
+    Failure {
+        kind,
+        message: error.to_string(),
+    }
+}
+
+pub fn read_input(path: &Path, options: &InputOptions) -> Result<Input, CliError> {
+    Input::read(path, options).map_err(|error| CliError::Read(path.into(), error))
+}
+
+/// Every protocol `input` can run
+fn offered(input: &Input, normalizer: &Normalizer) -> Hello {
+    let mut hello = Hello::supported().normalized(normalizer);
+    if input.numbers().is_err() {
+        hello.protocols.retain(|p| *p != ProtocolKind::Simple);
+    }
+    hello
+}
+
+#[allow(unused)]
+pub fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(error) => {
+            let _ = error.print();
+            return ExitCode::from(if error.use_stderr() { EXIT_USAGE } else { 0 });
+        }
+    };
+    let result = match cli.command.stdio() {
+        true => run(cli, &mut io::stderr().lock()),
+        false => run(cli, &mut io::stdout().lock()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("treehopper: {error}");
+            ExitCode::from(error.exit_code())
+        }
+    }
+}
+
+/// Run a parsed command, writing the intersection, `compare`'s table or the reports to `out`
+pub fn run(cli: Cli, out: &mut impl Write) -> Result<(), CliError> {
+    let output = cli.command.output();
+    let result = execute(cli.command, output, out);
+    if let (Output::Json, Err(error)) = (output, &result) {
+        let report = match error {
+            CliError::Failed(report, _) => Some(report.as_ref().clone()),
+            // compare has already written a report for every protocol
+            CliError::Mismatch(_) => None,
+            error => Some(Report::failure(error.failure())),
+        };
+        if let Some(report) = report {
+            writeln!(out, "{}", report.to_json())?;
+        }
+    }
+    result
+}
+
+fn execute(command: Command, output: Output, out: &mut impl Write) -> Result<(), CliError> {
+    let report = match command {
+        Command::Serve {
+            protocol,
+            input,
+            format,
+            listen,
+            unix,
+            stdio,
+            timeout,
+            ..
+        } => {
+            let timeout = Duration::from_secs(timeout);
+            match (unix, stdio) {
+                (_, true) => {
+                    serve_stdio(protocol, &read_input(&input, &format)?, &format.normalize)?
+                }
+                #[cfg(unix)]
+                (Some(path), false) => {
+                    let listener = UnixListener::bind(&path)?;
+                    eprintln!("listening on {}", path.display());
+                    let report = read_input(&input, &format).and_then(|input| {
+                        serve_unix(&listener, protocol, &input, &format.normalize, timeout)
+                    });
+                    // the socket file outlives the listener otherwise, and the next bind fails
+                    let _ = std::fs::remove_file(&path);
+                    report?
+                }
+                #[cfg(not(unix))]
+                (Some(_), false) => return Err(io::Error::from(io::ErrorKind::Unsupported).into()),
+                (None, false) => {
+                    let listener = TcpListener::bind(listen)?;
+                    eprintln!("listening on {}", listener.local_addr()?);
+                    let input = read_input(&input, &format)?;
+                    serve(&listener, protocol, &input, &format.normalize, timeout)?
+                }
+            }
+        }
+        Command::Connect {
+            input,
+            format,
+            address,
+            unix,
+            stdio,
+            timeout,
+            ..
+        } => {
+            let input = read_input(&input, &format)?;
+            let timeout = Duration::from_secs(timeout);
+            match (address, unix) {
+                _ if stdio => connect_stdio(&input, &format.normalize)?,
+                #[cfg(unix)]
+                (_, Some(path)) => connect_unix(&path, &input, &format.normalize, timeout)?,
+                #[cfg(not(unix))]
+                (_, Some(_)) => return Err(io::Error::from(io::ErrorKind::Unsupported).into()),
+                (Some(address), None) => connect(address, &input, &format.normalize, timeout)?,
+                (None, None) => unreachable!("clap wants an address without --unix or --stdio"),
+            }
+        }
+        Command::Compare {
+            a,
+            b,
+            format,
+            protocol,
+            ..
+        } => {
+            let (a, b) = (read_input(&a, &format)?, read_input(&b, &format)?);
+            let comparisons = compare(&a, &b, &protocol.protocols());
+            match output {
+                Output::Text => write_table(out, &comparisons)?,
+                Output::Json => {
+                    for comparison in &comparisons {
+                        let report = comparison.report(&a, &format.normalize);
+                        writeln!(out, "{}", report.to_json())?;
+                    }
+                }
+            }
+            let wrong: Vec<_> = comparisons
+                .iter()
+                .filter(|c| matches!(c.verdict, Verdict::Disagrees { .. } | Verdict::Failed(_)))
+                .map(|c| c.protocol)
+                .collect();
+            return match wrong.is_empty() {
+                true => Ok(()),
+                false => Err(CliError::Mismatch(wrong)),
+            };
+        }
+    };
+    match output {
+        Output::Text => {
+            for element in report.intersection.iter().flatten() {
+                writeln!(out, "{element}")?;
+            }
+        }
+        Output::Json => writeln!(out, "{}", report.to_json())?,
+    }
+    Ok(())
+}
+
+/// Serve a single peer on `listener`, running `protocol` and nothing else
+pub fn serve(
+    listener: &TcpListener,
+    protocol: ProtocolKind,
+    input: &Input,
+    normalizer: &Normalizer,
+    timeout: Duration,
+) -> Result<Report, CliError> {
+    let ours = serving(protocol, input, normalizer)?;
+    let (mut stream, _) = listener.accept()?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    session(&mut stream, &ours, input, Role::Follower)
+}
+
+pub fn connect(
+    address: SocketAddr,
+    input: &Input,
+    normalizer: &Normalizer,
+    timeout: Duration,
+) -> Result<Report, CliError> {
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    session(
+        &mut stream,
+        &offered(input, normalizer),
+        input,
+        Role::Leader,
+    )
+}
+
+/// `serve` for a peer on a Unix socket
+#[cfg(unix)]
+pub fn serve_unix(
+    listener: &UnixListener,
+    protocol: ProtocolKind,
+    input: &Input,
+    normalizer: &Normalizer,
+    timeout: Duration,
+) -> Result<Report, CliError> {
+    let ours = serving(protocol, input, normalizer)?;
+    let (mut stream, _) = listener.accept()?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    session(&mut stream, &ours, input, Role::Follower)
+}
+
+#[cfg(unix)]
+pub fn connect_unix(
+    path: &Path,
+    input: &Input,
+    normalizer: &Normalizer,
+    timeout: Duration,
+) -> Result<Report, CliError> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    session(
+        &mut stream,
+        &offered(input, normalizer),
+        input,
+        Role::Leader,
+    )
+}
+
+/// `serve` for a peer on the other end of our stdin and stdout
+pub fn serve_stdio(
+    protocol: ProtocolKind,
+    input: &Input,
+    normalizer: &Normalizer,
+) -> Result<Report, CliError> {
+    let ours = serving(protocol, input, normalizer)?;
+    let mut pipe = Pipe::new(io::stdin().lock(), io::stdout().lock());
+    session(&mut pipe, &ours, input, Role::Follower)
+}
+
+pub fn connect_stdio(input: &Input, normalizer: &Normalizer) -> Result<Report, CliError> {
+    let mut pipe = Pipe::new(io::stdin().lock(), io::stdout().lock());
+    session(&mut pipe, &offered(input, normalizer), input, Role::Leader)
+}
+
+/// The only protocol a server offers
+fn serving(
+    protocol: ProtocolKind,
+    input: &Input,
+    normalizer: &Normalizer,
+) -> Result<Hello, CliError> {
+    if protocol == ProtocolKind::Simple {
+        // find out now rather than after the peer has connected
+        input.numbers()?;
+    }
+    Ok(Hello {
+        protocols: vec![protocol],
+        ..Hello::supported().normalized(normalizer)
+    })
+}
+
+/// Negotiate then run whatever was picked, reporting the intersection sorted
+fn session<S: Read + Write>(
+    stream: &mut S,
+    ours: &Hello,
+    input: &Input,
+    role: Role,
+) -> Result<Report, CliError> {
+    let selected = negotiate(stream, ours, role)?;
+    let mut tally = Tally::default();
+    let common: Result<Vec<String>, _> = match selected.protocol {
+        ProtocolKind::Simple => {
+            let numbers = input.numbers()?;
+            let mut node = simple::NodeState::batched(&numbers, selected.batch as usize);
+            drive_counted(stream, &mut node, role, &mut tally)
+                .map(|common| common.iter().map(u32::to_string).collect())
+        }
+        ProtocolKind::Challenge => {
+            let node_type = match role {
+                Role::Leader => NodeType::Leader,
+                Role::Follower => NodeType::Follower,
+            };
+            let strings = input.strings();
+            let mut node = challenge::Node::new(&strings, node_type);
+            drive_counted(stream, &mut node, role, &mut tally)
+                .map(|common| common.into_iter().collect())
+        }
+    };
+    let report = Report::from_run(
+        selected.protocol,
+        role,
+        Parameters::negotiated(&selected, ours.normalization.clone()),
+        local_len(selected.protocol, input),
+        common.as_ref(),
+        &tally.stats(),
+    );
+    match common {
+        Ok(_) => Ok(report),
+        Err(error) => Err(CliError::Failed(Box::new(report), error)),
+    }
+}
+
+/// How many elements `input` goes into `protocol` with. `simple` works by position so every one
+/// counts, `challenge` only sees each distinct one once
+fn local_len(protocol: ProtocolKind, input: &Input) -> usize {
+    match protocol {
+        ProtocolKind::Simple => input.numbers().map_or(0, |numbers| numbers.len()),
+        ProtocolKind::Challenge => input.strings().iter().collect::<HashSet<_>>().len(),
+    }
+}
+
+/// How one protocol did in `compare`
+#[derive(Debug)]
+pub struct Comparison {
+    pub protocol: ProtocolKind,
+    pub verdict: Verdict,
+    /// `a` leads, `None` if it was skipped
+    pub run: Option<LocalRun<Vec<String>>>,
+}
+
+impl Comparison {
+    /// `a`'s side as a report, a wrong answer or a skip is its error
+    pub fn report(&self, a: &Input, normalizer: &Normalizer) -> Report {
+        let parameters = Parameters {
+            // what negotiation would pick between two of us
+            batch: (self.protocol == ProtocolKind::Simple).then_some(DEFAULT_MAX_BATCH),
+            normalization: normalizer.id(),
+            ..Parameters::default()
+        };
+        let Some(run) = &self.run else {
+            return Report {
+                protocol: Some(self.protocol.name()),
+                parameters,
+                ..Report::failure(failure(EXIT_INPUT, &self.verdict.summary()))
+            };
+        };
+        let report = Report::new(
+            self.protocol,
+            Role::Leader,
+            parameters,
+            local_len(self.protocol, a),
+            &run.stats,
+        );
+        let report = match &run.leader {
+            Ok(common) => report.found(common.clone()),
+            Err(_) => report,
+        };
+        match &self.verdict {
+            Verdict::Agrees(_) | Verdict::Skipped(_) => report,
+            verdict => report.failed(failure(EXIT_PEER, &verdict.summary())),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Verdict {
+    /// both sides found the reference answer, this many elements
+    Agrees(usize),
+    Disagrees {
+        expected: Vec<String>,
+        leader: Vec<String>,
+        follower: Vec<String>,
+    },
+    Failed(ProtocolError),
+    /// the input can't run this protocol
+    Skipped(String),
+}
+
+/// Run each of `protocols` between `a` leading and `b` following, and check what both sides end
+/// up with against a plain computation of the same thing
+pub fn compare(a: &Input, b: &Input, protocols: &[ProtocolKind]) -> Vec<Comparison> {
+    protocols
+        .iter()
+        .map(|protocol| {
+            let (expected, run) = match protocol {
+                ProtocolKind::Simple => match (a.numbers(), b.numbers()) {
+                    (Ok(a), Ok(b)) => {
+                        // simple only finds equal values at the same position
+                        let expected = a.iter().zip(b.iter()).filter(|(x, y)| x == y);
+                        let expected = expected.map(|(x, _)| x.to_string()).collect();
+                        let batch = DEFAULT_MAX_BATCH as usize;
+                        let run = run_local(
+                            &mut simple::NodeState::batched(&a, batch),
+                            &mut simple::NodeState::batched(&b, batch),
+                        );
+                        (
+                            expected,
+                            map_run(run, |common| common.iter().map(u32::to_string).collect()),
+                        )
+                    }
+                    (Err(error), _) | (_, Err(error)) => {
+                        return Comparison {
+                            protocol: *protocol,
+                            verdict: Verdict::Skipped(error.to_string()),
+                            run: None,
+                        };
+                    }
+                },
+                ProtocolKind::Challenge => {
+                    let (a, b) = (a.strings(), b.strings());
+                    let theirs: HashSet<&String> = b.iter().collect();
+                    let expected: HashSet<&String> = a
+                        .iter()
+                        .filter(|element| theirs.contains(element))
+                        .collect();
+                    let run = run_local(
+                        &mut challenge::Node::new(&a, NodeType::Leader),
+                        &mut challenge::Node::new(&b, NodeType::Follower),
+                    );
+                    (
+                        expected.into_iter().cloned().collect(),
+                        map_run(run, |common| common.into_iter().collect()),
+                    )
+                }
+            };
+            Comparison {
+                protocol: *protocol,
+                verdict: judge(sorted(expected), &run),
+                run: Some(run),
+            }
+        })
+        .collect()
+}
+
+impl Verdict {
+    fn summary(&self) -> String {
+        match self {
+            Verdict::Agrees(_) => "ok".to_string(),
+            Verdict::Disagrees { expected, .. } => {
+                format!("WRONG, expected {} in common", expected.len())
+            }
+            Verdict::Failed(error) => format!("FAILED, {error}"),
+            Verdict::Skipped(reason) => format!("skipped, {reason}"),
+        }
+    }
+}
+
+fn sorted(mut elements: Vec<String>) -> Vec<String> {
+    elements.sort();
+    elements
+}
+
+/// Turn either protocol's output into the sorted strings `compare` checks
+fn map_run<O>(run: LocalRun<O>, to_strings: impl Fn(O) -> Vec<String>) -> LocalRun<Vec<String>> {
+    let convert =
+        |outcome: Result<O, ProtocolError>| outcome.map(|output| sorted(to_strings(output)));
+    LocalRun {
+        leader: convert(run.leader),
+        follower: convert(run.follower),
+        stats: run.stats,
+        work: run.work,
+    }
+}
+
+fn judge(expected: Vec<String>, run: &LocalRun<Vec<String>>) -> Verdict {
+    match (&run.leader, &run.follower) {
+        (Err(error), _) | (_, Err(error)) => Verdict::Failed(error.clone()),
+        (Ok(leader), Ok(follower)) if *leader == expected && *follower == expected => {
+            Verdict::Agrees(expected.len())
+        }
+        (Ok(leader), Ok(follower)) => Verdict::Disagrees {
+            expected,
+            leader: leader.clone(),
+            follower: follower.clone(),
+        },
+    }
+}
+
+fn write_table(out: &mut impl Write, comparisons: &[Comparison]) -> io::Result<()> {
+    let row = |out: &mut dyn Write, cells: [&str; 6], result: &str| {
+        let [protocol, common, rounds, messages, bytes, time] = cells;
+        writeln!(
+            out,
+            "{protocol:<10} {common:>7} {rounds:>7} {messages:>9} {bytes:>10} {time:>12}  {result}"
+        )
+    };
+    row(
+        out,
+        ["protocol", "common", "rounds", "messages", "bytes", "time"],
+        "result",
+    )?;
+    for comparison in comparisons {
+        let result = comparison.verdict.summary();
+        let numbers = match &comparison.run {
+            None => ["-"; 5].map(String::from),
+            Some(run) => [
+                run.leader.as_ref().map_or(0, Vec::len).to_string(),
+                run.stats.rounds.to_string(),
+                run.stats.messages.to_string(),
+                run.stats.bytes.to_string(),
+                format!("{:.2?}", run.stats.elapsed),
+            ],
+        };
+        let [common, rounds, messages, bytes, time] = &numbers;
+        row(
+            out,
+            [
+                comparison.protocol.name(),
+                common,
+                rounds,
+                messages,
+                bytes,
+                time,
+            ],
+            &result,
+        )?;
+    }
+    Ok(())
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{input::Format, negotiate::NegotiationError};
+#[cfg(test)]
+use std::{fs, thread};
+
+/// A directory of one test's own for its input files, removed with them once dropped
+#[cfg(test)]
+struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// `test` and our pid keep runs in parallel or side by side from sharing a directory
+    fn new(test: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("treehopper-{test}-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn file(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[allow(unused)]
+fn input(elements: &[&str]) -> Input {
+    Input::Text(elements.iter().map(|s| s.to_string()).collect())
+}
+
+#[test]
+fn parses_commands() {
+    let cli = Cli::try_parse_from([
+        "treehopper",
+        "serve",
+        "--protocol",
+        "simple",
+        "--input",
+        "a.txt",
+        "--listen",
+        "127.0.0.1:9001",
+    ])
+    .unwrap();
+    assert!(matches!(
+        cli.command,
+        Command::Serve { protocol: ProtocolKind::Simple, listen, timeout: 30, .. }
+            if listen.port() == 9001
+    ));
+
+    let cli = Cli::try_parse_from([
+        "treehopper",
+        "connect",
+        "--input",
+        "b.txt",
+        "127.0.0.1:9000",
+    ])
+    .unwrap();
+    assert!(matches!(cli.command, Command::Connect { input, .. } if input == Path::new("b.txt")));
+
+    let error = Cli::try_parse_from(["treehopper", "serve", "--protocol", "range"]).unwrap_err();
+    assert!(error.use_stderr());
+
+    // an address is only needed without another transport, and only one transport at a time
+    let cli =
+        Cli::try_parse_from(["treehopper", "connect", "--input", "b.txt", "--stdio"]).unwrap();
+    assert!(cli.command.stdio());
+    assert!(Cli::try_parse_from(["treehopper", "connect", "--input", "b.txt"]).is_err());
+    let both = [
+        "treehopper",
+        "connect",
+        "--input",
+        "b.txt",
+        "--unix",
+        "s",
+        "--stdio",
+    ];
+    assert!(Cli::try_parse_from(both).is_err());
+}
+
+#[test]
+fn offers_what_input_can_run() {
+    let none = Normalizer::default();
+    assert_eq!(
+        offered(&input(&["1", "b"]), &none).protocols,
+        vec![ProtocolKind::Challenge]
+    );
+    assert_eq!(offered(&input(&["1", "2"]), &none), Hello::supported());
+    let trim = "trim".parse().unwrap();
+    assert_eq!(offered(&input(&["a"]), &trim).normalization, "trim");
+}
+
+#[test]
+fn input_formats_from_args() {
+    let cli = Cli::try_parse_from([
+        "treehopper",
+        "compare",
+        "--format",
+        "csv",
+        "--column",
+        "email",
+        "a.csv",
+        "b.csv",
+    ])
+    .unwrap();
+    let Command::Compare { format, .. } = cli.command else {
+        panic!("parsed {:?}", cli.command);
+    };
+    assert_eq!(format.format, Format::Csv);
+    assert_eq!(format.column.as_deref(), Some("email"));
+
+    let dir = TempDir::new("formats");
+    let a = dir.file("a.ndjson", "{\"id\": 3}\n{\"id\": 1}\n");
+    let b = dir.file("b.ndjson", "{\"id\": 1}\n{\"id\": 2}\n");
+    let cli = Cli::try_parse_from([
+        "treehopper".as_ref(),
+        "compare".as_ref(),
+        "--format=ndjson".as_ref(),
+        "--field=id".as_ref(),
+        "--protocol=challenge".as_ref(),
+        a.as_os_str(),
+        b.as_os_str(),
+    ])
+    .unwrap();
+    let mut out = vec![];
+    run(cli, &mut out).unwrap();
+    let table = String::from_utf8(out).unwrap();
+    assert!(table.lines().nth(1).unwrap().ends_with("ok"), "{table}");
+
+    let wrong = InputOptions {
+        format: Format::Json,
+        ..Default::default()
+    };
+    let error = read_input(&a, &wrong).unwrap_err();
+    assert!(matches!(error, CliError::Read(..)));
+    assert_eq!(error.exit_code(), EXIT_INPUT);
+}
+
+#[test]
+fn serve_and_connect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    let server = thread::spawn(move || {
+        serve(
+            &listener,
+            ProtocolKind::Challenge,
+            &input(&["b", "c", "d"]),
+            &Normalizer::default(),
+            timeout,
+        )
+    });
+    let common = connect(
+        address,
+        &input(&["c", "a", "b"]),
+        &Normalizer::default(),
+        timeout,
+    )
+    .unwrap();
+
+    assert_eq!(common.intersection.unwrap(), vec!["b", "c"]);
+    assert_eq!(common.local_only, Some(1));
+    let theirs = server.join().unwrap().unwrap();
+    assert_eq!(theirs.intersection.unwrap(), vec!["b", "c"]);
+    // both saw the same session
+    assert_eq!(theirs.role, Some("follower"));
+    assert_eq!(theirs.transcript, common.transcript);
+    assert_eq!(theirs.messages, common.messages);
+}
+
+#[test]
+fn serve_simple() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    let server = thread::spawn(move || {
+        serve(
+            &listener,
+            ProtocolKind::Simple,
+            &input(&["1", "2", "3"]),
+            &Normalizer::default(),
+            timeout,
+        )
+    });
+    let common = connect(
+        address,
+        &input(&["1", "5", "3"]),
+        &Normalizer::default(),
+        timeout,
+    )
+    .unwrap();
+
+    assert_eq!(common.intersection.unwrap(), vec!["1", "3"]);
+    let theirs = server.join().unwrap().unwrap();
+    assert_eq!(theirs.intersection.unwrap(), vec!["1", "3"]);
+}
+
+#[test]
+fn exit_codes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    // the server wants simple but our input can only run challenge
+    let none = Normalizer::default();
+    let server = thread::spawn(move || {
+        let none = Normalizer::default();
+        serve(
+            &listener,
+            ProtocolKind::Simple,
+            &input(&["1"]),
+            &none,
+            timeout,
+        )
+    });
+    let error = connect(address, &input(&["a"]), &none, timeout).unwrap_err();
+    assert!(matches!(
+        error,
+        CliError::Transport(TransportError::Negotiation(NegotiationError::PeerRejected(
+            _
+        )))
+    ));
+    assert_eq!(error.exit_code(), EXIT_PEER);
+    assert_eq!(server.join().unwrap().unwrap_err().exit_code(), EXIT_PEER);
+
+    // nobody listening any more
+    let error = connect(address, &input(&["a"]), &none, timeout).unwrap_err();
+    assert_eq!(error.exit_code(), EXIT_TRANSPORT);
+
+    let error = read_input(
+        Path::new("/nonexistent/treehopper"),
+        &InputOptions::default(),
+    );
+    let error = error.unwrap_err();
+    assert_eq!(error.exit_code(), EXIT_INPUT);
+}
+
+#[test]
+fn normalized_over_the_network() {
+    let dir = TempDir::new("normalized");
+    let a = dir.file("a", "Bob@X.com \nann@y.com\n");
+    let b = dir.file("b", "bob+list@x.com\nANN@Y.COM\ncy@z.com\n");
+    let options = InputOptions {
+        normalize: "trim,casefold,email".parse().unwrap(),
+        ..Default::default()
+    };
+    let (ours, theirs) = (
+        read_input(&a, &options).unwrap(),
+        read_input(&b, &options).unwrap(),
+    );
+    drop(dir);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let timeout = Duration::from_secs(5);
+    let normalizer = options.normalize.clone();
+    let server = thread::spawn(move || {
+        serve(
+            &listener,
+            ProtocolKind::Challenge,
+            &theirs,
+            &normalizer,
+            timeout,
+        )
+    });
+    let common = connect(address, &ours, &options.normalize, timeout).unwrap();
+    assert_eq!(common.intersection.unwrap(), vec!["ann@y.com", "bob@x.com"]);
+    assert_eq!(common.parameters.normalization, "trim,casefold,email");
+    let theirs = server.join().unwrap().unwrap();
+    assert_eq!(theirs.intersection.unwrap(), vec!["ann@y.com", "bob@x.com"]);
+
+    // the same data normalized differently would never match, so it doesn't run at all
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let none = Normalizer::default();
+        serve(
+            &listener,
+            ProtocolKind::Challenge,
+            &input(&["a"]),
+            &none,
+            timeout,
+        )
+    });
+    let error = connect(address, &input(&["a"]), &options.normalize, timeout).unwrap_err();
+    assert_eq!(error.exit_code(), EXIT_PEER);
+    assert!(matches!(
+        server.join().unwrap(),
+        Err(CliError::Transport(TransportError::Negotiation(
+            NegotiationError::NormalizationMismatch { .. }
+        )))
+    ));
+}
+
+#[test]
+fn compare_agrees_with_reference() {
+    let a = input(&["1", "2", "3", "4"]);
+    let b = input(&["1", "3", "2", "4", "9"]);
+    let comparisons = compare(&a, &b, &ProtocolChoice::All.protocols());
+
+    assert_eq!(comparisons.len(), 2);
+    for comparison in &comparisons {
+        let expected = match comparison.protocol {
+            // only 1 and 4 are in the same place
+            ProtocolKind::Simple => 2,
+            ProtocolKind::Challenge => 4,
+        };
+        assert_eq!(comparison.verdict, Verdict::Agrees(expected));
+        let run = comparison.run.as_ref().unwrap();
+        let stats = &run.stats;
+        assert!(stats.rounds > 0 && stats.messages >= stats.rounds && stats.bytes > stats.messages);
+    }
+}
+
+#[test]
+fn compare_skips_simple_on_text() {
+    let comparisons = compare(
+        &input(&["a", "b"]),
+        &input(&["b"]),
+        &ProtocolChoice::All.protocols(),
+    );
+    assert!(matches!(comparisons[0].verdict, Verdict::Skipped(_)));
+    assert!(comparisons[0].run.is_none());
+    assert_eq!(comparisons[1].verdict, Verdict::Agrees(1));
+
+    let mut table = vec![];
+    write_table(&mut table, &comparisons).unwrap();
+    let table = String::from_utf8(table).unwrap();
+    assert_eq!(table.lines().count(), 3);
+    assert!(table.lines().nth(1).unwrap().contains("skipped"));
+    assert!(table.lines().nth(2).unwrap().ends_with("ok"));
+}
+
+#[test]
+fn judges_wrong_answers() {
+    let run = |follower| LocalRun {
+        leader: Ok(vec!["a".to_string()]),
+        follower,
+        stats: Tally::default().stats(),
+        work: Work::default(),
+    };
+    let expected = vec!["a".to_string()];
+    assert_eq!(
+        judge(expected.clone(), &run(Ok(expected.clone()))),
+        Verdict::Agrees(1)
+    );
+    assert!(matches!(
+        judge(expected.clone(), &run(Ok(vec![]))),
+        Verdict::Disagrees { follower, .. } if follower.is_empty()
+    ));
+    assert_eq!(
+        judge(expected, &run(Err(ProtocolError::SessionClosed))),
+        Verdict::Failed(ProtocolError::SessionClosed)
+    );
+
+    let error = CliError::Mismatch(vec![ProtocolKind::Challenge]);
+    assert_eq!(error.exit_code(), EXIT_PEER);
+    assert_eq!(error.to_string(), "wrong answer from challenge");
+}
+
+#[test]
+fn parses_protocol_choice() {
+    assert_eq!("all".parse(), Ok(ProtocolChoice::All));
+    assert_eq!(
+        "simple".parse(),
+        Ok(ProtocolChoice::Only(ProtocolKind::Simple))
+    );
+    assert!("range".parse::<ProtocolChoice>().is_err());
+    let cli = Cli::try_parse_from(["treehopper", "compare", "a.txt", "b.txt"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Command::Compare {
+            protocol: ProtocolChoice::All,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn json_output() {
+    let dir = TempDir::new("json");
+    let a = dir.file("a", "x\ny\nz\n");
+    let b = dir.file("b", "y\nz\n");
+    let args = ["treehopper", "compare", "--output", "json"];
+    let cli = Cli::try_parse_from(
+        args.iter()
+            .copied()
+            .chain([a.to_str().unwrap(), b.to_str().unwrap()]),
+    )
+    .unwrap();
+    let mut out = vec![];
+    run(cli, &mut out).unwrap();
+    drop(dir);
+
+    let reports: Vec<serde_json::Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0]["protocol"], "simple");
+    assert_eq!(reports[0]["error"]["kind"], "input");
+    assert_eq!(reports[1]["protocol"], "challenge");
+    assert_eq!(reports[1]["intersection"], serde_json::json!(["y", "z"]));
+    assert_eq!(reports[1]["local_only"], 1);
+    assert!(reports[1]["error"].is_null() && reports[1]["messages"].as_u64() > Some(0));
+
+    // failing before anything runs still gives a report
+    let args = [
+        "treehopper",
+        "compare",
+        "--output",
+        "json",
+        "/nonexistent/a",
+        "b",
+    ];
+    let mut out = vec![];
+    let error = run(Cli::try_parse_from(args).unwrap(), &mut out).unwrap_err();
+    assert_eq!(error.exit_code(), EXIT_INPUT);
+    let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(report["error"]["kind"], "input");
+    assert!(report["protocol"].is_null());
+}
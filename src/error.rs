@@ -0,0 +1,99 @@
+//! Errors shared by every protocol.
+//!
+//! A `ProtocolError` is what a node puts in its `Fail` message, so it only holds plain owned data
+//! that can be sent to the peer as is
+
+use std::{error::Error, fmt};
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum ProtocolError {
+    /// We got a message that isn't valid in the state we're in
+    UnexpectedMessage { state: String, message: String },
+    /// The peer claimed to hold our element at `index` but couldn't prove it
+    VerificationFailed { index: usize },
+    /// The peer failed first and told us why
+    PeerFailed(Box<ProtocolError>),
+    /// A node was asked to do something its role can't, like a follower starting the protocol
+    RoleMisuse { role: String, action: String },
+    /// The node already finished with `Done` or `Fail` and won't handle anything else
+    SessionClosed,
+    /// The message was for the wrong step, e.g. a repeat of one we've already handled
+    OutOfSequence { expected: usize, received: usize },
+    /// A resumed session doesn't line up with the peer's, or our own data, so it can't carry on
+    ResumeMismatch,
+    /// The peer queried more at once than the batch size we agreed on
+    BatchTooLarge { limit: usize, received: usize },
+}
+
+impl ProtocolError {
+    pub fn unexpected(state: &str, message: &str) -> Self {
+        ProtocolError::UnexpectedMessage {
+            state: state.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+
+    /// Wrap the failure our peer sent us so we can pass it back along
+    pub fn peer(cause: ProtocolError) -> Self {
+        ProtocolError::PeerFailed(Box::new(cause))
+    }
+
+    pub fn role(role: &str, action: &str) -> Self {
+        ProtocolError::RoleMisuse {
+            role: role.to_owned(),
+            action: action.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedMessage { state, message } => {
+                write!(f, "unexpected {message} message while {state}")
+            }
+            ProtocolError::VerificationFailed { index } => {
+                write!(f, "peer failed verification of element {index}")
+            }
+            ProtocolError::PeerFailed(cause) => write!(f, "peer failed: {cause}"),
+            ProtocolError::RoleMisuse { role, action } => write!(f, "{role} cannot {action}"),
+            ProtocolError::SessionClosed => write!(f, "session already closed"),
+            ProtocolError::OutOfSequence { expected, received } => {
+                write!(f, "expected message {expected} but got {received}")
+            }
+            ProtocolError::ResumeMismatch => {
+                write!(f, "resume point doesn't match our checkpoint")
+            }
+            ProtocolError::BatchTooLarge { limit, received } => {
+                write!(f, "batch of {received} is over the {limit} agreed on")
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProtocolError::PeerFailed(cause) => Some(cause.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// tests
+
+#[test]
+fn peer_failure_chain() {
+    let error = ProtocolError::peer(ProtocolError::unexpected("Idle", "Start"));
+
+    assert_eq!(
+        error.to_string(),
+        "peer failed: unexpected Start message while Idle"
+    );
+    assert_eq!(
+        error.source().map(ToString::to_string),
+        Some("unexpected Start message while Idle".to_owned())
+    );
+}
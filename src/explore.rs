@@ -0,0 +1,324 @@
+//! Model checking for tiny sessions. `explore` runs a leader and a follower of any protocol through
+//! every order their messages could arrive in, dropping or duplicating up to `Limits` of them on the
+//! way, and reports every path that ends badly:
+//! - `Deadlock`, both sides waiting on each other with nothing in flight, though nothing was dropped
+//! - `Loop`, still going after `max_steps` deliveries
+//! - `Wrong`, a side finished with an answer `correct` doesn't accept, which no fault excuses
+//! - `Unfinished`, a side failed or was left waiting on a path with no faults at all
+//!
+//! Nodes are cloned at every branch, so they have to be deterministic (`challenge::Node::seeded`)
+//! for a path to mean the same thing twice. Like `drive` a node stops reading once it's finished, and
+//! the reply to a terminal message is never sent. States already seen are skipped, a state being
+//! what each node has received plus what's in flight
+
+use std::{
+    collections::{HashSet, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+};
+
+use crate::error::ProtocolError;
+use crate::protocol::{Message, Protocol};
+use crate::transport::Role;
+use crate::wire::Wire;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// messages that can be lost on any one path
+    pub drops: usize,
+    pub duplicates: usize,
+    /// deliveries before a path counts as a loop
+    pub max_steps: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            drops: 1,
+            duplicates: 1,
+            max_steps: 64,
+        }
+    }
+}
+
+/// One thing the network did, `to` is who the message was for
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub enum Step<M> {
+    Deliver { to: Role, message: M },
+    Drop { to: Role, message: M },
+    Duplicate { to: Role, message: M },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Problem {
+    Deadlock,
+    Loop,
+    Wrong(Role),
+    Unfinished(Role),
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct Violation<M> {
+    pub problem: Problem,
+    /// how the network got there from the leader's first message
+    pub trace: Vec<Step<M>>,
+}
+
+#[derive(Debug)]
+pub struct Exploration<M> {
+    /// distinct states visited
+    pub states: usize,
+    pub violations: Vec<Violation<M>>,
+}
+
+#[derive(Clone)]
+struct World<P: Protocol> {
+    leader: P,
+    follower: P,
+    in_flight: Vec<(Role, P::Message)>,
+    drops: usize,
+    duplicates: usize,
+    /// hash of everything each node has received, in order
+    received: [u64; 2],
+    trace: Vec<Step<P::Message>>,
+}
+
+impl<P: Protocol + Clone> World<P> {
+    fn node(&mut self, role: Role) -> &mut P {
+        match role {
+            Role::Leader => &mut self.leader,
+            Role::Follower => &mut self.follower,
+        }
+    }
+
+    fn key(&self) -> u64 {
+        let mut in_flight: Vec<(u8, Vec<u8>)> = self
+            .in_flight
+            .iter()
+            .map(|(to, message)| (*to as u8, message.encode()))
+            .collect();
+        in_flight.sort();
+        let mut hasher = DefaultHasher::new();
+        (self.received, in_flight, self.drops, self.duplicates).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn faulty(&self) -> bool {
+        self.trace
+            .iter()
+            .any(|step| !matches!(step, Step::Deliver { .. }))
+    }
+
+    fn dropped(&self) -> bool {
+        self.trace
+            .iter()
+            .any(|step| matches!(step, Step::Drop { .. }))
+    }
+
+    fn deliver(&self, index: usize) -> Self {
+        let mut next = self.clone();
+        let (to, message) = next.in_flight.remove(index);
+        let mut hasher = DefaultHasher::new();
+        (next.received[to as usize], message.encode()).hash(&mut hasher);
+        next.received[to as usize] = hasher.finish();
+        next.trace.push(Step::Deliver {
+            to,
+            message: message.clone(),
+        });
+        let hung_up = message.is_terminal();
+        let reply = next.node(to).receive(message);
+        if !hung_up {
+            next.in_flight.push((to.peer(), reply));
+        }
+        next
+    }
+
+    fn drop(&self, index: usize) -> Self {
+        let mut next = self.clone();
+        let (to, message) = next.in_flight.remove(index);
+        next.drops -= 1;
+        next.trace.push(Step::Drop { to, message });
+        next
+    }
+
+    fn duplicate(&self, index: usize) -> Self {
+        let mut next = self.clone();
+        let (to, message) = next.in_flight[index].clone();
+        next.in_flight.push((to, message.clone()));
+        next.duplicates -= 1;
+        next.trace.push(Step::Duplicate { to, message });
+        next
+    }
+
+    /// Nothing left that anyone will read, did it end well
+    fn judge(&self, correct: &impl Fn(Role, &P::Output) -> bool) -> Option<Problem> {
+        let outcomes = [
+            (Role::Leader, self.leader.outcome()),
+            (Role::Follower, self.follower.outcome()),
+        ];
+        if outcomes.iter().all(|(_, outcome)| outcome.is_none()) && !self.dropped() {
+            return Some(Problem::Deadlock);
+        }
+        outcomes
+            .into_iter()
+            .find_map(|(role, outcome)| match outcome {
+                Some(Ok(output)) if !correct(role, &output) => Some(Problem::Wrong(role)),
+                Some(Ok(_)) => None,
+                Some(Err(_)) | None if self.faulty() => None,
+                Some(Err(_)) | None => Some(Problem::Unfinished(role)),
+            })
+    }
+}
+
+/// Try every way the network could deliver a session between `leader` and `follower` within
+/// `limits`, `correct` says whether a side's output is the right answer
+pub fn explore<P>(
+    mut leader: P,
+    follower: P,
+    limits: Limits,
+    correct: impl Fn(Role, &P::Output) -> bool,
+) -> Exploration<P::Message>
+where
+    P: Protocol + Clone,
+{
+    let first = leader.start();
+    let mut stack = vec![World {
+        leader,
+        follower,
+        in_flight: vec![(Role::Follower, first)],
+        drops: limits.drops,
+        duplicates: limits.duplicates,
+        received: [0; 2],
+        trace: vec![],
+    }];
+    let mut seen = HashSet::new();
+    let mut violations = vec![];
+    while let Some(mut world) = stack.pop() {
+        // finished nodes have stopped reading, whatever's on its way to them is lost
+        let finished = [world.leader.is_finished(), world.follower.is_finished()];
+        world.in_flight.retain(|(to, _)| !finished[*to as usize]);
+        if !seen.insert(world.key()) {
+            continue;
+        }
+        let steps = world
+            .trace
+            .iter()
+            .filter(|step| matches!(step, Step::Deliver { .. }))
+            .count();
+        if steps > limits.max_steps {
+            violations.push(Violation {
+                problem: Problem::Loop,
+                trace: world.trace,
+            });
+            continue;
+        }
+        if world.in_flight.is_empty() {
+            if let Some(problem) = world.judge(&correct) {
+                violations.push(Violation {
+                    problem,
+                    trace: world.trace,
+                });
+            }
+            continue;
+        }
+        for index in 0..world.in_flight.len() {
+            stack.push(world.deliver(index));
+            if world.drops > 0 {
+                stack.push(world.drop(index));
+            }
+            if world.duplicates > 0 {
+                stack.push(world.duplicate(index));
+            }
+        }
+    }
+    Exploration {
+        states: seen.len(),
+        violations,
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::simple;
+
+/// Never hangs up, answers everything with another query
+#[derive(Clone)]
+struct Chatty;
+
+impl Protocol for Chatty {
+    type Message = simple::NodeMessage;
+    type Output = ();
+
+    fn start(&mut self) -> Self::Message {
+        simple::NodeMessage::HasQuery {
+            location: 0,
+            values: vec![0],
+        }
+    }
+
+    fn receive(&mut self, _message: Self::Message) -> Self::Message {
+        self.start()
+    }
+
+    fn is_finished(&self) -> bool {
+        false
+    }
+
+    fn outcome(&self) -> Option<Result<(), ProtocolError>> {
+        None
+    }
+}
+
+/// Hangs up on the first message without ever finishing itself
+#[derive(Clone)]
+struct Rude(bool);
+
+impl Protocol for Rude {
+    type Message = simple::NodeMessage;
+    type Output = ();
+
+    fn start(&mut self) -> Self::Message {
+        simple::NodeMessage::End
+    }
+
+    fn receive(&mut self, _message: Self::Message) -> Self::Message {
+        simple::NodeMessage::End
+    }
+
+    fn is_finished(&self) -> bool {
+        false
+    }
+
+    fn outcome(&self) -> Option<Result<(), ProtocolError>> {
+        self.0.then_some(Ok(()))
+    }
+}
+
+#[test]
+fn finds_loops_and_deadlocks() {
+    let limits = Limits {
+        max_steps: 8,
+        ..Limits::default()
+    };
+    let explored = explore(Chatty, Chatty, limits, |_, _| true);
+    assert!(
+        explored
+            .violations
+            .iter()
+            .any(|violation| violation.problem == Problem::Loop)
+    );
+
+    let explored = explore(Rude(false), Rude(false), Limits::default(), |_, _| true);
+    let problems: Vec<Problem> = explored.violations.iter().map(|v| v.problem).collect();
+    assert!(problems.contains(&Problem::Deadlock), "{problems:?}");
+
+    // one side thinks it worked, but with the wrong answer
+    let explored = explore(Rude(true), Rude(true), Limits::default(), |_, _| false);
+    let problems: Vec<Problem> = explored.violations.iter().map(|v| v.problem).collect();
+    assert!(
+        problems.contains(&Problem::Wrong(Role::Leader)),
+        "{problems:?}"
+    );
+}
@@ -0,0 +1,251 @@
+//! Serde helper for hashes, nonces and salts, use with `#[serde(with = "crate::hex")]`.
+//!
+//! Human readable formats like JSON get a lowercase hex string, binary ones like CBOR get the raw bytes
+
+use std::{fmt, marker::PhantomData};
+
+use serde::{
+    Deserializer, Serializer,
+    de::{self, SeqAccess, Visitor},
+};
+
+pub fn serialize<S, T>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: AsRef<[u8]>,
+{
+    let bytes = bytes.as_ref();
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&encode(bytes))
+    } else {
+        serializer.serialize_bytes(bytes)
+    }
+}
+
+pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<Vec<u8>>,
+{
+    let visitor = BytesVisitor(PhantomData);
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_str(visitor)
+    } else {
+        deserializer.deserialize_bytes(visitor)
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+struct BytesVisitor<T>(PhantomData<T>);
+
+impl<T: TryFrom<Vec<u8>>> BytesVisitor<T> {
+    /// Fixed size fields like nonces reject the wrong number of bytes here
+    fn finish<E: de::Error>(bytes: Vec<u8>) -> Result<T, E> {
+        let len = bytes.len();
+        T::try_from(bytes).map_err(|_| E::invalid_length(len, &"a byte array of the right size"))
+    }
+}
+
+impl<'de, T: TryFrom<Vec<u8>>> Visitor<'de> for BytesVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a hex string or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        match decode(value) {
+            Some(bytes) => Self::finish(bytes),
+            None => Err(E::invalid_value(de::Unexpected::Str(value), &self)),
+        }
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<T, E> {
+        Self::finish(value.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<T, E> {
+        Self::finish(value)
+    }
+
+    /// Some binary formats fall back to a list of numbers for bytes
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1024));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Self::finish(bytes)
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, ChallengeReponsePair},
+    error::ProtocolError,
+    simple,
+};
+
+#[test]
+fn json_uses_hex() {
+    let message = challenge::NodeMessage::ChallengeReponse {
+        index: 2,
+        proof: Some(ChallengeReponsePair {
+            salt: [0xab; 32],
+            hash: vec![0, 1, 0xff],
+        }),
+    };
+
+    let json = serde_json::to_string(&message).unwrap();
+    assert_eq!(
+        json,
+        format!(
+            r#"{{"ChallengeReponse":{{"index":2,"proof":{{"salt":"{}","hash":"0001ff"}}}}}}"#,
+            "ab".repeat(32)
+        )
+    );
+    assert_eq!(
+        serde_json::from_str::<challenge::NodeMessage>(&json).unwrap(),
+        message
+    );
+
+    let start = challenge::NodeMessage::Start { nonce: [1; 32] };
+    let json = serde_json::to_string(&start).unwrap();
+    assert_eq!(
+        json,
+        format!(r#"{{"Start":{{"nonce":"{}"}}}}"#, "01".repeat(32))
+    );
+}
+
+#[test]
+fn json_rejects_bad_hex() {
+    for json in [
+        r#"{"ChallengeQuery":{"index":0,"hash":"abc"}}"#,
+        r#"{"ChallengeQuery":{"index":0,"hash":"zz"}}"#,
+        r#"{"ChallengeQuery":{"index":0,"hash":"é0"}}"#,
+        // nonces have to be exactly 32 bytes
+        r#"{"Start":{"nonce":"0001"}}"#,
+    ] {
+        assert!(serde_json::from_str::<challenge::NodeMessage>(json).is_err());
+    }
+}
+
+#[test]
+fn cbor_uses_bytes() {
+    let message = challenge::NodeMessage::ChallengeQuery {
+        index: 0,
+        hash: vec![0xaa; 32],
+    };
+
+    let mut cbor = vec![];
+    ciborium::into_writer(&message, &mut cbor).unwrap();
+    // the hash goes in as a 32 byte string (major type 2), not as text
+    assert!(
+        cbor.windows(34)
+            .any(|w| w[..2] == [0x58, 32] && w[2..] == [0xaa; 32])
+    );
+    assert_eq!(
+        ciborium::from_reader::<challenge::NodeMessage, _>(cbor.as_slice()).unwrap(),
+        message
+    );
+}
+
+#[test]
+fn everything_round_trips() {
+    let messages = [
+        challenge::NodeMessage::Start { nonce: [1; 32] },
+        challenge::NodeMessage::Initialize { nonce: [2; 32] },
+        challenge::NodeMessage::ChallengeReponse {
+            index: 5,
+            proof: None,
+        },
+        challenge::NodeMessage::Fail(ProtocolError::peer(ProtocolError::VerificationFailed {
+            index: 3,
+        })),
+        challenge::NodeMessage::Resume {
+            session_id: [3; 32],
+            position: 4,
+            transcript: [5; 32],
+        },
+        challenge::NodeMessage::Done,
+    ];
+    for message in messages {
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            serde_json::from_str::<challenge::NodeMessage>(&json).unwrap(),
+            message
+        );
+        let mut cbor = vec![];
+        ciborium::into_writer(&message, &mut cbor).unwrap();
+        assert_eq!(
+            ciborium::from_reader::<challenge::NodeMessage, _>(cbor.as_slice()).unwrap(),
+            message
+        );
+    }
+
+    let simple = simple::NodeMessage::HasResponse {
+        location: 1,
+        has: vec![true],
+    };
+    let json = serde_json::to_string(&simple).unwrap();
+    assert_eq!(json, r#"{"HasResponse":{"location":1,"has":[true]}}"#);
+    assert_eq!(
+        serde_json::from_str::<simple::NodeMessage>(&json).unwrap(),
+        simple
+    );
+}
+
+#[test]
+fn results_serialize() {
+    let data = vec!["a".to_owned(), "b".to_owned()];
+    let mut leader = challenge::Node::new(&data, challenge::NodeType::Leader);
+    let mut follower = challenge::Node::new(&data, challenge::NodeType::Follower);
+    let mut message = leader.start();
+    while !leader.is_finished() {
+        message = leader.recieve_message(follower.recieve_message(message));
+    }
+
+    let json = serde_json::to_value(leader.outcome().unwrap().unwrap()).unwrap();
+    let mut common: Vec<String> = serde_json::from_value(json).unwrap();
+    common.sort();
+    assert_eq!(common, data);
+
+    let error = ProtocolError::unexpected("Idle", "Start");
+    let json = serde_json::to_string(&error).unwrap();
+    assert_eq!(
+        json,
+        r#"{"UnexpectedMessage":{"state":"Idle","message":"Start"}}"#
+    );
+}
+
+#[test]
+fn checkpoint_serializes() {
+    let data = vec!["a".to_owned(), "b".to_owned()];
+    let mut leader = challenge::Node::new(&data, challenge::NodeType::Leader);
+    let mut follower = challenge::Node::new(&data, challenge::NodeType::Follower);
+    let init = follower.recieve_message(leader.start());
+    follower.recieve_message(leader.recieve_message(init));
+
+    // a follower checkpoint carries its last answer too
+    let checkpoint = follower.checkpoint().unwrap();
+    let json = serde_json::to_string(&checkpoint).unwrap();
+    let salt = serde_json::to_value(&checkpoint).unwrap()["salt"].clone();
+    assert_eq!(salt.as_str().map(str::len), Some(64));
+    let restored: challenge::Checkpoint = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored, checkpoint);
+    assert!(challenge::Node::resume(&data, &restored).is_ok());
+}
@@ -0,0 +1,536 @@
+//! Reading a peer's set from a file. Plain text is one element per line, the other formats pick one
+//! value out of every record:
+//! - `csv` takes `--column` by header name, or the first column
+//! - `json` is one array, `ndjson` one value per line, both take `--field` as a dotted path into
+//!   each value (`user.email`, `tags.0`) or the whole value if it's already a string or number
+//! - `u32` is raw little endian numbers back to back, what `simple` runs on
+//!
+//! Files are read a record at a time so only the elements we keep are ever held, even a big JSON
+//! array never sits in memory as a whole document. Empty values are skipped in every format, a
+//! record without the column or field asked for is an error rather than a silent gap.
+//! Text elements then go through `--normalize` if there is one
+
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use clap::{Args, ValueEnum};
+use serde::de::{self, DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde_json::Value;
+
+use crate::normalize::Normalizer;
+
+#[derive(ValueEnum, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Format {
+    #[default]
+    Lines,
+    Csv,
+    Json,
+    Ndjson,
+    U32,
+}
+
+/// How to read an input file, shared by every command that takes one
+#[derive(Args, PartialEq, Debug, Clone, Default)]
+pub struct InputOptions {
+    #[arg(long, value_enum, default_value_t = Format::Lines)]
+    pub format: Format,
+    /// csv column to read, by header name
+    #[arg(long)]
+    pub column: Option<String>,
+    /// json or ndjson field to read, as a dotted path
+    #[arg(long)]
+    pub field: Option<String>,
+    /// steps to clean up text elements with before hashing, e.g. `trim,casefold,email`, both
+    /// peers have to use the same
+    #[arg(long, default_value_t)]
+    pub normalize: Normalizer,
+}
+
+#[derive(Debug)]
+pub enum InputError {
+    Io(io::Error),
+    /// an option that doesn't go with the format, like `--column` on json
+    Option(&'static str),
+    NoColumn(String),
+    /// record numbered from 1, a line for line based formats or an element of a json array
+    Record {
+        record: usize,
+        reason: String,
+    },
+    /// a json array that didn't parse, or an element in it we couldn't use
+    Json(serde_json::Error),
+    /// a `u32` file that doesn't end on a whole number
+    Truncated,
+    /// an element `simple` can't use
+    NotANumber {
+        record: usize,
+        value: String,
+    },
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Io(error) => write!(f, "{error}"),
+            InputError::Option(problem) => write!(f, "{problem}"),
+            InputError::NoColumn(column) => write!(f, "no column called {column:?}"),
+            InputError::Record { record, reason } => write!(f, "record {record}: {reason}"),
+            InputError::Json(error) => write!(f, "{error}"),
+            InputError::Truncated => write!(f, "length isn't a multiple of 4 bytes"),
+            InputError::NotANumber { record, value } => {
+                write!(
+                    f,
+                    "record {record} ({value:?}) isn't a u32, which simple needs"
+                )
+            }
+        }
+    }
+}
+
+impl Error for InputError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InputError::Io(error) => Some(error),
+            InputError::Json(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for InputError {
+    fn from(error: io::Error) -> Self {
+        InputError::Io(error)
+    }
+}
+
+/// Our set, as read from the input file
+#[derive(PartialEq, Debug, Clone)]
+pub enum Input {
+    Text(Vec<String>),
+    Numbers(Vec<u32>),
+}
+
+impl Input {
+    pub fn read(path: &Path, options: &InputOptions) -> Result<Input, InputError> {
+        Input::from_reader(BufReader::new(File::open(path)?), options)
+    }
+
+    pub fn from_reader(reader: impl BufRead, options: &InputOptions) -> Result<Input, InputError> {
+        if options.column.is_some() && options.format != Format::Csv {
+            return Err(InputError::Option("--column only goes with --format csv"));
+        }
+        if options.field.is_some() && !matches!(options.format, Format::Json | Format::Ndjson) {
+            return Err(InputError::Option(
+                "--field only goes with --format json or ndjson",
+            ));
+        }
+        if options.normalize != Normalizer::default() && options.format == Format::U32 {
+            return Err(InputError::Option(
+                "--normalize only goes with text formats",
+            ));
+        }
+        let field = options.field.as_deref();
+        let elements = match options.format {
+            Format::Lines => read_lines(reader)?,
+            Format::Csv => read_csv(reader, options.column.as_deref())?,
+            Format::Json => read_json(reader, field)?,
+            Format::Ndjson => read_ndjson(reader, field)?,
+            Format::U32 => return read_u32(reader).map(Input::Numbers),
+        };
+        let mut elements = options.normalize.apply_all(elements);
+        // normalizing can empty a value that wasn't, like `trim` on one that's only spaces
+        elements.retain(|element| !element.is_empty());
+        Ok(Input::Text(elements))
+    }
+
+    /// Every element as text, what `challenge` hashes
+    pub fn strings(&self) -> Cow<'_, [String]> {
+        match self {
+            Input::Text(elements) => Cow::Borrowed(elements),
+            Input::Numbers(numbers) => Cow::Owned(numbers.iter().map(u32::to_string).collect()),
+        }
+    }
+
+    /// The input as `simple` wants it, or the first element that isn't a number
+    // a `Vec` because that's what `simple::NodeState` borrows
+    #[allow(clippy::owned_cow)]
+    pub fn numbers(&self) -> Result<Cow<'_, Vec<u32>>, InputError> {
+        match self {
+            Input::Numbers(numbers) => Ok(Cow::Borrowed(numbers)),
+            Input::Text(elements) => elements
+                .iter()
+                .enumerate()
+                .map(|(index, element)| {
+                    element.parse().map_err(|_| InputError::NotANumber {
+                        record: index + 1,
+                        value: element.clone(),
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map(Cow::Owned),
+        }
+    }
+}
+
+fn read_lines(reader: impl BufRead) -> Result<Vec<String>, InputError> {
+    let mut elements = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            elements.push(line);
+        }
+    }
+    Ok(elements)
+}
+
+fn read_csv(reader: impl BufRead, column: Option<&str>) -> Result<Vec<String>, InputError> {
+    let mut csv = csv::Reader::from_reader(reader);
+    let index = match column {
+        None => 0,
+        Some(name) => csv
+            .headers()
+            .map_err(|error| record_error(1, error))?
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| InputError::NoColumn(name.to_string()))?,
+    };
+    let mut elements = vec![];
+    let mut record = csv::StringRecord::new();
+    // the header is record 1
+    for number in 2.. {
+        match csv.read_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) => match record.get(index) {
+                Some("") => {}
+                Some(value) => elements.push(value.to_string()),
+                None => return Err(record_error(number, "column missing")),
+            },
+            Err(error) => return Err(record_error(number, error)),
+        }
+    }
+    Ok(elements)
+}
+
+fn read_json(reader: impl BufRead, field: Option<&str>) -> Result<Vec<String>, InputError> {
+    let mut elements = vec![];
+    let mut json = serde_json::Deserializer::from_reader(reader);
+    let seed = Elements {
+        field,
+        elements: &mut elements,
+    };
+    seed.deserialize(&mut json)
+        .and_then(|()| json.end())
+        .map_err(InputError::Json)?;
+    Ok(elements)
+}
+
+fn read_ndjson(reader: impl BufRead, field: Option<&str>) -> Result<Vec<String>, InputError> {
+    let mut elements = vec![];
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value =
+            serde_json::from_str(&line).map_err(|error| record_error(index + 1, error))?;
+        if let Some(element) =
+            pick(&value, field).map_err(|reason| record_error(index + 1, reason))?
+        {
+            elements.push(element);
+        }
+    }
+    Ok(elements)
+}
+
+fn read_u32(mut reader: impl BufRead) -> Result<Vec<u32>, InputError> {
+    let mut numbers = vec![];
+    while !reader.fill_buf()?.is_empty() {
+        let mut bytes = [0; 4];
+        reader
+            .read_exact(&mut bytes)
+            .map_err(|error| match error.kind() {
+                io::ErrorKind::UnexpectedEof => InputError::Truncated,
+                _ => InputError::Io(error),
+            })?;
+        numbers.push(u32::from_le_bytes(bytes));
+    }
+    Ok(numbers)
+}
+
+fn record_error(record: usize, reason: impl fmt::Display) -> InputError {
+    InputError::Record {
+        record,
+        reason: reason.to_string(),
+    }
+}
+
+/// The element at `field` in `value`, `None` if it's empty
+fn pick(value: &Value, field: Option<&str>) -> Result<Option<String>, String> {
+    let mut value = value;
+    for key in field.into_iter().flat_map(|field| field.split('.')) {
+        let next = match value {
+            Value::Object(object) => object.get(key),
+            Value::Array(array) => key.parse().ok().and_then(|index: usize| array.get(index)),
+            _ => None,
+        };
+        value = next.ok_or_else(|| format!("no {key:?} in {}", field.unwrap_or_default()))?;
+    }
+    match value {
+        Value::String(string) if string.is_empty() => Ok(None),
+        Value::String(string) => Ok(Some(string.clone())),
+        Value::Number(number) => Ok(Some(number.to_string())),
+        other => Err(format!("expected a string or number, got {other}")),
+    }
+}
+
+/// Pulls the elements out of a json array one at a time, so the array is never held whole
+struct Elements<'a> {
+    field: Option<&'a str>,
+    elements: &'a mut Vec<String>,
+}
+
+impl<'de> DeserializeSeed<'de> for Elements<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Elements<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of elements")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut record = 0;
+        while let Some(value) = seq.next_element::<Value>()? {
+            record += 1;
+            match pick(&value, self.field) {
+                Ok(Some(element)) => self.elements.push(element),
+                Ok(None) => {}
+                Err(reason) => {
+                    return Err(de::Error::custom(format!("record {record}: {reason}")));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// tests
+
+#[allow(unused)]
+fn read(text: &str, format: Format, column: Option<&str>, field: Option<&str>) -> Input {
+    let options = InputOptions {
+        format,
+        column: column.map(String::from),
+        field: field.map(String::from),
+        ..Default::default()
+    };
+    Input::from_reader(text.as_bytes(), &options).unwrap()
+}
+
+#[allow(unused)]
+fn text(elements: &[&str]) -> Input {
+    Input::Text(elements.iter().map(|s| s.to_string()).collect())
+}
+
+#[test]
+fn lines() {
+    let input = read("b\r\n\na\nb\n", Format::Lines, None, None);
+    assert_eq!(input, text(&["b", "a", "b"]));
+    assert!(input.numbers().is_err());
+
+    let numbers = read("1\n2\n\n3", Format::Lines, None, None);
+    assert_eq!(numbers.numbers().unwrap().as_ref(), &vec![1, 2, 3]);
+    assert!(matches!(
+        read("1\nx\n", Format::Lines, None, None).numbers(),
+        Err(InputError::NotANumber { record: 2, value }) if value == "x"
+    ));
+}
+
+#[test]
+fn csv_columns() {
+    let csv = "name,email\nbob,bob@x.com\n\"smith, al\",\nann,\"ann@y.com\"\n";
+    assert_eq!(
+        read(csv, Format::Csv, Some("email"), None),
+        text(&["bob@x.com", "ann@y.com"])
+    );
+    assert_eq!(
+        read(csv, Format::Csv, None, None),
+        text(&["bob", "smith, al", "ann"])
+    );
+
+    let options = InputOptions {
+        format: Format::Csv,
+        column: Some("phone".into()),
+        field: None,
+        ..Default::default()
+    };
+    assert!(matches!(
+        Input::from_reader(csv.as_bytes(), &options),
+        Err(InputError::NoColumn(column)) if column == "phone"
+    ));
+    let ragged = "a,b\n1,2\n3\n";
+    assert!(matches!(
+        Input::from_reader(ragged.as_bytes(), &options),
+        Err(InputError::NoColumn(_))
+    ));
+    let options = InputOptions {
+        column: Some("b".into()),
+        ..options
+    };
+    assert!(matches!(
+        Input::from_reader(ragged.as_bytes(), &options),
+        Err(InputError::Record { record: 3, .. })
+    ));
+}
+
+#[test]
+fn json_arrays() {
+    assert_eq!(
+        read(r#"["a", 7, "", "ünï"]"#, Format::Json, None, None),
+        text(&["a", "7", "ünï"])
+    );
+    let json = r#"[{"user": {"email": "a@x"}}, {"user": {"email": "b@x"}, "tags": []}]"#;
+    assert_eq!(
+        read(json, Format::Json, None, Some("user.email")),
+        text(&["a@x", "b@x"])
+    );
+    assert_eq!(
+        read(
+            r#"[{"tags": ["x", "y"]}]"#,
+            Format::Json,
+            None,
+            Some("tags.1")
+        ),
+        text(&["y"])
+    );
+
+    let options = InputOptions {
+        format: Format::Json,
+        column: None,
+        field: Some("user.phone".into()),
+        ..Default::default()
+    };
+    let error = Input::from_reader(json.as_bytes(), &options).unwrap_err();
+    assert!(error.to_string().contains("record 1"), "{error}");
+    for bad in [r#"{"a": 1}"#, r#"["a", true]"#, r#"["a"] ["b"]"#, r#"["a""#] {
+        let options = InputOptions {
+            format: Format::Json,
+            ..Default::default()
+        };
+        assert!(
+            Input::from_reader(bad.as_bytes(), &options).is_err(),
+            "{bad}"
+        );
+    }
+}
+
+#[test]
+fn ndjson_records() {
+    let ndjson = "{\"id\": 1}\n\n{\"id\": 2}\n";
+    assert_eq!(
+        read(ndjson, Format::Ndjson, None, Some("id")),
+        text(&["1", "2"])
+    );
+    let options = InputOptions {
+        format: Format::Ndjson,
+        column: None,
+        field: Some("id".into()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        Input::from_reader("{\"id\": 1}\n{\"id\"\n".as_bytes(), &options),
+        Err(InputError::Record { record: 2, .. })
+    ));
+}
+
+#[test]
+fn raw_u32() {
+    let bytes: Vec<u8> = [1u32, 70000, u32::MAX]
+        .iter()
+        .flat_map(|n| n.to_le_bytes())
+        .collect();
+    let options = InputOptions {
+        format: Format::U32,
+        ..Default::default()
+    };
+    let input = Input::from_reader(bytes.as_slice(), &options).unwrap();
+    assert_eq!(input, Input::Numbers(vec![1, 70000, u32::MAX]));
+    assert_eq!(input.strings().as_ref(), ["1", "70000", "4294967295"]);
+    assert!(matches!(input.numbers(), Ok(Cow::Borrowed(_))));
+
+    assert!(matches!(
+        Input::from_reader(&bytes[..5], &options),
+        Err(InputError::Truncated)
+    ));
+    assert_eq!(
+        Input::from_reader(&[][..], &options).unwrap(),
+        Input::Numbers(vec![])
+    );
+}
+
+#[test]
+fn options_must_fit_format() {
+    let options = InputOptions {
+        format: Format::Json,
+        column: Some("email".into()),
+        field: None,
+        ..Default::default()
+    };
+    assert!(matches!(
+        Input::from_reader(&b"[]"[..], &options),
+        Err(InputError::Option(_))
+    ));
+    let options = InputOptions {
+        format: Format::Lines,
+        column: None,
+        field: Some("email".into()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        Input::from_reader(&b""[..], &options),
+        Err(InputError::Option(_))
+    ));
+}
+
+#[test]
+fn normalizes_text() {
+    let options = InputOptions {
+        format: Format::Csv,
+        normalize: "trim,casefold".parse().unwrap(),
+        ..Default::default()
+    };
+    let input = Input::from_reader(&b"name\n Ann \nBOB\n"[..], &options).unwrap();
+    assert_eq!(input, text(&["ann", "bob"]));
+
+    let options = InputOptions {
+        format: Format::U32,
+        ..options
+    };
+    assert!(matches!(
+        Input::from_reader(&[][..], &options),
+        Err(InputError::Option(_))
+    ));
+}
+
+#[test]
+fn empty_after_normalizing() {
+    let options = InputOptions {
+        format: Format::Csv,
+        normalize: "trim".parse().unwrap(),
+        ..Default::default()
+    };
+    let input = Input::from_reader(&b"name\na\n   \n\" \"\nb\n"[..], &options).unwrap();
+    assert_eq!(input, text(&["a", "b"]));
+}
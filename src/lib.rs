@@ -0,0 +1,37 @@
+//! Following modules are individual exercises for me trying to make a private set intersection protocol
+//! - Simple - Check two arrays of numbers are the same by blindly trusting other node
+//! - Challenge - Use a two sided protocol where one sets a common salt and the other issues challenges
+//!
+//! `error` holds the failures every protocol shares and `wire` turns their messages into bytes.
+//! With the `serde` feature messages and errors can also go through any serde format.
+//! Tests throw `adversary` peers at them that replay, reorder, drop, forge and flood messages, and
+//! `explore` tries every way the network could deliver a tiny session
+//!
+//! `protocol` is the api all the nodes share, which is what `transport` uses to run them over a connection.
+//! `negotiate` lets two peers agree on which one to run first, and `cli` is the binary on top of all that,
+//! reading its sets through `input` and cleaning them up with `normalize`. Every run it does can be
+//! summed up as a `report`.
+//!
+//! The binary itself only calls `cli::main`, everything lives here so the fuzz targets in `fuzz/` can
+//! get at it too. The `arbitrary` feature lets them generate messages directly
+
+#[cfg(test)]
+mod adversary;
+pub mod challenge;
+pub mod cli;
+pub mod error;
+#[cfg(test)]
+mod explore;
+#[cfg(feature = "serde")]
+mod hex;
+pub mod input;
+pub mod negotiate;
+pub mod normalize;
+pub mod protocol;
+pub mod report;
+pub mod simple;
+// work in progress, nothing drives it yet
+#[allow(unused)]
+mod third;
+pub mod transport;
+pub mod wire;
@@ -1,9 +1,3 @@
-//! Following modules are individual exercises for me trying to make a private set intersection protocol
-//! - Simple - Check two arrays of numbers are the same by blindly trusting other node
-//! - Challenge - Use a two sided protocol where one sets a common salt and the other issues challenges
-
-mod challenge;
-mod simple;
-mod third;
-
-fn main() {}
+fn main() -> std::process::ExitCode {
+    treehopper::cli::main()
+}
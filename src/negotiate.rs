@@ -0,0 +1,505 @@
+//! Agreeing on what to run before a session starts. The leader sends a `Hello` listing everything it
+//! supports, the follower picks the best option both have and answers with a `HelloAck`, or refuses
+//! with why there's no overlap.
+//!
+//! Selection is deterministic and doesn't care about list order: the newest wire version, the
+//! strongest protocol and hash both support, and the smaller of the two batch sizes. Normalization
+//! isn't picked, both sides have to name the same one or nothing would ever match. Ids we don't
+//! know are skipped when a `Hello` is decoded, so a newer peer can offer things we've never heard
+//! of, and `Hello` and `HelloAck` decode whatever wire version they were sent with so a peer on
+//! another one is told so instead of failing to decode
+
+use std::{
+    error::Error,
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use crate::normalize::Normalizer;
+use crate::transport::{Role, TransportError, read_frame, write_frame};
+use crate::wire;
+
+/// Largest batch we offer by default, how many values `simple` puts in one query
+pub const DEFAULT_MAX_BATCH: u32 = 64;
+
+/// Ordered weakest to strongest, the best one both support wins
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProtocolKind {
+    Simple,
+    Challenge,
+}
+
+impl ProtocolKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProtocolKind::Simple => "simple",
+            ProtocolKind::Challenge => "challenge",
+        }
+    }
+}
+
+impl fmt::Display for ProtocolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ProtocolKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "simple" => Ok(ProtocolKind::Simple),
+            "challenge" => Ok(ProtocolKind::Challenge),
+            _ => Err(format!(
+                "unknown protocol {name}, expected simple or challenge"
+            )),
+        }
+    }
+}
+
+/// Ordered weakest to strongest like `ProtocolKind`
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HashBackend {
+    Sha256,
+}
+
+impl HashBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashBackend::Sha256 => "sha256",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hello {
+    /// wire `VERSION`s we can speak
+    pub versions: Vec<u8>,
+    pub protocols: Vec<ProtocolKind>,
+    pub hashes: Vec<HashBackend>,
+    /// most values we'll send or take in one message, only `simple` batches so far
+    pub max_batch: u32,
+    /// `Normalizer::id` of what we do to elements before hashing them
+    pub normalization: String,
+}
+
+/// What both sides agreed to run
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Selected {
+    pub version: u8,
+    pub protocol: ProtocolKind,
+    pub hash: HashBackend,
+    pub batch: u32,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HelloAck {
+    Accept(Selected),
+    Reject(NegotiationError),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NegotiationError {
+    NoCommonProtocol,
+    NoCommonHash,
+    /// one side offered a batch size of zero
+    NoBatchSize,
+    /// the peers were built with different wire formats and neither speaks the other's
+    NoCommonVersion {
+        ours: Vec<u8>,
+        theirs: Vec<u8>,
+    },
+    /// the follower picked something we never offered
+    NotOffered(Selected),
+    /// the follower couldn't find a match and told us why
+    PeerRejected(Box<NegotiationError>),
+    /// elements are normalized differently on each side, nothing would match
+    NormalizationMismatch {
+        ours: String,
+        theirs: String,
+    },
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NegotiationError::NoCommonProtocol => write!(f, "no protocol supported by both sides"),
+            NegotiationError::NoCommonHash => write!(f, "no hash supported by both sides"),
+            NegotiationError::NoBatchSize => write!(f, "batch size of zero offered"),
+            NegotiationError::NoCommonVersion { ours, theirs } => write!(
+                f,
+                "no wire version spoken by both sides, we speak {ours:?} and the peer {theirs:?}"
+            ),
+            NegotiationError::NotOffered(selected) => {
+                write!(f, "peer picked {selected:?} which we never offered")
+            }
+            NegotiationError::PeerRejected(cause) => write!(f, "peer rejected our hello: {cause}"),
+            NegotiationError::NormalizationMismatch { ours, theirs } => {
+                write!(f, "we normalize with {ours} but the peer uses {theirs}")
+            }
+        }
+    }
+}
+
+impl Error for NegotiationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NegotiationError::PeerRejected(cause) => Some(cause.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Hello {
+    /// Everything this build can run
+    #[allow(unused)]
+    pub fn supported() -> Hello {
+        Hello {
+            versions: vec![wire::VERSION],
+            protocols: vec![ProtocolKind::Simple, ProtocolKind::Challenge],
+            hashes: vec![HashBackend::Sha256],
+            max_batch: DEFAULT_MAX_BATCH,
+            normalization: Normalizer::default().id(),
+        }
+    }
+
+    /// Same offer, saying elements go through `normalizer` first
+    #[allow(unused)]
+    pub fn normalized(self, normalizer: &Normalizer) -> Hello {
+        Hello {
+            normalization: normalizer.id(),
+            ..self
+        }
+    }
+
+    /// The best option we have in common with `peer`, the same whichever side asks
+    pub fn select(&self, peer: &Hello) -> Result<Selected, NegotiationError> {
+        let version = best_common(&self.versions, &peer.versions).ok_or_else(|| {
+            NegotiationError::NoCommonVersion {
+                ours: self.versions.clone(),
+                theirs: peer.versions.clone(),
+            }
+        })?;
+        if self.normalization != peer.normalization {
+            return Err(NegotiationError::NormalizationMismatch {
+                ours: self.normalization.clone(),
+                theirs: peer.normalization.clone(),
+            });
+        }
+        let protocol = best_common(&self.protocols, &peer.protocols)
+            .ok_or(NegotiationError::NoCommonProtocol)?;
+        let hash = best_common(&self.hashes, &peer.hashes).ok_or(NegotiationError::NoCommonHash)?;
+        let batch = match self.max_batch.min(peer.max_batch) {
+            0 => return Err(NegotiationError::NoBatchSize),
+            batch => batch,
+        };
+        Ok(Selected {
+            version,
+            protocol,
+            hash,
+            batch,
+        })
+    }
+
+    /// Follower side, what to send back for the leader's `Hello`
+    pub fn answer(&self, peer: &Hello) -> HelloAck {
+        match self.select(peer) {
+            Ok(selected) => HelloAck::Accept(selected),
+            Err(error) => HelloAck::Reject(error),
+        }
+    }
+
+    /// Leader side, check the follower's answer only picked things we offered
+    pub fn accept(&self, ack: HelloAck) -> Result<Selected, NegotiationError> {
+        match ack {
+            HelloAck::Accept(selected)
+                if self.versions.contains(&selected.version)
+                    && self.protocols.contains(&selected.protocol)
+                    && self.hashes.contains(&selected.hash)
+                    && (1..=self.max_batch).contains(&selected.batch) =>
+            {
+                Ok(selected)
+            }
+            HelloAck::Accept(selected) => Err(NegotiationError::NotOffered(selected)),
+            HelloAck::Reject(cause) => Err(NegotiationError::PeerRejected(Box::new(cause))),
+        }
+    }
+}
+
+fn best_common<T: Ord + Copy>(ours: &[T], theirs: &[T]) -> Option<T> {
+    ours.iter()
+        .filter(|option| theirs.contains(option))
+        .max()
+        .copied()
+}
+
+/// Exchange `Hello` and `HelloAck` over `stream` before running a protocol on it. Both sides get
+/// the same `Selected`, or the follower's reason for refusing
+#[allow(unused)]
+pub fn negotiate<S: Read + Write>(
+    stream: &mut S,
+    ours: &Hello,
+    role: Role,
+) -> Result<Selected, TransportError> {
+    match role {
+        Role::Leader => {
+            write_frame(stream, ours)?;
+            let ack = read_frame(stream)?;
+            ours.accept(ack).map_err(TransportError::Negotiation)
+        }
+        Role::Follower => {
+            let hello = read_frame(stream)?;
+            let ack = ours.answer(&hello);
+            write_frame(stream, &ack)?;
+            match ack {
+                HelloAck::Accept(selected) => Ok(selected),
+                HelloAck::Reject(error) => Err(TransportError::Negotiation(error)),
+            }
+        }
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    simple,
+    transport::{Pipe, drive},
+};
+#[cfg(test)]
+use std::{io, thread};
+
+#[test]
+fn best_common_option() {
+    let ours = Hello::supported();
+    assert_eq!(
+        ours.select(&ours),
+        Ok(Selected {
+            version: wire::VERSION,
+            protocol: ProtocolKind::Challenge,
+            hash: HashBackend::Sha256,
+            batch: DEFAULT_MAX_BATCH,
+        })
+    );
+
+    let old = Hello {
+        versions: vec![wire::VERSION - 1, wire::VERSION],
+        protocols: vec![ProtocolKind::Simple],
+        hashes: vec![HashBackend::Sha256],
+        max_batch: 4,
+        normalization: "none".to_string(),
+    };
+    let selected = ours.select(&old).unwrap();
+    assert_eq!(selected.protocol, ProtocolKind::Simple);
+    assert_eq!(selected.version, wire::VERSION);
+    assert_eq!(selected.batch, 4);
+}
+
+#[test]
+fn selection_is_deterministic() {
+    let a = Hello::supported();
+    let mut b = Hello::supported();
+    b.protocols.reverse();
+    b.versions = vec![wire::VERSION + 1, wire::VERSION];
+    b.max_batch = 8;
+
+    // same answer whichever side picks, and list order doesn't matter
+    assert_eq!(a.select(&b), b.select(&a));
+    assert_eq!(a.select(&b).unwrap().protocol, ProtocolKind::Challenge);
+    assert_eq!(a.select(&b).unwrap().version, wire::VERSION);
+    assert_eq!(a.select(&b).unwrap().batch, 8);
+}
+
+#[test]
+fn no_overlap() {
+    let ours = Hello::supported();
+    let theirs = Hello {
+        protocols: vec![],
+        ..Hello::supported()
+    };
+    assert_eq!(
+        ours.select(&theirs),
+        Err(NegotiationError::NoCommonProtocol)
+    );
+
+    let theirs = Hello {
+        hashes: vec![],
+        ..Hello::supported()
+    };
+    assert_eq!(ours.select(&theirs), Err(NegotiationError::NoCommonHash));
+
+    let theirs = Hello {
+        max_batch: 0,
+        ..Hello::supported()
+    };
+    assert_eq!(ours.select(&theirs), Err(NegotiationError::NoBatchSize));
+
+    // a peer on a newer wire format, told why rather than sent frames it can't read
+    let theirs = Hello {
+        versions: vec![wire::VERSION + 1],
+        ..Hello::supported()
+    };
+    let error = NegotiationError::NoCommonVersion {
+        ours: vec![wire::VERSION + 1],
+        theirs: vec![wire::VERSION],
+    };
+    let ack = theirs.answer(&ours);
+    assert_eq!(ack, HelloAck::Reject(error.clone()));
+    assert_eq!(
+        ours.accept(ack),
+        Err(NegotiationError::PeerRejected(Box::new(error)))
+    );
+}
+
+#[test]
+fn protocol_names() {
+    for protocol in [ProtocolKind::Simple, ProtocolKind::Challenge] {
+        assert_eq!(protocol.to_string().parse(), Ok(protocol));
+    }
+    assert!("range".parse::<ProtocolKind>().is_err());
+}
+
+#[test]
+fn normalization_must_match() {
+    let trim: Normalizer = "trim".parse().unwrap();
+    let ours = Hello::supported().normalized(&trim);
+    assert!(ours.select(&Hello::supported().normalized(&trim)).is_ok());
+
+    let theirs = Hello::supported().normalized(&"trim,casefold".parse().unwrap());
+    let error = NegotiationError::NormalizationMismatch {
+        ours: "trim".to_string(),
+        theirs: "trim,casefold".to_string(),
+    };
+    assert_eq!(ours.select(&theirs), Err(error.clone()));
+    assert_eq!(
+        error.to_string(),
+        "we normalize with trim but the peer uses trim,casefold"
+    );
+    assert!(matches!(
+        Hello::supported().select(&theirs),
+        Err(NegotiationError::NormalizationMismatch { ours, .. }) if ours == "none"
+    ));
+}
+
+#[test]
+fn rejects_choices_never_offered() {
+    let ours = Hello {
+        protocols: vec![ProtocolKind::Simple],
+        ..Hello::supported()
+    };
+    let sneaky = Selected {
+        version: wire::VERSION,
+        protocol: ProtocolKind::Challenge,
+        hash: HashBackend::Sha256,
+        batch: 1,
+    };
+    assert_eq!(
+        ours.accept(HelloAck::Accept(sneaky)),
+        Err(NegotiationError::NotOffered(sneaky))
+    );
+
+    let unknown_version = Selected {
+        version: wire::VERSION + 1,
+        protocol: ProtocolKind::Simple,
+        ..sneaky
+    };
+    assert_eq!(
+        ours.accept(HelloAck::Accept(unknown_version)),
+        Err(NegotiationError::NotOffered(unknown_version))
+    );
+
+    let too_big = Selected {
+        protocol: ProtocolKind::Simple,
+        batch: DEFAULT_MAX_BATCH + 1,
+        ..sneaky
+    };
+    assert_eq!(
+        ours.accept(HelloAck::Accept(too_big)),
+        Err(NegotiationError::NotOffered(too_big))
+    );
+}
+
+#[test]
+fn negotiate_then_run() {
+    let (from_leader, to_follower) = io::pipe().unwrap();
+    let (from_follower, to_leader) = io::pipe().unwrap();
+
+    // the follower only knows simple and small batches, so that's what both end up running
+    let follower = thread::spawn(move || {
+        let mut stream = Pipe::new(from_leader, to_leader);
+        let ours = Hello {
+            protocols: vec![ProtocolKind::Simple],
+            max_batch: 2,
+            ..Hello::supported()
+        };
+        let selected = negotiate(&mut stream, &ours, Role::Follower)?;
+        assert_eq!(selected.protocol, ProtocolKind::Simple);
+        let data = vec![1, 2, 3];
+        let mut node = simple::NodeState::batched(&data, selected.batch as usize);
+        drive(&mut stream, &mut node, Role::Follower)
+    });
+
+    let mut stream = Pipe::new(from_follower, to_follower);
+    let selected = negotiate(&mut stream, &Hello::supported(), Role::Leader).unwrap();
+    let data = vec![1, 5, 3];
+    assert_eq!(selected.batch, 2);
+    let result = match selected.protocol {
+        ProtocolKind::Simple => drive(
+            &mut stream,
+            &mut simple::NodeState::batched(&data, selected.batch as usize),
+            Role::Leader,
+        ),
+        ProtocolKind::Challenge => unreachable!("follower doesn't support it"),
+    };
+
+    assert_eq!(result.unwrap(), vec![1, 3]);
+    assert_eq!(follower.join().unwrap().unwrap(), vec![1, 3]);
+}
+
+#[test]
+fn negotiation_failure_reaches_both_sides() {
+    let (from_leader, to_follower) = io::pipe().unwrap();
+    let (from_follower, to_leader) = io::pipe().unwrap();
+
+    let follower = thread::spawn(move || {
+        let ours = Hello {
+            protocols: vec![ProtocolKind::Challenge],
+            ..Hello::supported()
+        };
+        negotiate(
+            &mut Pipe::new(from_leader, to_leader),
+            &ours,
+            Role::Follower,
+        )
+    });
+
+    let ours = Hello {
+        protocols: vec![ProtocolKind::Simple],
+        ..Hello::supported()
+    };
+    let result = negotiate(
+        &mut Pipe::new(from_follower, to_follower),
+        &ours,
+        Role::Leader,
+    );
+
+    assert!(matches!(
+        result,
+        Err(TransportError::Negotiation(NegotiationError::PeerRejected(cause)))
+            if *cause == NegotiationError::NoCommonProtocol
+    ));
+    assert!(matches!(
+        follower.join().unwrap(),
+        Err(TransportError::Negotiation(
+            NegotiationError::NoCommonProtocol
+        ))
+    ));
+}
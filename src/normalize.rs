@@ -0,0 +1,263 @@
+//! Cleaning up elements before they're hashed, so `Bob@X.com ` and `bob@x.com` match. Hashing hides
+//! any difference at all, so both peers have to run exactly the same steps, `id` names them and goes
+//! in the `Hello` for negotiation to check.
+//!
+//! Steps always run in the same order (trim, nfc, casefold, email, phone) however they were listed,
+//! so `casefold,trim` and `trim,casefold` are the same normalization. With both nfc and casefold,
+//! folded text is composed again since folding can undo it. Listing a step twice is fine but two
+//! phone steps with different countries is an error. The email and phone steps only touch values
+//! shaped like one and leave anything else as it is, nothing is ever dropped
+
+use std::{fmt, str::FromStr};
+
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Step {
+    /// whitespace off both ends
+    Trim,
+    /// unicode composed form, so an accent typed two ways is the same
+    Nfc,
+    /// full unicode case folding, `ß` matches `ss`
+    CaseFold,
+    /// lowercase and drop any `+tag` from the local part
+    Email,
+    /// E.164, `+` then digits. Numbers without a country code get `country` if there is one,
+    /// losing their leading trunk zero
+    Phone { country: Option<u16> },
+}
+
+impl Step {
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Step::Trim => value.trim().to_string(),
+            Step::Nfc => value.nfc().collect(),
+            Step::CaseFold => caseless::default_case_fold_str(value),
+            Step::Email => email(value).unwrap_or_else(|| value.to_string()),
+            Step::Phone { country } => phone(value, *country).unwrap_or_else(|| value.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Trim => write!(f, "trim"),
+            Step::Nfc => write!(f, "nfc"),
+            Step::CaseFold => write!(f, "casefold"),
+            Step::Email => write!(f, "email"),
+            Step::Phone { country: None } => write!(f, "phone"),
+            Step::Phone {
+                country: Some(country),
+            } => write!(f, "phone={country}"),
+        }
+    }
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "trim" => Ok(Step::Trim),
+            "nfc" => Ok(Step::Nfc),
+            "casefold" => Ok(Step::CaseFold),
+            "email" => Ok(Step::Email),
+            "phone" => Ok(Step::Phone { country: None }),
+            _ => match name.strip_prefix("phone=").map(str::parse) {
+                Some(Ok(country)) => Ok(Step::Phone {
+                    country: Some(country),
+                }),
+                _ => Err(format!(
+                    "unknown normalization {name:?}, expected trim, nfc, casefold, email, phone or phone=<country code>"
+                )),
+            },
+        }
+    }
+}
+
+/// The steps to run, no steps leaves everything as it is
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Normalizer {
+    steps: Vec<Step>,
+}
+
+impl Normalizer {
+    /// Fails if two steps of the same kind disagree, like `phone` and `phone=44`
+    pub fn new(steps: impl IntoIterator<Item = Step>) -> Result<Self, String> {
+        let mut steps: Vec<Step> = steps.into_iter().collect();
+        steps.sort();
+        steps.dedup();
+        if let Some(pair) = steps
+            .windows(2)
+            .find(|pair| std::mem::discriminant(&pair[0]) == std::mem::discriminant(&pair[1]))
+        {
+            return Err(format!(
+                "conflicting normalizations {} and {}",
+                pair[0], pair[1]
+            ));
+        }
+        Ok(Normalizer { steps })
+    }
+
+    pub fn apply(&self, value: &str) -> String {
+        let composed = self.steps.contains(&Step::Nfc);
+        self.steps
+            .iter()
+            .fold(value.to_string(), |value, step| match step {
+                // folding can decompose, `ǰ` folds to `j` and a combining caron
+                Step::CaseFold if composed => step.apply(&value).nfc().collect(),
+                _ => step.apply(&value),
+            })
+    }
+
+    pub fn apply_all(&self, values: Vec<String>) -> Vec<String> {
+        if self.steps.is_empty() {
+            return values;
+        }
+        values.into_iter().map(|value| self.apply(&value)).collect()
+    }
+
+    /// Names the steps, equal for two normalizers exactly when they do the same thing
+    pub fn id(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Normalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "none");
+        }
+        let names: Vec<String> = self.steps.iter().map(Step::to_string).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Comma separated step names, `none` or nothing for no steps
+impl FromStr for Normalizer {
+    type Err = String;
+
+    fn from_str(names: &str) -> Result<Self, Self::Err> {
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "none")
+            .map(str::parse)
+            .collect::<Result<Vec<Step>, _>>()
+            .and_then(Normalizer::new)
+    }
+}
+
+fn email(value: &str) -> Option<String> {
+    let (local, domain) = value.split_once('@')?;
+    if local.is_empty() || domain.is_empty() || domain.contains('@') {
+        return None;
+    }
+    let local = local.split('+').next().filter(|local| !local.is_empty())?;
+    Some(format!(
+        "{}@{}",
+        local.to_lowercase(),
+        domain.to_lowercase()
+    ))
+}
+
+fn phone(value: &str, country: Option<u16>) -> Option<String> {
+    let compact: String = value
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect();
+    let digits = match (compact.strip_prefix('+'), compact.strip_prefix("00")) {
+        (Some(international), _) | (None, Some(international)) => international.to_string(),
+        (None, None) => format!("{}{}", country?, compact.trim_start_matches('0')),
+    };
+    // E.164 allows at most 15 digits, anything much under 8 isn't a whole number
+    let valid = (8..=15).contains(&digits.len()) && digits.bytes().all(|b| b.is_ascii_digit());
+    valid.then(|| format!("+{digits}"))
+}
+
+// tests
+
+#[test]
+fn matches_trivial_differences() {
+    let normalizer: Normalizer = "trim,casefold,email".parse().unwrap();
+    assert_eq!(
+        normalizer.apply("Bob@X.com "),
+        normalizer.apply("bob@x.com")
+    );
+    assert_eq!(normalizer.apply(" Bob+news@X.com"), "bob@x.com");
+    assert_eq!(normalizer.apply("Straße"), "strasse");
+    // not an email, only trimmed and folded
+    assert_eq!(
+        normalizer.apply(" Not An @ Email @ All "),
+        "not an @ email @ all"
+    );
+}
+
+#[test]
+fn composes_unicode() {
+    let normalizer = Normalizer::new([Step::Nfc]).unwrap();
+    let decomposed = "e\u{301}";
+    assert_eq!(normalizer.apply(decomposed), "\u{e9}");
+    assert_eq!(Normalizer::default().apply(decomposed), decomposed);
+}
+
+#[test]
+fn formats_phone_numbers() {
+    let uk = Normalizer::new([Step::Trim, Step::Phone { country: Some(44) }]).unwrap();
+    for number in [
+        "+44 20 7946 0958",
+        "0044 20 7946 0958",
+        "020 7946 0958",
+        "(020) 7946-0958",
+    ] {
+        assert_eq!(uk.apply(number), "+442079460958", "{number}");
+    }
+    // too short, or not a number at all
+    assert_eq!(uk.apply("12"), "12");
+    assert_eq!(uk.apply("call me"), "call me");
+
+    // without a country code national numbers can't be placed
+    let anywhere = Normalizer::new([Step::Phone { country: None }]).unwrap();
+    assert_eq!(anywhere.apply("020 7946 0958"), "020 7946 0958");
+    assert_eq!(anywhere.apply("+1 (555) 010-0000"), "+15550100000");
+}
+
+#[test]
+fn identity_ignores_order() {
+    let a: Normalizer = "casefold, trim,trim".parse().unwrap();
+    let b: Normalizer = "trim,casefold".parse().unwrap();
+    assert_eq!(a, b);
+    assert_eq!(a.id(), "trim,casefold");
+    assert_eq!(a.id().parse(), Ok(a));
+
+    assert_eq!(Normalizer::default().id(), "none");
+    assert_eq!("none".parse(), Ok(Normalizer::default()));
+    assert_eq!("phone=1".parse::<Normalizer>().unwrap().id(), "phone=1");
+    assert!("phone=x".parse::<Normalizer>().is_err());
+    assert!("soundex".parse::<Normalizer>().is_err());
+}
+
+#[test]
+fn conflicting_steps_rejected() {
+    assert_eq!(
+        "phone,phone=44".parse::<Normalizer>(),
+        Err("conflicting normalizations phone and phone=44".to_string())
+    );
+    assert!("phone=1,phone=44".parse::<Normalizer>().is_err());
+    assert_eq!(
+        "phone=44,phone=44".parse::<Normalizer>().unwrap().id(),
+        "phone=44"
+    );
+}
+
+#[test]
+fn folded_text_stays_composed() {
+    let normalizer: Normalizer = "nfc,casefold".parse().unwrap();
+    let folded = normalizer.apply("\u{1f0}");
+    assert!(unicode_normalization::is_nfc(&folded), "{folded:?}");
+    assert_eq!(folded, normalizer.apply("j\u{30c}"));
+    // without nfc folding is left as it is
+    let fold_only: Normalizer = "casefold".parse().unwrap();
+    assert_eq!(fold_only.apply("\u{1f0}"), "j\u{30c}");
+}
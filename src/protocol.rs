@@ -0,0 +1,245 @@
+//! The common state machine api every protocol node offers, so transports can run any of them
+//! without knowing which one it is. `run_local` runs two nodes against each other in this process.
+//!
+//! `Tally` counts what a session sends and hashes every message into a transcript, runners keep one
+//! so every run can say what it cost whether it worked or not
+
+use std::{
+    collections::HashSet,
+    fmt,
+    ops::Add,
+    time::{Duration, Instant},
+};
+
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::challenge;
+use crate::error::ProtocolError;
+use crate::simple;
+use crate::transport::{FRAME_HEADER_LEN, Role};
+use crate::wire::Wire;
+
+pub trait Message: Wire + Clone + fmt::Debug {
+    /// `Done`, `Fail` or `End`, the sender has hung up and won't read a reply
+    fn is_terminal(&self) -> bool;
+}
+
+pub trait Protocol {
+    type Message: Message;
+    /// What a successful run leaves us with
+    type Output;
+
+    /// First message, only valid on the side that leads
+    fn start(&mut self) -> Self::Message;
+
+    fn receive(&mut self, message: Self::Message) -> Self::Message;
+
+    fn is_finished(&self) -> bool;
+
+    /// Tie the session to the channel it runs over, e.g. a secure channel's handshake hash, so it
+    /// can't be relayed between two different channels. Called before the first message, protocols
+    /// with nothing to bind ignore it
+    #[allow(unused)]
+    fn bind_channel(&mut self, _binding: &[u8]) {}
+
+    /// `None` until finished, then our results or why we failed
+    fn outcome(&self) -> Option<Result<Self::Output, ProtocolError>>;
+
+    /// What we've done so far besides sending messages, protocols that don't count it say nothing
+    fn work(&self) -> Work {
+        Work::default()
+    }
+}
+
+/// Work a node does that the messages don't show, for benchmarks to compare
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Work {
+    /// elements hashed, proofs included
+    pub hashes: usize,
+    /// elements or hashes compared looking for a match
+    pub comparisons: usize,
+}
+
+impl Add for Work {
+    type Output = Work;
+
+    fn add(self, other: Work) -> Work {
+        Work {
+            hashes: self.hashes + other.hashes,
+            comparisons: self.comparisons + other.comparisons,
+        }
+    }
+}
+
+impl Message for challenge::NodeMessage {
+    fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            challenge::NodeMessage::Done | challenge::NodeMessage::Fail(_)
+        )
+    }
+}
+
+impl<R: RngCore + CryptoRng> Protocol for challenge::Node<'_, R> {
+    type Message = challenge::NodeMessage;
+    type Output = HashSet<String>;
+
+    fn start(&mut self) -> Self::Message {
+        challenge::Node::start(self)
+    }
+
+    fn receive(&mut self, message: Self::Message) -> Self::Message {
+        self.recieve_message(message)
+    }
+
+    fn is_finished(&self) -> bool {
+        challenge::Node::is_finished(self)
+    }
+
+    fn bind_channel(&mut self, binding: &[u8]) {
+        challenge::Node::bind_channel(self, binding)
+    }
+
+    fn outcome(&self) -> Option<Result<Self::Output, ProtocolError>> {
+        challenge::Node::outcome(self).map(|outcome| outcome.cloned().map_err(Clone::clone))
+    }
+
+    fn work(&self) -> Work {
+        challenge::Node::work(self)
+    }
+}
+
+impl Message for simple::NodeMessage {
+    fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            simple::NodeMessage::End | simple::NodeMessage::Fail(_)
+        )
+    }
+}
+
+impl Protocol for simple::NodeState<'_> {
+    type Message = simple::NodeMessage;
+    type Output = Vec<u32>;
+
+    fn start(&mut self) -> Self::Message {
+        simple::NodeState::start(self)
+    }
+
+    fn receive(&mut self, message: Self::Message) -> Self::Message {
+        simple::NodeState::receive(self, message)
+    }
+
+    fn is_finished(&self) -> bool {
+        simple::NodeState::is_finished(self)
+    }
+
+    fn outcome(&self) -> Option<Result<Self::Output, ProtocolError>> {
+        simple::NodeState::outcome(self)
+            .map(|outcome| outcome.map(<[u32]>::to_vec).map_err(Clone::clone))
+    }
+
+    fn work(&self) -> Work {
+        simple::NodeState::work(self)
+    }
+}
+
+/// What a session cost, and a hash of everything in it
+#[derive(PartialEq, Debug, Clone)]
+pub struct Stats {
+    /// messages the leader sent, each one is a round trip unless it hung up
+    pub rounds: usize,
+    /// sent and received
+    pub messages: usize,
+    /// what those messages take on the wire, frame headers included
+    pub bytes: usize,
+    pub elapsed: Duration,
+    /// SHA-256 over every message in the order they were sent, both sides of a session that saw the
+    /// same messages agree on it
+    pub transcript: [u8; 32],
+}
+
+/// Keeps `Stats` for a session as it runs, every message sent or received goes through `record`
+#[derive(Debug, Clone)]
+pub struct Tally {
+    rounds: usize,
+    messages: usize,
+    bytes: usize,
+    began: Instant,
+    transcript: Sha256,
+}
+
+impl Default for Tally {
+    fn default() -> Self {
+        Tally {
+            rounds: 0,
+            messages: 0,
+            bytes: 0,
+            began: Instant::now(),
+            transcript: Sha256::new(),
+        }
+    }
+}
+
+impl Tally {
+    /// `from` is the side that sent it
+    pub fn record<M: Message>(&mut self, from: Role, message: &M) {
+        let bytes = message.encode();
+        if from == Role::Leader {
+            self.rounds += 1;
+        }
+        self.messages += 1;
+        self.bytes += FRAME_HEADER_LEN + bytes.len();
+        // length first so message boundaries are part of the hash
+        self.transcript.update((bytes.len() as u32).to_le_bytes());
+        self.transcript.update(bytes);
+    }
+
+    /// Everything so far, the clock keeps running
+    pub fn stats(&self) -> Stats {
+        Stats {
+            rounds: self.rounds,
+            messages: self.messages,
+            bytes: self.bytes,
+            elapsed: self.began.elapsed(),
+            transcript: self.transcript.clone().finalize().into(),
+        }
+    }
+}
+
+/// How a session run in-process by `run_local` went, with what it cost
+#[derive(Debug)]
+pub struct LocalRun<O> {
+    pub leader: Result<O, ProtocolError>,
+    pub follower: Result<O, ProtocolError>,
+    pub stats: Stats,
+    /// both sides together
+    pub work: Work,
+}
+
+/// Run a whole session between two nodes in this process, handing messages straight across
+pub fn run_local<P: Protocol>(leader: &mut P, follower: &mut P) -> LocalRun<P::Output> {
+    let mut tally = Tally::default();
+    let mut message = leader.start();
+    loop {
+        tally.record(Role::Leader, &message);
+        let hung_up = message.is_terminal();
+        let reply = follower.receive(message);
+        if hung_up {
+            break;
+        }
+        tally.record(Role::Follower, &reply);
+        let hung_up = reply.is_terminal();
+        message = leader.receive(reply);
+        if hung_up {
+            break;
+        }
+    }
+    LocalRun {
+        leader: leader.outcome().expect("leader has hung up"),
+        follower: follower.outcome().expect("follower has hung up"),
+        stats: tally.stats(),
+        work: leader.work() + follower.work(),
+    }
+}
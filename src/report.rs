@@ -0,0 +1,246 @@
+//! A summary of one side of a run for programs rather than people, what was found, what was agreed
+//! on and what it cost. `--output json` prints these instead of the bare intersection, and
+//! `Report::from_run` makes one from any driver's result and the `Tally` it kept.
+//!
+//! Every field is there even when it doesn't apply, as `null`, so readers don't have to guess. A run
+//! that failed before a protocol was picked only has its `error`
+
+use serde::Serialize;
+
+use crate::negotiate::{ProtocolKind, Selected};
+use crate::protocol::Stats;
+use crate::transport::{Role, TransportError};
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Report {
+    pub protocol: Option<&'static str>,
+    pub role: Option<&'static str>,
+    pub parameters: Parameters,
+    /// distinct elements we went in with
+    pub local: Option<usize>,
+    /// what both have in common, sorted
+    pub intersection: Option<Vec<String>>,
+    pub cardinality: Option<usize>,
+    /// ours that the peer doesn't have
+    pub local_only: Option<usize>,
+    /// `Stats::transcript` in hex, matches the peer's when both saw the same session
+    pub transcript: Option<String>,
+    pub rounds: usize,
+    pub messages: usize,
+    pub bytes: usize,
+    pub duration_ms: f64,
+    pub error: Option<Failure>,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone, Default)]
+pub struct Parameters {
+    /// only known if they were negotiated
+    pub hash: Option<&'static str>,
+    /// wire `VERSION`
+    pub version: Option<u8>,
+    /// `Selected::batch`, how many values go in one message
+    pub batch: Option<u32>,
+    /// `Normalizer::id`
+    pub normalization: String,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Failure {
+    /// who to blame, `input`, `peer` or `transport`, same as the exit code
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl Report {
+    /// A run of `protocol` that got as far as exchanging messages, without a result yet
+    pub fn new(
+        protocol: ProtocolKind,
+        role: Role,
+        parameters: Parameters,
+        local: usize,
+        stats: &Stats,
+    ) -> Self {
+        let transcript = stats
+            .transcript
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Report {
+            protocol: Some(protocol.name()),
+            role: Some(role.name()),
+            parameters,
+            local: Some(local),
+            intersection: None,
+            cardinality: None,
+            local_only: None,
+            transcript: Some(transcript),
+            rounds: stats.rounds,
+            messages: stats.messages,
+            bytes: stats.bytes,
+            duration_ms: stats.elapsed.as_secs_f64() * 1000.0,
+            error: None,
+        }
+    }
+
+    /// One side of a run that's over, straight from what the driver gave back, e.g. `drive_counted`
+    /// and the `Tally` it kept. The common elements can come in any order
+    pub fn from_run<I>(
+        protocol: ProtocolKind,
+        role: Role,
+        parameters: Parameters,
+        local: usize,
+        outcome: Result<I, &TransportError>,
+        stats: &Stats,
+    ) -> Self
+    where
+        I: IntoIterator,
+        I::Item: ToString,
+    {
+        let report = Report::new(protocol, role, parameters, local, stats);
+        match outcome {
+            Ok(common) => report.found(common.into_iter().map(|e| e.to_string()).collect()),
+            Err(error) => report.failed(Failure::transport(error)),
+        }
+    }
+
+    /// Nothing ran at all
+    pub fn failure(failure: Failure) -> Self {
+        Report {
+            protocol: None,
+            role: None,
+            parameters: Parameters::default(),
+            local: None,
+            intersection: None,
+            cardinality: None,
+            local_only: None,
+            transcript: None,
+            rounds: 0,
+            messages: 0,
+            bytes: 0,
+            duration_ms: 0.0,
+            error: Some(failure),
+        }
+    }
+
+    pub fn found(self, mut common: Vec<String>) -> Self {
+        common.sort();
+        let cardinality = common.len();
+        Report {
+            local_only: self.local.map(|local| local.saturating_sub(cardinality)),
+            intersection: Some(common),
+            cardinality: Some(cardinality),
+            ..self
+        }
+    }
+
+    pub fn failed(self, failure: Failure) -> Self {
+        Report {
+            error: Some(failure),
+            ..self
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("reports only hold plain values")
+    }
+}
+
+impl Failure {
+    /// The peer's fault if the protocol failed or we couldn't agree on one, otherwise the
+    /// connection's
+    pub fn transport(error: &TransportError) -> Self {
+        let kind = match error {
+            TransportError::Protocol(_) | TransportError::Negotiation(_) => "peer",
+            _ => "transport",
+        };
+        Failure {
+            kind,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Parameters {
+    pub fn negotiated(selected: &Selected, normalization: String) -> Self {
+        Parameters {
+            hash: Some(selected.hash.name()),
+            version: Some(selected.version),
+            batch: Some(selected.batch),
+            normalization,
+        }
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{error::ProtocolError, negotiate::HashBackend, protocol::Tally};
+
+#[test]
+fn reports_to_json() {
+    let selected = Selected {
+        version: 1,
+        protocol: ProtocolKind::Challenge,
+        hash: HashBackend::Sha256,
+        batch: 64,
+    };
+    let parameters = Parameters::negotiated(&selected, "trim".into());
+    let stats = Tally::default().stats();
+    let report = Report::new(ProtocolKind::Challenge, Role::Leader, parameters, 3, &stats)
+        .found(vec!["b".into(), "a".into()]);
+    assert_eq!(report.local_only, Some(1));
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["protocol"], "challenge");
+    assert_eq!(json["role"], "leader");
+    assert_eq!(json["parameters"]["hash"], "sha256");
+    assert_eq!(json["parameters"]["version"], 1);
+    assert_eq!(json["parameters"]["batch"], 64);
+    assert_eq!(json["parameters"]["normalization"], "trim");
+    assert_eq!(json["intersection"], serde_json::json!(["a", "b"]));
+    assert_eq!(json["cardinality"], 2);
+    assert_eq!(json["transcript"].as_str().unwrap().len(), 64);
+    assert!(json["error"].is_null());
+
+    let failure = Failure {
+        kind: "input",
+        message: "no such file".into(),
+    };
+    let json: serde_json::Value =
+        serde_json::from_str(&Report::failure(failure).to_json()).unwrap();
+    assert!(json["protocol"].is_null() && json["intersection"].is_null());
+    assert_eq!(json["error"]["kind"], "input");
+}
+
+#[test]
+fn reports_from_runs() {
+    let stats = Tally::default().stats();
+    let found: Result<_, &TransportError> = Ok(vec![3, 1]);
+    let report = Report::from_run(
+        ProtocolKind::Simple,
+        Role::Follower,
+        Parameters::default(),
+        3,
+        found,
+        &stats,
+    );
+    assert_eq!(report.intersection, Some(vec!["1".into(), "3".into()]));
+    assert_eq!(report.local_only, Some(1));
+    assert_eq!(report.error, None);
+
+    let error = TransportError::Protocol(ProtocolError::SessionClosed);
+    let report = Report::from_run(
+        ProtocolKind::Challenge,
+        Role::Leader,
+        Parameters::default(),
+        3,
+        Err::<Vec<String>, _>(&error),
+        &stats,
+    );
+    assert_eq!(report.intersection, None);
+    assert_eq!(report.error.unwrap().kind, "peer");
+    assert_eq!(
+        Failure::transport(&TransportError::Closed).kind,
+        "transport"
+    );
+}
@@ -1,3 +1,5 @@
+#[allow(clippy::module_inception)]
 mod simple;
 
-pub use simple::*;
\ No newline at end of file
+#[allow(unused)]
+pub use simple::*;
@@ -1,3 +1,6 @@
+use crate::error::ProtocolError;
+use crate::protocol::Work;
+
 /// Naive attempt #1, thinking about the basics of protocols in rust
 /// We check common elements position-wise between two arrays, wrapped in NodeStates. Return a counter of how many iterations it took
 ///
@@ -6,86 +9,207 @@
 /// But `[1,2,3]` and `[3,2,1]` only have `[2]` in common
 ///
 /// This is only metaphorically related to walking a tree, this code serves no useful purpose now
-
-#[derive(PartialEq, Debug, Clone, Copy)]
+///
+/// Once a node has sent or received `End` (or `Fail`) it's finished and answers anything else with `SessionClosed`
+///
+/// Queries and responses have to come in increasing `location` order, a repeat is a `Fail` with
+/// `OutOfSequence` so a message duplicated by the network can't be counted twice
+///
+/// A query carries the values from `location` on, up to the node's batch size, and the response
+/// says for each one whether it matched, stopping short where the responder's data runs out. A
+/// query bigger than our batch is a `Fail` with `BatchTooLarge`, both sides use the one they
+/// negotiated
+
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum NodeMessage {
-    HasQuery { location: usize, value: u32 },
-    HasResponse { location: usize, has: bool },
+    HasQuery { location: usize, values: Vec<u32> },
+    HasResponse { location: usize, has: Vec<bool> },
+    Fail(ProtocolError),
     End,
 }
 
+#[derive(Clone)]
 pub struct NodeState<'a> {
     data: &'a Vec<u32>, // set we're testing the other node for
     index: usize,
+    /// most values we send or accept in one query
+    batch: usize,
     pub common: Vec<u32>,
+    /// last location we answered a query for and got a response for
+    last_query: Option<usize>,
+    last_response: Option<usize>,
+    finished: bool,
+    failure: Option<ProtocolError>,
+    /// queries we've checked against our data
+    comparisons: usize,
 }
 
 impl<'a> NodeState<'a> {
+    /// One value per query
     #[allow(unused)]
     pub fn new(data: &'a Vec<u32>) -> Self {
+        NodeState::batched(data, 1)
+    }
+
+    /// Up to `batch` values per query, both sides need the same one
+    #[allow(unused)]
+    pub fn batched(data: &'a Vec<u32>, batch: usize) -> Self {
         NodeState {
             data,
             common: vec![],
             index: 0,
+            batch: batch.max(1),
+            last_query: None,
+            last_response: None,
+            finished: false,
+            failure: None,
+            comparisons: 0,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// `None` until the exchange ends, then what we have in common or why it failed
+    #[allow(unused)]
+    pub fn outcome(&self) -> Option<Result<&[u32], &ProtocolError>> {
+        match (self.finished, &self.failure) {
+            (false, _) => None,
+            (true, None) => Some(Ok(&self.common)),
+            (true, Some(failure)) => Some(Err(failure)),
+        }
+    }
+
+    /// Nothing is hashed, each query is a single comparison
+    #[allow(unused)]
+    pub fn work(&self) -> Work {
+        Work {
+            hashes: 0,
+            comparisons: self.comparisons,
         }
     }
 
     /// Call on the intiator node to get first message
     pub fn start(&mut self) -> NodeMessage {
-        self.next_query()
+        if self.finished {
+            return NodeMessage::Fail(ProtocolError::SessionClosed);
+        }
+        let message = self.next_query();
+        self.finish_on(&message);
+        message
     }
 
     /// feed messages from other peer in here
     pub fn receive(&mut self, message: NodeMessage) -> NodeMessage {
+        if self.finished {
+            return NodeMessage::Fail(ProtocolError::SessionClosed);
+        }
+        let reply = self.respond(message);
+        self.finish_on(&reply);
+        reply
+    }
+
+    /// Both sides finish on the first `End` or `Fail`, whether they sent it or not
+    fn finish_on(&mut self, reply: &NodeMessage) {
+        match reply {
+            NodeMessage::End => self.finished = true,
+            NodeMessage::Fail(error) => {
+                self.finished = true;
+                self.failure = Some(error.clone());
+            }
+            _ => {}
+        }
+    }
+
+    fn respond(&mut self, message: NodeMessage) -> NodeMessage {
         match message {
-            NodeMessage::HasQuery { location, value } => match self.data.get(location) {
-                None => NodeMessage::End,
-                Some(val) => {
-                    if *val == value {
-                        self.common.push(value);
-                        NodeMessage::HasResponse {
-                            location,
-                            has: true,
-                        }
-                    } else {
-                        NodeMessage::HasResponse {
-                            location,
-                            has: false,
-                        }
-                    }
+            NodeMessage::HasQuery { values, .. } if values.len() > self.batch => {
+                NodeMessage::Fail(ProtocolError::BatchTooLarge {
+                    limit: self.batch,
+                    received: values.len(),
+                })
+            }
+            NodeMessage::HasQuery { location, values }
+                if repeated(&mut self.last_query, location, values.len()) =>
+            {
+                out_of_sequence(self.last_query, location)
+            }
+            NodeMessage::HasResponse { location, has }
+                if repeated(&mut self.last_response, location, has.len()) =>
+            {
+                out_of_sequence(self.last_response, location)
+            }
+            NodeMessage::HasQuery { location, values } => match self.data.get(location..) {
+                None | Some([]) => NodeMessage::End,
+                Some(ours) => {
+                    let has: Vec<bool> = values.iter().zip(ours).map(|(a, b)| a == b).collect();
+                    self.comparisons += has.len();
+                    let matched = values.iter().zip(&has).filter(|(_, has)| **has);
+                    self.common.extend(matched.map(|(value, _)| *value));
+                    NodeMessage::HasResponse { location, has }
                 }
             },
-            NodeMessage::HasResponse { location, has } => match (location, has) {
-                (location, true) => match self.data.get(location) {
-                    None => NodeMessage::End,
-                    Some(value) => {
-                        self.common.push(*value);
-                        self.next_query()
+            NodeMessage::HasResponse { location, has } => {
+                for (offset, _) in has.iter().enumerate().filter(|(_, has)| **has) {
+                    match self.data.get(location.saturating_add(offset)) {
+                        None => return NodeMessage::End,
+                        Some(value) => self.common.push(*value),
                     }
-                },
-                (_location, false) => self.next_query(),
-            },
+                }
+                self.next_query()
+            }
+            NodeMessage::Fail(cause) => NodeMessage::Fail(ProtocolError::peer(cause)),
             NodeMessage::End => NodeMessage::End,
         }
     }
 
     fn next_query(&mut self) -> NodeMessage {
-        let response = if self.index < self.data.len() {
-            NodeMessage::HasQuery {
-                location: self.index,
-                value: self.data[self.index],
-            }
-        } else {
-            NodeMessage::End
-        };
-        self.index += 1;
+        let values: Vec<u32> = self
+            .data
+            .iter()
+            .skip(self.index)
+            .take(self.batch)
+            .copied()
+            .collect();
+        if values.is_empty() {
+            return NodeMessage::End;
+        }
+        let location = self.index;
+        self.index += values.len();
+        NodeMessage::HasQuery { location, values }
+    }
+}
 
-        response
+/// Whether `location` isn't past the `last` one we saw, otherwise the last of the `len` from
+/// `location` becomes the new last
+fn repeated(last: &mut Option<usize>, location: usize, len: usize) -> bool {
+    match *last {
+        Some(previous) if location <= previous => true,
+        _ => {
+            *last = Some(location.saturating_add(len.saturating_sub(1)));
+            false
+        }
     }
 }
 
+fn out_of_sequence(last: Option<usize>, location: usize) -> NodeMessage {
+    NodeMessage::Fail(ProtocolError::OutOfSequence {
+        expected: last.map_or(0, |previous| previous.saturating_add(1)),
+        received: location,
+    })
+}
+
 // tests
 
+#[cfg(test)]
+use crate::{adversary, explore, protocol::run_local, transport::Role};
+#[cfg(test)]
+use proptest::{collection::vec, prelude::*};
+
 #[test]
 fn start_node() {
     let data = vec![1, 2, 3];
@@ -97,7 +221,7 @@ fn start_node() {
         message,
         NodeMessage::HasQuery {
             location: 0,
-            value: 1
+            values: vec![1],
         }
     );
 }
@@ -108,31 +232,31 @@ fn basic_has_query() {
     let mut node = NodeState::new(&data);
     let response1 = node.receive(NodeMessage::HasQuery {
         location: 0,
-        value: 1,
+        values: vec![1],
     });
     assert_eq!(
         response1,
         NodeMessage::HasResponse {
             location: 0,
-            has: true
+            has: vec![true]
         }
     );
 
     let response2 = node.receive(NodeMessage::HasQuery {
         location: 1,
-        value: 1,
+        values: vec![1],
     });
     assert_eq!(
         response2,
         NodeMessage::HasResponse {
             location: 1,
-            has: false
+            has: vec![false]
         }
     );
 
     let response3 = node.receive(NodeMessage::HasQuery {
         location: 9,
-        value: 1,
+        values: vec![1],
     });
     // expect end when either side runs out of elements
     assert_eq!(response3, NodeMessage::End);
@@ -145,11 +269,11 @@ fn common_state_for_querier() {
 
     node.receive(NodeMessage::HasResponse {
         location: 0,
-        has: true,
+        has: vec![true],
     });
     node.receive(NodeMessage::HasResponse {
         location: 2,
-        has: true,
+        has: vec![true],
     });
 
     assert_eq!(node.common, vec![1, 3]);
@@ -162,11 +286,11 @@ fn common_state_for_responder() {
 
     node.receive(NodeMessage::HasQuery {
         location: 0,
-        value: 1,
+        values: vec![1],
     });
     node.receive(NodeMessage::HasQuery {
         location: 2,
-        value: 3,
+        values: vec![3],
     });
 
     assert_eq!(node.common, vec![1, 3]);
@@ -179,19 +303,110 @@ fn bad_response() {
 
     let response = node.receive(NodeMessage::HasResponse {
         location: 9,
-        has: true,
+        has: vec![true],
     });
 
     assert_eq!(response, NodeMessage::End);
 }
 
+#[test]
+fn finished_node_stays_closed() {
+    let data = vec![1, 2];
+    let mut node = NodeState::new(&data);
+
+    assert_eq!(node.outcome(), None);
+    node.receive(NodeMessage::HasQuery {
+        location: 0,
+        values: vec![1],
+    });
+    assert_eq!(node.receive(NodeMessage::End), NodeMessage::End);
+    assert!(node.is_finished());
+
+    let response = node.receive(NodeMessage::HasQuery {
+        location: 1,
+        values: vec![2],
+    });
+    assert_eq!(response, NodeMessage::Fail(ProtocolError::SessionClosed));
+    assert_eq!(node.outcome(), Some(Ok(&[1][..])));
+}
+
+#[test]
+fn peer_failure_outcome() {
+    let data = vec![1, 2];
+    let mut node = NodeState::new(&data);
+
+    node.start();
+    let response = node.receive(NodeMessage::Fail(ProtocolError::SessionClosed));
+
+    let cause = ProtocolError::peer(ProtocolError::SessionClosed);
+    assert_eq!(response, NodeMessage::Fail(cause.clone()));
+    assert_eq!(node.outcome(), Some(Err(&cause)));
+    assert_eq!(
+        node.start(),
+        NodeMessage::Fail(ProtocolError::SessionClosed)
+    );
+}
+
+#[test]
+fn repeated_messages() {
+    let data = vec![1, 2, 3];
+    let mut responder = NodeState::new(&data);
+    let query = NodeMessage::HasQuery {
+        location: 1,
+        values: vec![2],
+    };
+
+    responder.receive(query.clone());
+    assert_eq!(
+        responder.receive(query),
+        NodeMessage::Fail(ProtocolError::OutOfSequence {
+            expected: 2,
+            received: 1
+        })
+    );
+    // counted once even though it came twice
+    assert_eq!(responder.common, vec![2]);
+
+    let mut querier = NodeState::new(&data);
+    querier.start();
+    let response = NodeMessage::HasResponse {
+        location: 0,
+        has: vec![true],
+    };
+    querier.receive(response.clone());
+    assert!(matches!(
+        querier.receive(response),
+        NodeMessage::Fail(ProtocolError::OutOfSequence { .. })
+    ));
+    assert_eq!(querier.common, vec![1]);
+}
+
+#[test]
+fn repeated_last_location() {
+    // working out what we expected next mustn't overflow on the largest location a peer can send
+    let data = vec![1];
+    let mut node = NodeState::new(&data);
+    let response = NodeMessage::HasResponse {
+        location: usize::MAX,
+        has: vec![false],
+    };
+    node.receive(response.clone());
+    assert_eq!(
+        node.receive(response),
+        NodeMessage::Fail(ProtocolError::OutOfSequence {
+            expected: usize::MAX,
+            received: usize::MAX
+        })
+    );
+}
+
 #[allow(unused)]
 fn protocol(node1: &mut NodeState, node2: &mut NodeState) -> usize {
     let mut message = node1.start();
     let mut counter = 0;
     loop {
         counter += 1;
-        let response = node2.receive(message);
+        let response = node2.receive(message.clone());
         if message == NodeMessage::End {
             break;
         }
@@ -256,3 +471,122 @@ fn protocol_odd_lengths_reversed() {
     // initiator hangs up quicker because it's shorter, so only 2 loops
     assert_eq!(iterations, 3);
 }
+
+#[test]
+fn batched_protocol() {
+    let data = vec![7, 9, 10, 11, 12, 13, 14];
+    let data2 = vec![8, 9, 10];
+    let one = run_local(&mut NodeState::new(&data), &mut NodeState::new(&data2));
+    let batched = run_local(
+        &mut NodeState::batched(&data, 4),
+        &mut NodeState::batched(&data2, 4),
+    );
+
+    // same answer, the follower's data runs out part way through the first batch
+    assert_eq!(batched.leader.unwrap(), vec![9, 10]);
+    assert_eq!(batched.follower.unwrap(), vec![9, 10]);
+    assert_eq!(one.leader.unwrap(), vec![9, 10]);
+    assert_eq!((one.stats.rounds, batched.stats.rounds), (4, 2));
+}
+
+#[test]
+fn batch_over_the_limit() {
+    let data = vec![1, 2, 3];
+    let mut node = NodeState::batched(&data, 2);
+    let response = node.receive(NodeMessage::HasQuery {
+        location: 0,
+        values: vec![1, 2, 3],
+    });
+    assert_eq!(
+        response,
+        NodeMessage::Fail(ProtocolError::BatchTooLarge {
+            limit: 2,
+            received: 3
+        })
+    );
+    assert!(node.common.is_empty());
+}
+
+#[test]
+fn repeated_inside_a_batch() {
+    // a query starting inside the last one's batch has already been answered
+    let data = vec![1, 2, 3, 4];
+    let mut node = NodeState::batched(&data, 2);
+    node.receive(NodeMessage::HasQuery {
+        location: 0,
+        values: vec![1, 2],
+    });
+    assert_eq!(
+        node.receive(NodeMessage::HasQuery {
+            location: 1,
+            values: vec![2, 3],
+        }),
+        NodeMessage::Fail(ProtocolError::OutOfSequence {
+            expected: 2,
+            received: 1
+        })
+    );
+    assert_eq!(node.common, vec![1, 2]);
+}
+
+#[test]
+fn evil_peers() {
+    let ours = vec![1, 2, 3, 4];
+    for theirs in [vec![1, 5, 3], vec![1, 2, 3, 4], vec![9], vec![]] {
+        let runs = [Role::Leader, Role::Follower].map(|role| [(role, 1), (role, 3)]);
+        for (role, batch) in runs.into_iter().flatten() {
+            // simple takes the peer's word for it, all it can promise is not to make anything up
+            adversary::assault(
+                || NodeState::batched(&ours, batch),
+                || NodeState::batched(&theirs, batch),
+                role,
+                |element| ours.contains(element),
+            );
+        }
+    }
+}
+
+#[test]
+fn every_delivery_order() {
+    let sets = [vec![], vec![1], vec![1, 2], vec![2, 1]];
+    for (a, b) in sets.iter().flat_map(|a| sets.iter().map(move |b| (a, b))) {
+        let expected = positional(a, b);
+        let explored = explore::explore(
+            NodeState::new(a),
+            NodeState::new(b),
+            explore::Limits::default(),
+            |_, common| *common == expected,
+        );
+        assert!(
+            explored.violations.is_empty(),
+            "{a:?} and {b:?}: {:#?}",
+            explored.violations
+        );
+    }
+}
+
+/// What simple should find, equal values at the same position up to the shorter of the two
+#[cfg(test)]
+fn positional(a: &[u32], b: &[u32]) -> Vec<u32> {
+    a.iter()
+        .zip(b)
+        .filter(|(x, y)| x == y)
+        .map(|(x, _)| *x)
+        .collect()
+}
+
+#[cfg(test)]
+proptest! {
+    // few distinct values so there are plenty of matches and repeats
+    #[test]
+    fn matches_positional_model(a in vec(0..4u32, 0..24), b in vec(0..4u32, 0..24)) {
+        let run = run_local(&mut NodeState::new(&a), &mut NodeState::new(&b));
+        let expected = positional(&a, &b);
+        prop_assert_eq!(run.leader.unwrap(), expected.clone());
+        prop_assert_eq!(run.follower.unwrap(), expected.clone());
+
+        let run = run_local(&mut NodeState::batched(&a, 5), &mut NodeState::batched(&b, 5));
+        prop_assert_eq!(run.leader.unwrap(), expected.clone());
+        prop_assert_eq!(run.follower.unwrap(), expected);
+    }
+}
@@ -2,4 +2,4 @@ mod naive;
 mod node;
 mod traits;
 
-pub use node::SessionSalt;
\ No newline at end of file
+pub use node::SessionSalt;
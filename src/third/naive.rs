@@ -14,19 +14,19 @@ where
 type HashDigest = [u8; 32];
 
 impl<'a, T: Hash> PrivateSession<HashDigest> for NaiveSession<'a, T> {
-    fn next_challenge(&mut self, session_salt: SessionSalt) -> Option<HashDigest> {
+    fn next_challenge(&mut self, _session_salt: SessionSalt) -> Option<HashDigest> {
         None
     }
 
     fn respond_to_challenge(
         &mut self,
-        session_salt: SessionSalt,
-        challenge: HashDigest,
+        _session_salt: SessionSalt,
+        _challenge: HashDigest,
     ) -> Option<HashDigest> {
         None
     }
 
-    fn verify_challenge(&self, session_salt: SessionSalt, challenge: HashDigest) -> bool {
+    fn verify_challenge(&self, _session_salt: SessionSalt, _challenge: HashDigest) -> bool {
         false
     }
 }
@@ -2,7 +2,7 @@
 
 use std::{hash::Hash, marker::PhantomData};
 
-use sha2::Digest;
+use crate::error::ProtocolError;
 
 pub type SessionSalt = [u8; 32];
 pub type RoleSalt = [u8; 1];
@@ -19,6 +19,13 @@ impl NodeRole {
             NodeRole::Follower => [1],
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            NodeRole::Leader => "Leader",
+            NodeRole::Follower => "Follower",
+        }
+    }
 }
 
 pub struct Node<T> {
@@ -39,21 +46,24 @@ where
         }
     }
 
-    pub fn start(&self) -> Result<Message<T>, ApiError> {
+    pub fn start(&self) -> Result<Message<T>, ProtocolError> {
         if matches!(self.role, NodeRole::Follower) {
-            return Err(ApiError::FollowerCannotStart);
+            return Err(ProtocolError::role(self.role.name(), "start"));
         }
-        Err(())
+        Err(ProtocolError::role(
+            self.role.name(),
+            "start until the draft is finished",
+        ))
     }
 
-    pub fn receive(message: Message<T>) -> Message<T> {
+    pub fn receive(_message: Message<T>) -> Message<T> {
         Message::Done
     }
 
     pub fn final_results() {}
 }
 
-enum Message<T>
+pub enum Message<T>
 where
     T: Hash,
 {
@@ -62,12 +72,3 @@ where
     Fail(ProtocolError),
     Done,
 }
-
-enum ProtocolError {
-    UnexpectedMessage,
-    VerificationFailed,
-}
-
-enum ApiError {
-    FollowerCannotStart,
-}
@@ -0,0 +1,242 @@
+//! Running protocol nodes over a real connection instead of calling both sides in one loop.
+//!
+//! Every message goes out as one frame, a `u32` little endian length followed by the `wire` encoding.
+//! `drive` runs a node over anything `Read + Write` until either side sends a terminal message,
+//! the other modules here only set up the connection (TCP, a Unix socket, or stdin and stdout for
+//! piping between processes), or with the `tokio` feature do the same async.
+//! `mux` runs many sessions over one connection. `sim` skips the connection entirely and runs both sides over a lossy simulated network.
+//! With the `noise` feature any of these can run over an authenticated, encrypted channel instead
+mod mux;
+#[cfg(feature = "noise")]
+mod noise;
+mod sim;
+mod stdio;
+mod tcp;
+#[cfg(feature = "tokio")]
+mod tokio_io;
+#[cfg(unix)]
+mod unix;
+
+#[allow(unused)]
+pub use mux::*;
+#[cfg(feature = "noise")]
+#[allow(unused)]
+pub use noise::*;
+#[allow(unused)]
+pub use sim::*;
+#[allow(unused)]
+pub use stdio::*;
+#[allow(unused)]
+pub use tcp::*;
+#[cfg(feature = "tokio")]
+#[allow(unused)]
+pub use tokio_io::*;
+#[cfg(unix)]
+#[allow(unused)]
+pub use unix::*;
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+};
+
+use crate::error::ProtocolError;
+use crate::negotiate::NegotiationError;
+use crate::protocol::{Message, Protocol, Tally};
+use crate::wire::{self, DecodeError, Wire};
+
+/// Largest frame we'll read, a message is a handful of fields that each fit in `MAX_FIELD_LEN`
+pub const MAX_FRAME_LEN: usize = 4 * wire::MAX_FIELD_LEN;
+
+/// Every frame starts with its length as a `u32`
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Which end of the protocol we're running, the leader sends the first message
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Role {
+    Leader,
+    Follower,
+}
+
+impl Role {
+    pub fn peer(&self) -> Role {
+        match self {
+            Role::Leader => Role::Follower,
+            Role::Follower => Role::Leader,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Leader => "leader",
+            Role::Follower => "follower",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    /// the peer didn't send or read anything for longer than our timeout
+    TimedOut,
+    /// the connection ended before either side sent a terminal message
+    Closed,
+    FrameTooLong(usize),
+    Decode(DecodeError),
+    /// the connection was fine but the protocol itself failed, on either side
+    Protocol(ProtocolError),
+    /// the peers couldn't agree on what to run
+    Negotiation(NegotiationError),
+    /// the peer was already following as many of our sessions as it will, see `Mux`
+    Refused,
+    /// the secure channel handshake failed, or a message on it didn't decrypt
+    #[cfg(feature = "noise")]
+    Noise(snow::Error),
+    /// the handshake worked but the peer's static key isn't one we trust
+    #[cfg(feature = "noise")]
+    UntrustedPeer(PublicKey),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(error) => write!(f, "connection error: {error}"),
+            TransportError::TimedOut => write!(f, "timed out waiting for peer"),
+            TransportError::Closed => write!(f, "peer closed the connection early"),
+            TransportError::FrameTooLong(len) => {
+                write!(f, "frame of {len} bytes is over the {MAX_FRAME_LEN} limit")
+            }
+            TransportError::Decode(error) => write!(f, "bad message from peer: {error}"),
+            TransportError::Protocol(error) => write!(f, "protocol failed: {error}"),
+            TransportError::Negotiation(error) => write!(f, "negotiation failed: {error}"),
+            TransportError::Refused => write!(f, "peer refused the session, too many open"),
+            #[cfg(feature = "noise")]
+            TransportError::Noise(error) => write!(f, "secure channel failed: {error}"),
+            #[cfg(feature = "noise")]
+            TransportError::UntrustedPeer(key) => {
+                write!(f, "peer key ")?;
+                key.iter().try_for_each(|byte| write!(f, "{byte:02x}"))?;
+                write!(f, " isn't trusted")
+            }
+        }
+    }
+}
+
+impl Error for TransportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransportError::Io(error) => Some(error),
+            TransportError::Decode(error) => Some(error),
+            TransportError::Protocol(error) => Some(error),
+            TransportError::Negotiation(error) => Some(error),
+            #[cfg(feature = "noise")]
+            TransportError::Noise(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(error: io::Error) -> Self {
+        // the secure stream can only report a bad message as an io error, unwrap it again
+        #[cfg(feature = "noise")]
+        if error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<snow::Error>())
+        {
+            let inner = error.into_inner().expect("checked above");
+            return TransportError::Noise(*inner.downcast().expect("checked above"));
+        }
+        match error.kind() {
+            // read and write timeouts show up as either depending on the platform
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => TransportError::TimedOut,
+            io::ErrorKind::UnexpectedEof => TransportError::Closed,
+            _ => TransportError::Io(error),
+        }
+    }
+}
+
+pub fn write_frame<W: Write, M: Wire>(stream: &mut W, message: &M) -> Result<(), TransportError> {
+    let bytes = message.encode();
+    stream.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    Ok(())
+}
+
+pub fn read_frame<R: Read, M: Wire>(stream: &mut R) -> Result<M, TransportError> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(TransportError::FrameTooLong(len));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes)?;
+    M::decode(&bytes).map_err(TransportError::Decode)
+}
+
+/// Run `node` to completion over `stream`, returning its results or why it failed.
+///
+/// We stop as soon as the node is finished. A reply to the peer's own `Done` or `Fail` isn't sent,
+/// they've already hung up
+pub fn drive<P, S>(stream: &mut S, node: &mut P, role: Role) -> Result<P::Output, TransportError>
+where
+    P: Protocol,
+    S: Read + Write,
+{
+    drive_counted(stream, node, role, &mut Tally::default())
+}
+
+/// `drive`, recording every message sent and received in `tally`. It's kept up to date even when
+/// the session fails partway
+pub fn drive_counted<P, S>(
+    stream: &mut S,
+    node: &mut P,
+    role: Role,
+    tally: &mut Tally,
+) -> Result<P::Output, TransportError>
+where
+    P: Protocol,
+    S: Read + Write,
+{
+    let mut outgoing = match role {
+        Role::Leader => Some(node.start()),
+        Role::Follower => None,
+    };
+    loop {
+        if let Some(message) = outgoing.take() {
+            write_frame(stream, &message)?;
+            tally.record(role, &message);
+        }
+        if node.is_finished() {
+            break;
+        }
+        let incoming: P::Message = read_frame(stream)?;
+        tally.record(role.peer(), &incoming);
+        let hung_up = incoming.is_terminal();
+        let reply = node.receive(incoming);
+        if !hung_up {
+            outgoing = Some(reply);
+        }
+    }
+    finish(node)
+}
+
+/// Turn a finished node's outcome into the driver's result
+pub fn finish<P: Protocol>(node: &P) -> Result<P::Output, TransportError> {
+    match node.outcome() {
+        Some(Ok(output)) => Ok(output),
+        Some(Err(error)) => Err(TransportError::Protocol(error)),
+        None => Err(TransportError::Closed),
+    }
+}
+
+// tests
+
+/// Owned strings from literals, for every transport's tests
+#[cfg(test)]
+fn fix_array(input: &[&str]) -> Vec<String> {
+    input.iter().map(|s| s.to_string()).collect()
+}
@@ -0,0 +1,572 @@
+//! Many sessions over one connection. Every frame is wrapped in an `Envelope` carrying its session
+//! id, and `Mux` routes each one to that session's node.
+//!
+//! Either side can lead sessions. The side that dialed numbers its sessions with even ids and the
+//! side that listened uses odd ones, so they never collide. A frame for an id we haven't seen from
+//! the peer starts a new follower for it.
+//!
+//! Flow control is a window on how many sessions we lead at once, the rest wait until one
+//! finishes. The same window caps how many the peer can have us follow, a new session past it is
+//! turned away with `Refused` and ends on the peer's side with `TransportError::Refused`. Both
+//! sides should use the same window, a peer that respects its own never gets refused.
+//!
+//! Every session is lockstep, so at most one frame per session is ever waiting to go out, and they
+//! go out in the order they became ready. A session with lots of data gets one turn per round like
+//! everyone else, it can't starve the others. Both sides write everything they owe before reading,
+//! so keep `window` times the largest frame under what the socket buffers. A peer that stops
+//! reading (or sending) fails the whole connection with `TimedOut` once `MuxConfig::timeout`
+//! passes, rather than stalling every session on it forever.
+//!
+//! When a side has nothing more to lead it sends `GoAway`, the connection is done once both have
+//! and every session has finished
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::protocol::{Message, Protocol};
+use crate::transport::{DEFAULT_TIMEOUT, Role, TransportError, finish, read_frame, write_frame};
+
+pub type SessionId = u64;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Envelope<M> {
+    Data {
+        session: SessionId,
+        message: M,
+    },
+    /// the sender won't lead any more sessions on this connection
+    GoAway,
+    /// the sender already follows a full window of sessions and won't start this one
+    Refused {
+        session: SessionId,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct MuxConfig {
+    /// most sessions we lead at the same time, and most the peer can have us follow
+    pub window: usize,
+    /// longest we wait for the peer to take or send a frame before giving up on the connection
+    pub timeout: Duration,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        MuxConfig {
+            window: 16,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// A connection that can stop waiting on the peer, which `Mux::run` needs so a stalled peer is an
+/// error rather than a hang
+pub trait Deadline {
+    fn set_deadline(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Deadline for TcpStream {
+    fn set_deadline(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl Deadline for UnixStream {
+    fn set_deadline(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+/// How one session on the connection ended
+#[allow(unused)]
+#[derive(Debug)]
+pub struct SessionOutcome<O> {
+    pub session: SessionId,
+    /// the role our node had in it
+    pub role: Role,
+    pub result: Result<O, TransportError>,
+}
+
+pub struct Mux<P: Protocol, F> {
+    /// which end of the connection we are, decides the parity of our session ids
+    side: Role,
+    config: MuxConfig,
+    new_follower: F,
+    sessions: HashMap<SessionId, (P, Role)>,
+    /// sessions we'll lead once the window has room
+    waiting: VecDeque<(SessionId, P)>,
+    leading: usize,
+    following: usize,
+    next_id: SessionId,
+    /// highest id the peer has started, anything at or below it that we don't know is finished
+    peer_latest: Option<SessionId>,
+    outbox: VecDeque<Envelope<P::Message>>,
+    sent_go_away: bool,
+    peer_gone_away: bool,
+    outcomes: Vec<SessionOutcome<P::Output>>,
+}
+
+impl<P: Protocol, F: FnMut(SessionId) -> P> Mux<P, F> {
+    /// `side` is `Leader` on the end that dialed. `new_follower` makes the node for each session
+    /// the peer starts
+    #[allow(unused)]
+    pub fn new(side: Role, config: MuxConfig, new_follower: F) -> Self {
+        Mux {
+            side,
+            config,
+            new_follower,
+            sessions: HashMap::new(),
+            waiting: VecDeque::new(),
+            leading: 0,
+            following: 0,
+            next_id: match side {
+                Role::Leader => 0,
+                Role::Follower => 1,
+            },
+            peer_latest: None,
+            outbox: VecDeque::new(),
+            sent_go_away: false,
+            peer_gone_away: false,
+            outcomes: vec![],
+        }
+    }
+
+    /// Queue a session for `node` to lead, it starts once `run` has room for it in the window
+    #[allow(unused)]
+    pub fn open(&mut self, node: P) -> SessionId {
+        let session = self.next_id;
+        self.next_id += 2;
+        self.waiting.push_back((session, node));
+        session
+    }
+
+    /// Run every session we opened and every one the peer starts until both sides are done, giving
+    /// back how each ended. A connection error ends all of them and is returned instead
+    #[allow(unused)]
+    pub fn run<S: Read + Write + Deadline>(
+        mut self,
+        stream: &mut S,
+    ) -> Result<Vec<SessionOutcome<P::Output>>, TransportError> {
+        stream.set_deadline(self.config.timeout)?;
+        loop {
+            self.fill_window();
+            if self.waiting.is_empty() && !self.sent_go_away {
+                self.sent_go_away = true;
+                self.outbox.push_back(Envelope::GoAway);
+            }
+            while let Some(envelope) = self.outbox.pop_front() {
+                write_frame(stream, &envelope)?;
+            }
+            if self.peer_gone_away && self.sent_go_away && self.sessions.is_empty() {
+                return Ok(self.outcomes);
+            }
+            match read_frame(stream)? {
+                Envelope::Data { session, message } => self.deliver(session, message),
+                Envelope::GoAway => self.peer_gone_away = true,
+                Envelope::Refused { session } => self.refused(session),
+            }
+        }
+    }
+
+    fn fill_window(&mut self) {
+        while self.leading < self.config.window {
+            let Some((session, mut node)) = self.waiting.pop_front() else {
+                break;
+            };
+            self.leading += 1;
+            let message = node.start();
+            let refused = message.is_terminal();
+            self.send(session, message);
+            self.sessions.insert(session, (node, Role::Leader));
+            if refused {
+                // the node couldn't start, there's nothing to wait for
+                self.close(session);
+            } else {
+                self.close_if_finished(session);
+            }
+        }
+    }
+
+    fn deliver(&mut self, session: SessionId, message: P::Message) {
+        if !self.sessions.contains_key(&session) {
+            if self.is_ours(session) || self.peer_latest.is_some_and(|latest| session <= latest) {
+                // a straggler for a session that's already finished
+                return;
+            }
+            self.peer_latest = Some(session);
+            if self.following >= self.config.window {
+                // otherwise a peer opening new ids without end would grow `sessions` without end
+                self.outbox.push_back(Envelope::Refused { session });
+                return;
+            }
+            self.following += 1;
+            let node = (self.new_follower)(session);
+            self.sessions.insert(session, (node, Role::Follower));
+        }
+        let (node, _) = self.sessions.get_mut(&session).expect("inserted above");
+        let hung_up = message.is_terminal();
+        let reply = node.receive(message);
+        if hung_up {
+            // they've hung up on this session, no reply
+            self.close(session);
+        } else {
+            self.send(session, reply);
+            self.close_if_finished(session);
+        }
+    }
+
+    /// The peer turned away a session we lead, only ever before it replied to anything
+    fn refused(&mut self, session: SessionId) {
+        if !self.is_ours(session) || !self.sessions.contains_key(&session) {
+            return;
+        }
+        self.sessions.remove(&session);
+        self.leading -= 1;
+        self.outcomes.push(SessionOutcome {
+            session,
+            role: Role::Leader,
+            result: Err(TransportError::Refused),
+        });
+    }
+
+    fn send(&mut self, session: SessionId, message: P::Message) {
+        self.outbox.push_back(Envelope::Data { session, message });
+    }
+
+    fn is_ours(&self, session: SessionId) -> bool {
+        let even = session.is_multiple_of(2);
+        even == (self.side == Role::Leader)
+    }
+
+    fn close_if_finished(&mut self, session: SessionId) {
+        if self.sessions[&session].0.is_finished() {
+            self.close(session);
+        }
+    }
+
+    fn close(&mut self, session: SessionId) {
+        let (node, role) = self.sessions.remove(&session).expect("session is open");
+        match role {
+            Role::Leader => self.leading -= 1,
+            Role::Follower => self.following -= 1,
+        }
+        self.outcomes.push(SessionOutcome {
+            session,
+            role,
+            result: finish(&node),
+        });
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, NodeType},
+    simple,
+    transport::fix_array,
+};
+#[cfg(test)]
+use std::{collections::HashSet, net::TcpListener, thread};
+
+#[cfg(test)]
+fn connected() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dialer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (listened, _) = listener.accept().unwrap();
+    for stream in [&dialer, &listened] {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // lots of tiny lockstep frames, same as the TCP driver
+        stream.set_nodelay(true).unwrap();
+    }
+    (dialer, listened)
+}
+
+#[test]
+fn sessions_in_both_directions() {
+    let (mut a, mut b) = connected();
+    let ours = fix_array(&["a", "b", "c"]);
+    let theirs = fix_array(&["b", "c", "d"]);
+
+    // each side leads three sessions and follows the peer's three
+    let (opened, outcomes, listened) = thread::scope(|scope| {
+        let listener = scope.spawn(|| {
+            let mut mux = Mux::new(Role::Follower, MuxConfig::default(), |_| {
+                challenge::Node::new(&theirs, NodeType::Follower)
+            });
+            for _ in 0..3 {
+                mux.open(challenge::Node::new(&theirs, NodeType::Leader));
+            }
+            mux.run(&mut b)
+        });
+        let mut mux = Mux::new(Role::Leader, MuxConfig::default(), |_| {
+            challenge::Node::new(&ours, NodeType::Follower)
+        });
+        let opened: Vec<_> = (0..3)
+            .map(|_| mux.open(challenge::Node::new(&ours, NodeType::Leader)))
+            .collect();
+        let outcomes = mux.run(&mut a).unwrap();
+        (opened, outcomes, listener.join().unwrap().unwrap())
+    });
+
+    let common = HashSet::from_iter(fix_array(&["b", "c"]));
+    assert_eq!(opened, vec![0, 2, 4]);
+    for outcomes in [outcomes, listened] {
+        assert_eq!(outcomes.len(), 6);
+        let leading = outcomes.iter().filter(|o| o.role == Role::Leader).count();
+        assert_eq!(leading, 3);
+        for outcome in outcomes {
+            assert_eq!(outcome.result.unwrap(), common);
+        }
+    }
+}
+
+#[test]
+fn window_limits_sessions_in_flight() {
+    let (mut a, mut b) = connected();
+    let long: Vec<u32> = (0..200).collect();
+    let short = vec![0, 1];
+
+    let (big, outcomes, listened) = thread::scope(|scope| {
+        let listener = scope.spawn(|| {
+            Mux::new(Role::Follower, MuxConfig::default(), |_| {
+                simple::NodeState::new(&long)
+            })
+            .run(&mut b)
+        });
+
+        // one long session and a pile of short ones, with room for two at a time
+        let mut mux = Mux::new(
+            Role::Leader,
+            MuxConfig {
+                window: 2,
+                ..MuxConfig::default()
+            },
+            |_| panic!("the listener doesn't lead"),
+        );
+        let big = mux.open(simple::NodeState::new(&long));
+        for _ in 0..10 {
+            mux.open(simple::NodeState::new(&short));
+        }
+        let outcomes = mux.run(&mut a).unwrap();
+        (big, outcomes, listener.join().unwrap().unwrap())
+    });
+
+    // the short sessions all got through while the long one was still going
+    let finished: Vec<_> = outcomes.iter().map(|o| o.session).collect();
+    assert_eq!(finished.last(), Some(&big));
+    assert_eq!(outcomes.len(), 11);
+    for outcome in outcomes {
+        let expected = if outcome.session == big { 200 } else { 2 };
+        assert_eq!(outcome.result.unwrap().len(), expected);
+    }
+    assert_eq!(listened.len(), 11);
+}
+
+#[test]
+fn one_failed_session_leaves_the_rest() {
+    let (mut a, mut b) = connected();
+    let data = fix_array(&["a"]);
+
+    let (outcomes, theirs) = thread::scope(|scope| {
+        let listener = scope.spawn(|| {
+            // the second session the peer starts gets a node that thinks it leads
+            Mux::new(Role::Follower, MuxConfig::default(), |session| {
+                let node_type = match session {
+                    2 => NodeType::Leader,
+                    _ => NodeType::Follower,
+                };
+                challenge::Node::new(&data, node_type)
+            })
+            .run(&mut b)
+        });
+
+        let mut mux = Mux::new(Role::Leader, MuxConfig::default(), |_| {
+            panic!("the listener doesn't lead")
+        });
+        for _ in 0..3 {
+            mux.open(challenge::Node::new(&data, NodeType::Leader));
+        }
+        let outcomes = mux.run(&mut a).unwrap();
+        (outcomes, listener.join().unwrap().unwrap())
+    });
+
+    assert_eq!(outcomes.len(), 3);
+    for outcome in outcomes {
+        match outcome.session {
+            2 => assert!(matches!(outcome.result, Err(TransportError::Protocol(_)))),
+            _ => assert_eq!(outcome.result.unwrap().len(), 1),
+        }
+    }
+    assert_eq!(theirs.iter().filter(|o| o.result.is_err()).count(), 1);
+}
+
+#[test]
+fn nothing_to_do() {
+    let (mut a, mut b) = connected();
+    let empty = vec![];
+    thread::scope(|scope| {
+        let listener = scope.spawn(|| {
+            Mux::new(Role::Follower, MuxConfig::default(), |_| {
+                simple::NodeState::new(&empty)
+            })
+            .run(&mut b)
+        });
+
+        let mux = Mux::new(Role::Leader, MuxConfig::default(), |_| {
+            simple::NodeState::new(&empty)
+        });
+        assert!(mux.run(&mut a).unwrap().is_empty());
+        assert!(listener.join().unwrap().unwrap().is_empty());
+    });
+}
+
+#[test]
+fn peer_flooding_new_sessions_is_refused() {
+    let (mut evil, mut b) = connected();
+    let data = vec![1, 2, 3];
+
+    let replies = thread::scope(|scope| {
+        let listener = scope.spawn(|| {
+            Mux::new(
+                Role::Follower,
+                MuxConfig {
+                    window: 2,
+                    ..MuxConfig::default()
+                },
+                |_| simple::NodeState::new(&data),
+            )
+            .run(&mut b)
+        });
+
+        // starts ten sessions at once and never finishes any of them
+        let query = simple::NodeMessage::HasQuery {
+            location: 0,
+            values: vec![1],
+        };
+        for session in (0..20).step_by(2) {
+            let message = query.clone();
+            write_frame(&mut evil, &Envelope::Data { session, message }).unwrap();
+        }
+        let replies: Vec<Envelope<simple::NodeMessage>> =
+            (0..11).map(|_| read_frame(&mut evil).unwrap()).collect();
+        drop(evil);
+        assert!(listener.join().unwrap().is_err());
+        replies
+    });
+
+    // the listener has nothing to lead, then follows two and turns the rest away
+    let started: Vec<SessionId> = replies
+        .iter()
+        .filter_map(|reply| match reply {
+            Envelope::Data { session, .. } => Some(*session),
+            _ => None,
+        })
+        .collect();
+    let refused: Vec<SessionId> = replies
+        .iter()
+        .filter_map(|reply| match reply {
+            Envelope::Refused { session } => Some(*session),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(replies[0], Envelope::GoAway);
+    assert_eq!(started, vec![0, 2]);
+    assert_eq!(refused, vec![4, 6, 8, 10, 12, 14, 16, 18]);
+}
+
+#[test]
+fn sessions_past_the_peers_window_end_refused() {
+    let (mut a, mut b) = connected();
+    let data = vec![1, 2];
+
+    let outcomes = thread::scope(|scope| {
+        let listener = scope.spawn(|| {
+            Mux::new(
+                Role::Follower,
+                MuxConfig {
+                    window: 1,
+                    ..MuxConfig::default()
+                },
+                |_| simple::NodeState::new(&data),
+            )
+            .run(&mut b)
+        });
+        let mut mux = Mux::new(
+            Role::Leader,
+            MuxConfig {
+                window: 3,
+                ..MuxConfig::default()
+            },
+            |_| panic!("the listener doesn't lead"),
+        );
+        for _ in 0..3 {
+            mux.open(simple::NodeState::new(&data));
+        }
+        let outcomes = mux.run(&mut a).unwrap();
+        assert_eq!(listener.join().unwrap().unwrap().len(), 1);
+        outcomes
+    });
+
+    assert_eq!(outcomes.len(), 3);
+    for outcome in outcomes {
+        match outcome.session {
+            0 => assert_eq!(outcome.result.unwrap(), data),
+            _ => assert!(matches!(outcome.result, Err(TransportError::Refused))),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn peer_that_stops_reading_times_out() {
+    // a unix socket buffers far less than loopback tcp, so it backs up sooner
+    let (mut a, mut evil) = UnixStream::pair().unwrap();
+    let data: Vec<u32> = (0..100_000).collect();
+    let config = MuxConfig {
+        timeout: Duration::from_millis(200),
+        ..MuxConfig::default()
+    };
+
+    thread::scope(|scope| {
+        let listener = scope.spawn(|| {
+            Mux::new(Role::Follower, config, |_| simple::NodeState::new(&data)).run(&mut a)
+        });
+
+        // asks about every element and never reads an answer, until the answers back up so far
+        // that the listener stops reading the questions too
+        let mut queries = vec![];
+        for location in 0..data.len() {
+            let message = simple::NodeMessage::HasQuery {
+                location,
+                values: vec![0],
+            };
+            write_frame(
+                &mut queries,
+                &Envelope::Data {
+                    session: 0,
+                    message,
+                },
+            )
+            .unwrap();
+        }
+        evil.set_write_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(evil.write_all(&queries).is_err());
+        assert!(matches!(
+            listener.join().unwrap(),
+            Err(TransportError::TimedOut)
+        ));
+    });
+}
@@ -0,0 +1,764 @@
+//! Noise secure channel under any driver. Both sides run a handshake with static keys before the
+//! protocol starts and check the peer's key against the ones they trust. Every frame after that
+//! is encrypted, and the handshake hash is handed to the node with `bind_channel` so a relay in the
+//! middle would end up with two sessions that don't line up.
+//!
+//! `XX` sends both static keys during the handshake. `IK` needs the leader to know the follower's
+//! key up front and saves a round trip.
+//!
+//! On the wire every handshake and transport message is a `u32` little endian length followed by
+//! the noise message, a protocol frame is split over as many transport messages as it needs
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use snow::{Builder, HandshakeState, TransportState};
+
+use crate::protocol::Protocol;
+use crate::transport::{Role, TransportError, drive};
+use crate::wire;
+
+/// X25519 public key, what a peer is known by
+pub type PublicKey = [u8; 32];
+
+const XX: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const IK: &str = "Noise_IK_25519_ChaChaPoly_SHA256";
+
+/// Longest noise message, tag included
+const MAX_MESSAGE_LEN: usize = 65535;
+/// ChaChaPoly tag on every transport message
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Keypair {
+    pub private: [u8; 32],
+    pub public: PublicKey,
+}
+
+impl Keypair {
+    #[allow(unused)]
+    pub fn generate() -> Keypair {
+        let keys = Builder::new(XX.parse().expect("valid noise params"))
+            .generate_keypair()
+            .expect("default resolver supports 25519");
+        Keypair {
+            private: keys.private.try_into().expect("25519 keys are 32 bytes"),
+            public: keys.public.try_into().expect("25519 keys are 32 bytes"),
+        }
+    }
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keep private keys out of logs
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+#[allow(unused)]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Handshake {
+    XX,
+    /// `follower` is only used by the leader, the follower just needs to agree on `IK`
+    IK {
+        follower: PublicKey,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct SecureConfig {
+    pub keys: Keypair,
+    pub handshake: Handshake,
+    /// static keys of the peers we'll run the protocol with, anyone else fails the handshake
+    pub trusted: Vec<PublicKey>,
+}
+
+/// A stream that encrypts everything written to it once flushed, and decrypts what it reads
+pub struct SecureStream<S> {
+    inner: S,
+    noise: TransportState,
+    /// decrypted bytes not read yet, starting at `read_pos`
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// plaintext waiting on a flush
+    write_buf: Vec<u8>,
+    /// encrypted and framed, waiting to go out
+    sealed: Vec<u8>,
+    /// raw bytes of a frame still arriving, only the async side reads in pieces
+    #[allow(unused)]
+    incoming: Vec<u8>,
+}
+
+impl<S> SecureStream<S> {
+    fn new(inner: S, noise: TransportState) -> Self {
+        SecureStream {
+            inner,
+            noise,
+            read_buf: vec![],
+            read_pos: 0,
+            write_buf: vec![],
+            sealed: vec![],
+            incoming: vec![],
+        }
+    }
+
+    /// The peer's static key, already checked against `trusted`
+    #[allow(unused)]
+    pub fn remote_key(&self) -> PublicKey {
+        remote_key(self.noise.get_remote_static()).expect("checked during the handshake")
+    }
+
+    /// Encrypt `write_buf` into framed messages on the end of `sealed`
+    fn seal(&mut self) -> io::Result<()> {
+        let mut message = vec![0; MAX_MESSAGE_LEN];
+        for chunk in self.write_buf.chunks(MAX_MESSAGE_LEN - TAG_LEN) {
+            let len = self
+                .noise
+                .write_message(chunk, &mut message)
+                .map_err(io::Error::other)?;
+            self.sealed.extend((len as u32).to_le_bytes());
+            self.sealed.extend(&message[..len]);
+        }
+        self.write_buf.clear();
+        Ok(())
+    }
+
+    /// Decrypt one message into `read_buf`
+    fn open(&mut self, message: &[u8]) -> io::Result<()> {
+        let mut plain = vec![0; message.len()];
+        let len = self
+            .noise
+            .read_message(message, &mut plain)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        plain.truncate(len);
+        self.read_buf = plain;
+        self.read_pos = 0;
+        Ok(())
+    }
+
+    fn read_buffered(&mut self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.read_buf.len() - self.read_pos);
+        out[..len].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + len]);
+        self.read_pos += len;
+        len
+    }
+}
+
+impl<S: Read> Read for SecureStream<S> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // a message can decrypt to nothing, returning that would look like the end of the stream
+        while self.read_pos == self.read_buf.len() && !out.is_empty() {
+            let message = read_message(&mut self.inner)?;
+            self.open(&message)?;
+        }
+        Ok(self.read_buffered(out))
+    }
+}
+
+impl<S: Write> Write for SecureStream<S> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.seal()?;
+        self.inner.write_all(&self.sealed)?;
+        self.sealed.clear();
+        self.inner.flush()
+    }
+}
+
+fn write_message<W: Write>(stream: &mut W, message: &[u8]) -> io::Result<()> {
+    stream.write_all(&(message.len() as u32).to_le_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()
+}
+
+fn read_message<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = message_len(len)?;
+    let mut message = vec![0; len];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn message_len(header: [u8; 4]) -> io::Result<usize> {
+    let len = u32::from_le_bytes(header) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("noise message of {len} bytes is over the {MAX_MESSAGE_LEN} limit"),
+        ));
+    }
+    Ok(len)
+}
+
+fn remote_key(key: Option<&[u8]>) -> Option<PublicKey> {
+    key.and_then(|key| key.try_into().ok())
+}
+
+fn handshake_state(config: &SecureConfig, role: Role) -> Result<HandshakeState, TransportError> {
+    let params = match config.handshake {
+        Handshake::XX => XX,
+        Handshake::IK { .. } => IK,
+    };
+    // both sides have to agree on the wire format too, a mismatch fails the handshake
+    let prologue = format!("treehopper wire v{}", wire::VERSION);
+    let builder = Builder::new(params.parse().expect("valid noise params"))
+        .prologue(prologue.as_bytes())
+        .local_private_key(&config.keys.private);
+    match (role, &config.handshake) {
+        (Role::Leader, Handshake::IK { follower }) => {
+            builder.remote_public_key(follower).build_initiator()
+        }
+        (Role::Leader, Handshake::XX) => builder.build_initiator(),
+        (Role::Follower, _) => builder.build_responder(),
+    }
+    .map_err(TransportError::Noise)
+}
+
+/// Check who we ended up talking to, returning the handshake hash and the transport keys
+fn finish_handshake(
+    state: HandshakeState,
+    config: &SecureConfig,
+) -> Result<(Vec<u8>, TransportState), TransportError> {
+    let remote =
+        remote_key(state.get_remote_static()).expect("XX and IK always send the peer's static key");
+    if !config.trusted.contains(&remote) {
+        return Err(TransportError::UntrustedPeer(remote));
+    }
+    let hash = state.get_handshake_hash().to_vec();
+    let noise = state.into_transport_mode().map_err(TransportError::Noise)?;
+    Ok((hash, noise))
+}
+
+/// Run the handshake over `stream`, the leader initiates. Returns the encrypted stream and the
+/// handshake hash, which both sides end up with but nobody else can
+pub fn handshake<S: Read + Write>(
+    mut stream: S,
+    config: &SecureConfig,
+    role: Role,
+) -> Result<(SecureStream<S>, Vec<u8>), TransportError> {
+    let mut state = handshake_state(config, role)?;
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state
+                .write_message(&[], &mut buf)
+                .map_err(TransportError::Noise)?;
+            write_message(&mut stream, &buf[..len])?;
+        } else {
+            let message = read_message(&mut stream)?;
+            state
+                .read_message(&message, &mut buf)
+                .map_err(TransportError::Noise)?;
+        }
+    }
+    let (hash, noise) = finish_handshake(state, config)?;
+    Ok((SecureStream::new(stream, noise), hash))
+}
+
+/// `drive` over a secure channel, handshaking first and binding the node to it
+#[allow(unused)]
+pub fn drive_secure<P, S>(
+    stream: S,
+    node: &mut P,
+    role: Role,
+    config: &SecureConfig,
+) -> Result<P::Output, TransportError>
+where
+    P: Protocol,
+    S: Read + Write,
+{
+    let (mut stream, hash) = handshake(stream, config, role)?;
+    node.bind_channel(&hash);
+    drive(&mut stream, node, role)
+}
+
+#[cfg(feature = "tokio")]
+mod io_async {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll, ready},
+    };
+
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+    use super::*;
+    use crate::transport::drive_async;
+
+    async fn write_message_async<W>(stream: &mut W, message: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        stream
+            .write_all(&(message.len() as u32).to_le_bytes())
+            .await?;
+        stream.write_all(message).await?;
+        stream.flush().await
+    }
+
+    async fn read_message_async<R>(stream: &mut R) -> io::Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).await?;
+        let len = message_len(len)?;
+        let mut message = vec![0; len];
+        stream.read_exact(&mut message).await?;
+        Ok(message)
+    }
+
+    /// Async version of `handshake`
+    pub async fn handshake_async<S>(
+        mut stream: S,
+        config: &SecureConfig,
+        role: Role,
+    ) -> Result<(SecureStream<S>, Vec<u8>), TransportError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut state = handshake_state(config, role)?;
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+        while !state.is_handshake_finished() {
+            if state.is_my_turn() {
+                let len = state
+                    .write_message(&[], &mut buf)
+                    .map_err(TransportError::Noise)?;
+                write_message_async(&mut stream, &buf[..len]).await?;
+            } else {
+                let message = read_message_async(&mut stream).await?;
+                state
+                    .read_message(&message, &mut buf)
+                    .map_err(TransportError::Noise)?;
+            }
+        }
+        let (hash, noise) = finish_handshake(state, config)?;
+        Ok((SecureStream::new(stream, noise), hash))
+    }
+
+    /// Async version of `drive_secure`
+    #[allow(unused)]
+    pub async fn drive_secure_async<P, S>(
+        stream: S,
+        node: &mut P,
+        role: Role,
+        config: &SecureConfig,
+    ) -> Result<P::Output, TransportError>
+    where
+        P: Protocol,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut stream, hash) = handshake_async(stream, config, role).await?;
+        node.bind_channel(&hash);
+        drive_async(&mut stream, node, role).await
+    }
+
+    impl<S: AsyncRead + Unpin> SecureStream<S> {
+        /// Read the rest of the next message into `incoming` and decrypt it, never reading past it
+        fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            loop {
+                let wanted = match self.incoming.get(..4) {
+                    Some(header) => 4 + message_len(header.try_into().unwrap())?,
+                    None => 4,
+                };
+                if self.incoming.len() >= 4 && self.incoming.len() == wanted {
+                    let message = self.incoming.split_off(4);
+                    self.incoming.clear();
+                    self.open(&message)?;
+                    return Poll::Ready(Ok(()));
+                }
+                let mut chunk = vec![0; wanted - self.incoming.len()];
+                let mut chunk_buf = ReadBuf::new(&mut chunk);
+                ready!(Pin::new(&mut self.inner).poll_read(cx, &mut chunk_buf))?;
+                if chunk_buf.filled().is_empty() {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                self.incoming.extend(chunk_buf.filled());
+            }
+        }
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for SecureStream<S> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            out: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            // skip messages that decrypt to nothing, same as the sync side
+            while this.read_pos == this.read_buf.len() && out.remaining() > 0 {
+                ready!(this.poll_message(cx))?;
+            }
+            let len = this.read_buffered(out.initialize_unfilled());
+            out.advance(len);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for SecureStream<S> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bytes: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().write_buf.extend(bytes);
+            Poll::Ready(Ok(bytes.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            this.seal()?;
+            while !this.sealed.is_empty() {
+                let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.sealed))?;
+                if written == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                this.sealed.drain(..written);
+            }
+            Pin::new(&mut this.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            ready!(self.as_mut().poll_flush(cx))?;
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[allow(unused)]
+pub use io_async::*;
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, NodeType},
+    simple,
+    transport::fix_array,
+};
+#[cfg(test)]
+use std::{
+    collections::HashSet,
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+#[allow(unused)]
+fn config(keys: &Keypair, handshake: Handshake, trusted: &[&Keypair]) -> SecureConfig {
+    SecureConfig {
+        keys: keys.clone(),
+        handshake,
+        trusted: trusted.iter().map(|keys| keys.public).collect(),
+    }
+}
+
+/// Both ends of a localhost connection, with timeouts so a failed test can't hang
+#[cfg(test)]
+fn connected() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let leader = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (follower, _) = listener.accept().unwrap();
+    for stream in [&leader, &follower] {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+    }
+    (leader, follower)
+}
+
+/// Both ends of a connection that's finished its handshake
+#[cfg(test)]
+fn secured() -> (SecureStream<TcpStream>, SecureStream<TcpStream>) {
+    let (a, b) = (Keypair::generate(), Keypair::generate());
+    let (leader_stream, follower_stream) = connected();
+    let follower_config = config(&b, Handshake::XX, &[&a]);
+    let follower =
+        thread::spawn(move || handshake(follower_stream, &follower_config, Role::Follower));
+    let (leader, _) = handshake(
+        leader_stream,
+        &config(&a, Handshake::XX, &[&b]),
+        Role::Leader,
+    )
+    .unwrap();
+    let (follower, _) = follower.join().unwrap().unwrap();
+    (leader, follower)
+}
+
+#[test]
+fn challenge_over_xx() {
+    let (a, b) = (Keypair::generate(), Keypair::generate());
+    let (leader_stream, follower_stream) = connected();
+
+    let leader_config = config(&a, Handshake::XX, &[&b]);
+    let follower = thread::spawn(move || {
+        let data = fix_array(&["b", "c", "d"]);
+        let mut node = challenge::Node::new(&data, NodeType::Follower);
+        let config = config(&b, Handshake::XX, &[&a]);
+        drive_secure(follower_stream, &mut node, Role::Follower, &config)
+    });
+
+    let data = fix_array(&["a", "b", "c"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = drive_secure(leader_stream, &mut node, Role::Leader, &leader_config);
+
+    let common = HashSet::from_iter(fix_array(&["b", "c"]));
+    assert_eq!(result.unwrap(), common);
+    assert_eq!(follower.join().unwrap().unwrap(), common);
+}
+
+#[test]
+fn simple_over_ik() {
+    let (a, b) = (Keypair::generate(), Keypair::generate());
+    let (leader_stream, follower_stream) = connected();
+
+    let leader_config = config(&a, Handshake::IK { follower: b.public }, &[&b]);
+    let follower = thread::spawn(move || {
+        let config = config(&b, Handshake::IK { follower: b.public }, &[&a]);
+        let (stream, hash) = handshake(follower_stream, &config, Role::Follower).unwrap();
+        assert_eq!(stream.remote_key(), a.public);
+        let data = vec![8, 9];
+        let mut node = simple::NodeState::new(&data);
+        let mut stream = stream;
+        (drive(&mut stream, &mut node, Role::Follower), hash)
+    });
+
+    let (mut stream, hash) = handshake(leader_stream, &leader_config, Role::Leader).unwrap();
+    let data = vec![7, 9, 10];
+    let mut node = simple::NodeState::new(&data);
+    let result = drive(&mut stream, &mut node, Role::Leader);
+
+    let (follower_result, follower_hash) = follower.join().unwrap();
+    assert_eq!(result.unwrap(), vec![9]);
+    assert_eq!(follower_result.unwrap(), vec![9]);
+    assert_eq!(hash, follower_hash);
+}
+
+#[test]
+fn untrusted_peer_rejected() {
+    let (a, b, stranger) = (
+        Keypair::generate(),
+        Keypair::generate(),
+        Keypair::generate(),
+    );
+    let (leader_stream, follower_stream) = connected();
+
+    // the follower only trusts someone else
+    let leader_config = config(&a, Handshake::XX, &[&b]);
+    let follower = thread::spawn(move || {
+        let config = config(&b, Handshake::XX, &[&stranger]);
+        handshake(follower_stream, &config, Role::Follower).map(|_| ())
+    });
+
+    let data = fix_array(&["a"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = drive_secure(leader_stream, &mut node, Role::Leader, &leader_config);
+
+    assert!(matches!(
+        follower.join().unwrap(),
+        Err(TransportError::UntrustedPeer(key)) if key == a.public
+    ));
+    assert!(result.is_err());
+    assert_eq!(node.outcome(), None);
+}
+
+#[test]
+fn ik_with_wrong_follower_key() {
+    let (a, b, stranger) = (
+        Keypair::generate(),
+        Keypair::generate(),
+        Keypair::generate(),
+    );
+    let (leader_stream, follower_stream) = connected();
+
+    // the leader encrypts to a key the follower doesn't have
+    let leader_config = config(
+        &a,
+        Handshake::IK {
+            follower: stranger.public,
+        },
+        &[&stranger],
+    );
+    let follower = thread::spawn(move || {
+        let config = config(&b, Handshake::IK { follower: b.public }, &[&a]);
+        handshake(follower_stream, &config, Role::Follower).map(|_| ())
+    });
+
+    let result = handshake(leader_stream, &leader_config, Role::Leader);
+
+    assert!(matches!(
+        follower.join().unwrap(),
+        Err(TransportError::Noise(_))
+    ));
+    assert!(result.is_err());
+}
+
+#[test]
+fn tampered_message_rejected() {
+    let (a, b) = (Keypair::generate(), Keypair::generate());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let relay_address = listener.local_addr().unwrap();
+    let (relay_out, follower_stream) = connected();
+
+    // passes the handshake through untouched, then flips a bit in the leader's first message
+    let relay = thread::spawn(move || {
+        let (mut from_leader, _) = listener.accept().unwrap();
+        let mut to_follower = relay_out;
+        let forward = |from: &mut TcpStream, to: &mut TcpStream| {
+            let message = read_message(from).unwrap();
+            write_message(to, &message).unwrap();
+        };
+        forward(&mut from_leader, &mut to_follower);
+        forward(&mut to_follower, &mut from_leader);
+        forward(&mut from_leader, &mut to_follower);
+        let mut message = read_message(&mut from_leader).unwrap();
+        message[0] ^= 1;
+        write_message(&mut to_follower, &message).unwrap();
+        // hold the leader's end open until the follower has given up
+        thread::sleep(Duration::from_millis(200));
+    });
+
+    let leader_config = config(&a, Handshake::XX, &[&b]);
+    let follower = thread::spawn(move || {
+        let data = fix_array(&["a"]);
+        let mut node = challenge::Node::new(&data, NodeType::Follower);
+        let config = config(&b, Handshake::XX, &[&a]);
+        drive_secure(follower_stream, &mut node, Role::Follower, &config)
+    });
+
+    let stream = TcpStream::connect(relay_address).unwrap();
+    let (mut stream, _) = handshake(stream, &leader_config, Role::Leader).unwrap();
+    let data = fix_array(&["a"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    crate::transport::write_frame(&mut stream, &node.start()).unwrap();
+
+    assert!(matches!(
+        follower.join().unwrap(),
+        Err(TransportError::Noise(_))
+    ));
+    relay.join().unwrap();
+}
+
+#[test]
+fn wrong_wire_version_fails_handshake() {
+    let (a, b) = (Keypair::generate(), Keypair::generate());
+    let (leader_stream, mut follower_stream) = connected();
+
+    // a follower from another version, same keys but a different prologue
+    let follower = thread::spawn(move || {
+        let mut state = Builder::new(XX.parse().unwrap())
+            .prologue(b"treehopper wire v0")
+            .local_private_key(&b.private)
+            .build_responder()
+            .unwrap();
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+        let message = read_message(&mut follower_stream).unwrap();
+        state.read_message(&message, &mut buf).unwrap();
+        let len = state.write_message(&[], &mut buf).unwrap();
+        write_message(&mut follower_stream, &buf[..len]).unwrap();
+    });
+
+    let result = handshake(
+        leader_stream,
+        &config(&a, Handshake::XX, &[&b]),
+        Role::Leader,
+    );
+
+    assert!(matches!(result, Err(TransportError::Noise(_))));
+    follower.join().unwrap();
+}
+
+#[test]
+fn empty_message_is_not_eof() {
+    let (mut left, mut right) = secured();
+
+    // a valid transport message with nothing in it, then some data
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+    let len = left.noise.write_message(&[], &mut message).unwrap();
+    write_message(&mut left.inner, &message[..len]).unwrap();
+    left.write_all(b"hi").unwrap();
+    left.flush().unwrap();
+
+    let mut received = [0; 2];
+    assert_eq!(right.read(&mut received).unwrap(), 2);
+    assert_eq!(&received, b"hi");
+}
+
+#[test]
+fn large_writes_split() {
+    let (mut left, mut right) = secured();
+
+    // bigger than one noise message so it goes out in pieces
+    let sent: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    let mut received = vec![0; sent.len()];
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            left.write_all(&sent).unwrap();
+            left.flush().unwrap();
+        });
+        right.read_exact(&mut received).unwrap();
+    });
+    assert_eq!(received, sent);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn challenge_over_xx_async() {
+    let (a, b) = (Keypair::generate(), Keypair::generate());
+    let (left, right) = tokio::io::duplex(64);
+    let data = fix_array(&["a", "b", "c"]);
+    let data2 = fix_array(&["c", "d", "a"]);
+    let mut leader = challenge::Node::new(&data, NodeType::Leader);
+    let mut follower = challenge::Node::new(&data2, NodeType::Follower);
+    let leader_config = config(&a, Handshake::XX, &[&b]);
+    let follower_config = config(&b, Handshake::XX, &[&a]);
+
+    let (r1, r2) = tokio::join!(
+        drive_secure_async(left, &mut leader, Role::Leader, &leader_config),
+        drive_secure_async(right, &mut follower, Role::Follower, &follower_config),
+    );
+
+    let common = HashSet::from_iter(fix_array(&["a", "c"]));
+    assert_eq!(r1.unwrap(), common);
+    assert_eq!(r2.unwrap(), common);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn large_writes_split_async() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (a, b) = (Keypair::generate(), Keypair::generate());
+    let (left, right) = tokio::io::duplex(1024);
+    let leader_config = config(&a, Handshake::IK { follower: b.public }, &[&b]);
+    let follower_config = config(&b, Handshake::IK { follower: b.public }, &[&a]);
+    let (left, right) = tokio::join!(
+        handshake_async(left, &leader_config, Role::Leader),
+        handshake_async(right, &follower_config, Role::Follower),
+    );
+    let ((mut left, hash1), (mut right, hash2)) = (left.unwrap(), right.unwrap());
+    assert_eq!(hash1, hash2);
+
+    // bigger than one noise message so it goes out in pieces
+    let sent: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    let mut received = vec![0; sent.len()];
+    let (written, read) = tokio::join!(
+        async {
+            left.write_all(&sent).await?;
+            left.flush().await
+        },
+        right.read_exact(&mut received),
+    );
+    written.unwrap();
+    read.unwrap();
+    assert_eq!(received, sent);
+}
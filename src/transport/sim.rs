@@ -0,0 +1,385 @@
+//! Simulated network for testing, runs a leader and a follower against each other in one loop over
+//! virtual time, with seeded latency, drops, duplicates and reordering.
+//!
+//! Messages still go through the `wire` encoding. Each side behaves like `drive` would: it stops
+//! reading once finished and doesn't answer the peer's `Done` or `Fail`. When nothing is left in
+//! flight any side that hasn't finished gets `TimedOut`, which is what a real timeout would do
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::protocol::{Message, Protocol};
+use crate::transport::{TransportError, finish};
+use crate::wire::Wire;
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64,
+    /// every message takes between these many ticks to arrive, inclusive
+    pub latency: (u64, u64),
+    pub drop_rate: f64,
+    /// chance a message is delivered a second time, with its own latency
+    pub duplicate_rate: f64,
+    /// chance a message is held back an extra `latency.1` ticks so later ones can overtake it
+    pub reorder_rate: f64,
+    /// stop delivering after this many messages, a guard against runaway duplication
+    pub max_messages: usize,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            seed: 0,
+            latency: (1, 1),
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            max_messages: 10_000,
+        }
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct SimReport<L, F> {
+    pub leader: Result<L, TransportError>,
+    pub follower: Result<F, TransportError>,
+    pub delivered: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    /// virtual ticks from the first message to the last delivery
+    pub elapsed: u64,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+enum Side {
+    Leader,
+    Follower,
+}
+
+/// (arrival time, send order, recipient, frame), the send order keeps equal times fifo
+type InFlight = Reverse<(u64, usize, Side, Vec<u8>)>;
+
+struct Network {
+    config: SimConfig,
+    rng: StdRng,
+    now: u64,
+    sent: usize,
+    in_flight: BinaryHeap<InFlight>,
+    dropped: usize,
+    duplicated: usize,
+}
+
+impl Network {
+    fn send<M: Wire>(&mut self, to: Side, message: &M) {
+        if self.rng.random_bool(self.config.drop_rate) {
+            self.dropped += 1;
+            return;
+        }
+        let bytes = message.encode();
+        if self.rng.random_bool(self.config.duplicate_rate) {
+            self.duplicated += 1;
+            self.schedule(to, bytes.clone());
+        }
+        self.schedule(to, bytes);
+    }
+
+    fn schedule(&mut self, to: Side, bytes: Vec<u8>) {
+        let (min, max) = self.config.latency;
+        let mut arrival = self.now + self.rng.random_range(min..=max);
+        if self.rng.random_bool(self.config.reorder_rate) {
+            arrival += max;
+        }
+        self.sent += 1;
+        self.in_flight
+            .push(Reverse((arrival, self.sent, to, bytes)));
+    }
+}
+
+/// Hand a frame to `node`, returning the reply it wants sent if any
+fn deliver<P: Protocol>(node: &mut P, bytes: &[u8]) -> Option<P::Message> {
+    if node.is_finished() {
+        // closed its end, nobody reads this
+        return None;
+    }
+    let message = P::Message::decode(bytes).expect("sim only carries frames it encoded");
+    let hung_up = message.is_terminal();
+    let reply = node.receive(message);
+    (!hung_up).then_some(reply)
+}
+
+fn result<P: Protocol>(node: &P) -> Result<P::Output, TransportError> {
+    match node.is_finished() {
+        true => finish(node),
+        false => Err(TransportError::TimedOut),
+    }
+}
+
+/// Run both nodes to completion over a simulated network, never blocks
+#[allow(unused)]
+pub fn run_simulated<L, F>(
+    leader: &mut L,
+    follower: &mut F,
+    config: &SimConfig,
+) -> SimReport<L::Output, F::Output>
+where
+    L: Protocol,
+    F: Protocol<Message = L::Message>,
+{
+    let mut network = Network {
+        rng: StdRng::seed_from_u64(config.seed),
+        config: config.clone(),
+        now: 0,
+        sent: 0,
+        in_flight: BinaryHeap::new(),
+        dropped: 0,
+        duplicated: 0,
+    };
+    let mut delivered = 0;
+
+    let first = leader.start();
+    network.send(Side::Follower, &first);
+
+    while let Some(Reverse((arrival, _, to, bytes))) = network.in_flight.pop() {
+        if delivered >= config.max_messages {
+            break;
+        }
+        network.now = arrival;
+        delivered += 1;
+        match to {
+            Side::Leader => {
+                if let Some(reply) = deliver(leader, &bytes) {
+                    network.send(Side::Follower, &reply);
+                }
+            }
+            Side::Follower => {
+                if let Some(reply) = deliver(follower, &bytes) {
+                    network.send(Side::Leader, &reply);
+                }
+            }
+        }
+    }
+
+    SimReport {
+        leader: result(leader),
+        follower: result(follower),
+        delivered,
+        dropped: network.dropped,
+        duplicated: network.duplicated,
+        elapsed: network.now,
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, NodeType},
+    simple,
+};
+#[cfg(test)]
+use std::collections::HashSet;
+
+/// Random small sets that overlap a fair amount
+#[allow(unused)]
+fn random_strings(rng: &mut StdRng) -> Vec<String> {
+    let len = rng.random_range(0..8);
+    (0..len)
+        .map(|_| format!("{}", rng.random_range(0..12)))
+        .collect()
+}
+
+#[allow(unused)]
+fn faulty_configs() -> Vec<SimConfig> {
+    let base = SimConfig {
+        latency: (1, 20),
+        ..SimConfig::default()
+    };
+    vec![
+        SimConfig {
+            drop_rate: 0.05,
+            ..base.clone()
+        },
+        SimConfig {
+            duplicate_rate: 0.1,
+            ..base.clone()
+        },
+        SimConfig {
+            duplicate_rate: 0.1,
+            reorder_rate: 0.3,
+            ..base.clone()
+        },
+        SimConfig {
+            drop_rate: 0.05,
+            duplicate_rate: 0.1,
+            reorder_rate: 0.3,
+            ..base
+        },
+    ]
+}
+
+/// A side either got the right answer or failed with a typed error, never anything else
+#[allow(unused)]
+fn correct_or_failed<T: PartialEq + std::fmt::Debug>(
+    result: &Result<T, TransportError>,
+    expected: &T,
+) -> bool {
+    match result {
+        Ok(output) => {
+            assert_eq!(output, expected);
+            true
+        }
+        Err(TransportError::Protocol(_) | TransportError::TimedOut) => false,
+        Err(error) => panic!("untyped failure {error:?}"),
+    }
+}
+
+#[test]
+fn perfect_network_completes() {
+    let data = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+    let data2 = vec!["c".to_owned(), "a".to_owned()];
+    let mut leader = challenge::Node::seeded(&data, NodeType::Leader, 1);
+    let mut follower = challenge::Node::seeded(&data2, NodeType::Follower, 2);
+
+    let report = run_simulated(&mut leader, &mut follower, &SimConfig::default());
+
+    let common = HashSet::from_iter(data2.iter().cloned());
+    assert_eq!(report.leader.unwrap(), common);
+    assert_eq!(report.follower.unwrap(), common);
+    // start, init, 3 queries and responses, done
+    assert_eq!(report.delivered, 9);
+    assert_eq!(report.elapsed, 9);
+}
+
+#[test]
+fn latency_and_reordering_alone_always_complete() {
+    // only one message is ever in flight so there's nothing to overtake
+    let mut rng = StdRng::seed_from_u64(0);
+    for seed in 0..100 {
+        let data = random_strings(&mut rng);
+        let data2 = random_strings(&mut rng);
+        let mut leader = challenge::Node::seeded(&data, NodeType::Leader, seed);
+        let mut follower = challenge::Node::seeded(&data2, NodeType::Follower, seed);
+        let config = SimConfig {
+            seed,
+            latency: (1, 50),
+            reorder_rate: 0.5,
+            ..SimConfig::default()
+        };
+
+        let report = run_simulated(&mut leader, &mut follower, &config);
+
+        let all: HashSet<_> = data2.iter().cloned().collect();
+        let common: HashSet<_> = data.iter().filter(|d| all.contains(*d)).cloned().collect();
+        assert_eq!(report.leader.unwrap(), common);
+        assert_eq!(report.follower.unwrap(), common);
+    }
+}
+
+#[test]
+fn drops_time_out() {
+    let data = vec!["a".to_owned()];
+    let mut leader = challenge::Node::seeded(&data, NodeType::Leader, 1);
+    let mut follower = challenge::Node::seeded(&data, NodeType::Follower, 2);
+    let config = SimConfig {
+        drop_rate: 1.0,
+        ..SimConfig::default()
+    };
+
+    let report = run_simulated(&mut leader, &mut follower, &config);
+
+    assert!(matches!(report.leader, Err(TransportError::TimedOut)));
+    assert!(matches!(report.follower, Err(TransportError::TimedOut)));
+    assert_eq!(report.dropped, 1);
+    assert_eq!(report.delivered, 0);
+}
+
+#[test]
+fn challenge_under_faults() {
+    let mut rng = StdRng::seed_from_u64(1);
+    for config in faulty_configs() {
+        let mut completed = 0;
+        for seed in 0..200 {
+            let data = random_strings(&mut rng);
+            let data2 = random_strings(&mut rng);
+            let mut leader = challenge::Node::seeded(&data, NodeType::Leader, seed);
+            let mut follower = challenge::Node::seeded(&data2, NodeType::Follower, seed + 1);
+            let config = SimConfig {
+                seed,
+                ..config.clone()
+            };
+
+            let report = run_simulated(&mut leader, &mut follower, &config);
+
+            let all: HashSet<_> = data2.iter().cloned().collect();
+            let common: HashSet<_> = data.iter().filter(|d| all.contains(*d)).cloned().collect();
+            let done = correct_or_failed(&report.leader, &common);
+            correct_or_failed(&report.follower, &common);
+            completed += done as usize;
+        }
+        // the faults are rare enough that most runs should still get through
+        assert!(completed > 50, "{config:?} only completed {completed}");
+    }
+}
+
+#[test]
+fn simple_under_faults() {
+    let mut rng = StdRng::seed_from_u64(2);
+    for config in faulty_configs() {
+        let mut completed = 0;
+        for seed in 0..200 {
+            let data: Vec<u32> = (0..rng.random_range(0..8))
+                .map(|_| rng.random_range(0..3))
+                .collect();
+            let data2: Vec<u32> = (0..rng.random_range(0..8))
+                .map(|_| rng.random_range(0..3))
+                .collect();
+            let mut leader = simple::NodeState::new(&data);
+            let mut follower = simple::NodeState::new(&data2);
+            let config = SimConfig {
+                seed,
+                ..config.clone()
+            };
+
+            let report = run_simulated(&mut leader, &mut follower, &config);
+
+            // position-wise reference model
+            let common: Vec<u32> = data
+                .iter()
+                .zip(&data2)
+                .filter(|(a, b)| a == b)
+                .map(|(a, _)| *a)
+                .collect();
+            let done = correct_or_failed(&report.leader, &common);
+            correct_or_failed(&report.follower, &common);
+            completed += done as usize;
+        }
+        assert!(completed > 50, "{config:?} only completed {completed}");
+    }
+}
+
+#[test]
+fn same_seed_same_run() {
+    let data = vec!["a".to_owned(), "b".to_owned()];
+    let config = SimConfig {
+        seed: 7,
+        latency: (1, 30),
+        drop_rate: 0.1,
+        duplicate_rate: 0.3,
+        reorder_rate: 0.3,
+        ..SimConfig::default()
+    };
+    let run = || {
+        let mut leader = challenge::Node::seeded(&data, NodeType::Leader, 1);
+        let mut follower = challenge::Node::seeded(&data, NodeType::Follower, 2);
+        let report = run_simulated(&mut leader, &mut follower, &config);
+        (
+            report.delivered,
+            report.dropped,
+            report.duplicated,
+            report.elapsed,
+            format!("{:?} {:?}", report.leader, report.follower),
+        )
+    };
+    assert_eq!(run(), run());
+}
@@ -0,0 +1,113 @@
+//! Driver over a separate reader and writer, stdin and stdout by default, so two processes can be
+//! wired together with a pipe or `socat`.
+//!
+//! Nothing else may write to stdout while a session runs or the peer will read it as a frame, log to
+//! stderr instead. There's no timeout here, a pipe can't have one, wrap the session in something
+//! that can kill it if that matters
+
+use std::io::{self, Read, Stdin, Stdout, Write};
+
+use crate::protocol::Protocol;
+use crate::transport::{Role, TransportError, drive};
+
+/// A reader and a writer used as one stream, e.g. stdin and stdout or both ends of a child process
+pub struct Pipe<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Pipe<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Pipe { reader, writer }
+    }
+}
+
+impl Pipe<Stdin, Stdout> {
+    #[allow(unused)]
+    pub fn stdio() -> Self {
+        Pipe::new(io::stdin(), io::stdout())
+    }
+}
+
+impl<R: Read, W> Read for Pipe<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Pipe<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Run `node` over this process's stdin and stdout
+#[allow(unused)]
+pub fn run_stdio<P: Protocol>(node: &mut P, role: Role) -> Result<P::Output, TransportError> {
+    let mut pipe = Pipe::new(io::stdin().lock(), io::stdout().lock());
+    drive(&mut pipe, node, role)
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, NodeType},
+    simple,
+    transport::fix_array,
+};
+#[cfg(test)]
+use std::{collections::HashSet, thread};
+
+#[test]
+fn challenge_over_pipes() {
+    let (from_leader, to_follower) = io::pipe().unwrap();
+    let (from_follower, to_leader) = io::pipe().unwrap();
+
+    let follower = thread::spawn(move || {
+        let data = fix_array(&["b", "c", "d"]);
+        let mut node = challenge::Node::new(&data, NodeType::Follower);
+        drive(
+            &mut Pipe::new(from_leader, to_leader),
+            &mut node,
+            Role::Follower,
+        )
+    });
+
+    let data = fix_array(&["a", "b", "c"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = drive(
+        &mut Pipe::new(from_follower, to_follower),
+        &mut node,
+        Role::Leader,
+    );
+
+    let common = HashSet::from_iter(fix_array(&["b", "c"]));
+    assert_eq!(result.unwrap(), common);
+    assert_eq!(follower.join().unwrap().unwrap(), common);
+}
+
+#[test]
+fn writer_closed_early() {
+    let (from_leader, to_follower) = io::pipe().unwrap();
+    let (from_follower, to_leader) = io::pipe().unwrap();
+    // the follower's process exited before answering
+    drop((from_leader, to_leader));
+
+    let data = vec![1, 2];
+    let mut node = simple::NodeState::new(&data);
+    let result = drive(
+        &mut Pipe::new(from_follower, to_follower),
+        &mut node,
+        Role::Leader,
+    );
+
+    assert!(matches!(
+        result,
+        Err(TransportError::Io(_) | TransportError::Closed)
+    ));
+}
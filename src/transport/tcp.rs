@@ -0,0 +1,292 @@
+//! Blocking TCP driver. The leader connects, the follower listens and serves a single peer
+
+use std::{
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+use crate::protocol::Protocol;
+use crate::transport::{Role, TransportError, drive};
+
+/// How long either side waits on a read or write before giving up on the peer
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[allow(unused)]
+pub fn run_leader<P: Protocol>(
+    stream: TcpStream,
+    node: &mut P,
+) -> Result<P::Output, TransportError> {
+    run_leader_timeout(stream, node, DEFAULT_TIMEOUT)
+}
+
+#[allow(unused)]
+pub fn run_follower<P: Protocol>(
+    listener: &TcpListener,
+    node: &mut P,
+) -> Result<P::Output, TransportError> {
+    run_follower_timeout(listener, node, DEFAULT_TIMEOUT)
+}
+
+pub fn run_leader_timeout<P: Protocol>(
+    stream: TcpStream,
+    node: &mut P,
+    timeout: Duration,
+) -> Result<P::Output, TransportError> {
+    run(stream, node, Role::Leader, timeout)
+}
+
+/// Waits for the next connection on `listener` and runs the protocol with it
+pub fn run_follower_timeout<P: Protocol>(
+    listener: &TcpListener,
+    node: &mut P,
+    timeout: Duration,
+) -> Result<P::Output, TransportError> {
+    let (stream, _) = listener.accept()?;
+    run(stream, node, Role::Follower, timeout)
+}
+
+fn run<P: Protocol>(
+    mut stream: TcpStream,
+    node: &mut P,
+    role: Role,
+    timeout: Duration,
+) -> Result<P::Output, TransportError> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    // every message waits on a reply so don't hold small frames back
+    stream.set_nodelay(true)?;
+    drive(&mut stream, node, role)
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, NodeType},
+    error::ProtocolError,
+    simple,
+    transport::{fix_array, read_frame, write_frame},
+};
+#[cfg(test)]
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+    net::Shutdown,
+    thread,
+};
+
+/// Kills the connection instead of writing its `frames`th frame, as if the process died mid-run
+#[cfg(test)]
+struct Cut {
+    stream: TcpStream,
+    frames: usize,
+}
+
+#[cfg(test)]
+impl Read for Cut {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for Cut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.frames <= 1 {
+            self.stream.shutdown(Shutdown::Both)?;
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.stream.write(buf)
+    }
+
+    // every frame ends with a flush
+    fn flush(&mut self) -> io::Result<()> {
+        self.frames -= 1;
+        self.stream.flush()
+    }
+}
+
+/// Run a challenge session where `cut_leader` decides who dies before sending its `frame`th frame,
+/// then resume both nodes from their checkpoints over a new connection
+#[cfg(test)]
+fn killed_and_resumed(cut_leader: bool, frame: usize) -> HashSet<String> {
+    let data = fix_array(&["a", "b", "c", "d", "e"]);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let follower = thread::spawn(move || {
+        let data2 = fix_array(&["e", "c", "x", "a", "y"]);
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+        let frames = if cut_leader { usize::MAX } else { frame };
+        let mut node = challenge::Node::new(&data2, NodeType::Follower);
+        let result = drive(&mut Cut { stream, frames }, &mut node, Role::Follower);
+        assert!(result.is_err());
+        let checkpoint = node.checkpoint().expect("cut while querying");
+
+        let mut node = challenge::Node::resume(&data2, &checkpoint).unwrap();
+        run_follower(&listener, &mut node)
+    });
+
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+    let frames = if cut_leader { frame } else { usize::MAX };
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = drive(&mut Cut { stream, frames }, &mut node, Role::Leader);
+    assert!(result.is_err());
+    let checkpoint = node.checkpoint().expect("cut while querying");
+
+    let mut node = challenge::Node::resume(&data, &checkpoint).unwrap();
+    let result = run_leader(TcpStream::connect(address).unwrap(), &mut node).unwrap();
+    assert_eq!(follower.join().unwrap().unwrap(), result);
+    result
+}
+
+#[test]
+fn challenge_over_localhost() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let follower = thread::spawn(move || {
+        let data = fix_array(&["b", "c", "d"]);
+        let mut node = challenge::Node::new(&data, NodeType::Follower);
+        run_follower(&listener, &mut node)
+    });
+
+    let data = fix_array(&["a", "b", "c"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = run_leader(TcpStream::connect(address).unwrap(), &mut node).unwrap();
+
+    let common = HashSet::from_iter(fix_array(&["b", "c"]));
+    assert_eq!(result, common);
+    assert_eq!(follower.join().unwrap().unwrap(), common);
+}
+
+#[test]
+fn simple_over_localhost() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let follower = thread::spawn(move || {
+        let data = vec![8, 9];
+        let mut node = simple::NodeState::new(&data);
+        run_follower(&listener, &mut node)
+    });
+
+    let data = vec![7, 9, 10, 11];
+    let mut node = simple::NodeState::new(&data);
+    let result = run_leader(TcpStream::connect(address).unwrap(), &mut node).unwrap();
+
+    assert_eq!(result, vec![9]);
+    assert_eq!(follower.join().unwrap().unwrap(), vec![9]);
+}
+
+#[test]
+fn protocol_failure_reaches_both_sides() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // both think they lead
+    let follower = thread::spawn(move || {
+        let data = fix_array(&["a"]);
+        let mut node = challenge::Node::new(&data, NodeType::Leader);
+        run_follower(&listener, &mut node)
+    });
+
+    let data = fix_array(&["a"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = run_leader(TcpStream::connect(address).unwrap(), &mut node);
+
+    let cause = ProtocolError::unexpected("Idle", "Start");
+    assert!(matches!(
+        result,
+        Err(TransportError::Protocol(error)) if error == ProtocolError::peer(cause.clone())
+    ));
+    assert!(matches!(
+        follower.join().unwrap(),
+        Err(TransportError::Protocol(error)) if error == cause
+    ));
+}
+
+#[test]
+fn silent_peer_times_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // accepts but never answers
+    let peer = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_millis(500));
+        drop(stream);
+    });
+
+    let data = fix_array(&["a"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = run_leader_timeout(
+        TcpStream::connect(address).unwrap(),
+        &mut node,
+        Duration::from_millis(50),
+    );
+
+    assert!(matches!(result, Err(TransportError::TimedOut)));
+    peer.join().unwrap();
+}
+
+#[test]
+fn bad_frames_from_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let peer = thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        // a frame claiming to be huge
+        stream.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        // garbage that isn't a message
+        stream.write_all(&[2, 0, 0, 0, 9, 9]).unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        // a valid start, then hanging up without a terminal message
+        write_frame(
+            &mut stream,
+            &challenge::NodeMessage::Start { nonce: [0; 32] },
+        )
+        .unwrap();
+        let _: challenge::NodeMessage = read_frame(&mut stream).unwrap();
+    });
+
+    let data = fix_array(&["a"]);
+    let mut node = challenge::Node::new(&data, NodeType::Follower);
+    assert!(matches!(
+        run_follower(&listener, &mut node),
+        Err(TransportError::FrameTooLong(len)) if len == u32::MAX as usize
+    ));
+    let mut node = challenge::Node::new(&data, NodeType::Follower);
+    assert!(matches!(
+        run_follower(&listener, &mut node),
+        Err(TransportError::Decode(_))
+    ));
+    let mut node = challenge::Node::new(&data, NodeType::Follower);
+    assert!(matches!(
+        run_follower(&listener, &mut node),
+        Err(TransportError::Closed)
+    ));
+    peer.join().unwrap();
+}
+
+#[test]
+fn resume_after_connection_dies() {
+    let common = HashSet::from_iter(fix_array(&["a", "c", "e"]));
+    // anywhere from the first query until the last one's answer, from either side
+    for frame in 2..=6 {
+        assert_eq!(
+            killed_and_resumed(true, frame),
+            common,
+            "leader frame {frame}"
+        );
+        assert_eq!(
+            killed_and_resumed(false, frame),
+            common,
+            "follower frame {frame}"
+        );
+    }
+}
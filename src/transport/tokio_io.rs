@@ -0,0 +1,306 @@
+//! Async driver for tokio, same framing and loop as `drive` but over `AsyncRead + AsyncWrite`.
+//!
+//! Dropping a session's future (or losing a `select!`) cancels it between frames, the node is left
+//! unfinished with no outcome
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+
+use futures_util::{StreamExt, stream::FuturesUnordered};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+use crate::protocol::{Message, Protocol};
+use crate::transport::{MAX_FRAME_LEN, Role, TransportError, finish};
+use crate::wire::Wire;
+
+pub async fn write_frame_async<W, M>(stream: &mut W, message: &M) -> Result<(), TransportError>
+where
+    W: AsyncWrite + Unpin,
+    M: Wire,
+{
+    let bytes = message.encode();
+    stream
+        .write_all(&(bytes.len() as u32).to_le_bytes())
+        .await?;
+    stream.write_all(&bytes).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+pub async fn read_frame_async<R, M>(stream: &mut R) -> Result<M, TransportError>
+where
+    R: AsyncRead + Unpin,
+    M: Wire,
+{
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(TransportError::FrameTooLong(len));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes).await?;
+    M::decode(&bytes).map_err(TransportError::Decode)
+}
+
+/// Async version of `drive`, runs `node` until it's finished
+pub async fn drive_async<P, S>(
+    stream: &mut S,
+    node: &mut P,
+    role: Role,
+) -> Result<P::Output, TransportError>
+where
+    P: Protocol,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut outgoing = match role {
+        Role::Leader => Some(node.start()),
+        Role::Follower => None,
+    };
+    loop {
+        if let Some(message) = outgoing.take() {
+            write_frame_async(stream, &message).await?;
+        }
+        if node.is_finished() {
+            break;
+        }
+        let incoming: P::Message = read_frame_async(stream).await?;
+        let hung_up = incoming.is_terminal();
+        let reply = node.receive(incoming);
+        if !hung_up {
+            outgoing = Some(reply);
+        }
+    }
+    finish(node)
+}
+
+/// Gives up on a session with `TimedOut` if it isn't finished within `timeout`
+pub async fn with_timeout<T>(
+    timeout: Duration,
+    session: impl Future<Output = Result<T, TransportError>>,
+) -> Result<T, TransportError> {
+    tokio::time::timeout(timeout, session)
+        .await
+        .unwrap_or(Err(TransportError::TimedOut))
+}
+
+pub type SessionResult<P> = (SocketAddr, Result<<P as Protocol>::Output, TransportError>);
+
+/// Accept connections until this future is dropped, running a fresh follower from `new_node` on
+/// each one. Every finished session's result is sent to `results` with the peer's address.
+///
+/// Sessions run concurrently inside `serve` rather than as tasks of their own, so the nodes can
+/// borrow data that only lives as long as the call. Sessions that take longer than `timeout` end
+/// with `TimedOut`, and dropping `serve` cancels any still running. A connection that fails while
+/// being accepted is skipped, `serve` only returns if the listener itself is broken
+#[allow(unused)]
+pub async fn serve<P, F>(
+    listener: TcpListener,
+    mut new_node: F,
+    timeout: Duration,
+    results: mpsc::UnboundedSender<SessionResult<P>>,
+) -> io::Result<()>
+where
+    F: FnMut() -> P,
+    P: Protocol,
+{
+    let mut sessions = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (mut stream, address) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(error) if lost_connection(&error) => continue,
+                    // give running sessions a moment to finish and free a descriptor
+                    Err(error) if out_of_descriptors(&error) => {
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                    Err(error) => return Err(error),
+                };
+                let mut node = new_node();
+                let results = &results;
+                sessions.push(async move {
+                    let session = drive_async(&mut stream, &mut node, Role::Follower);
+                    let result = with_timeout(timeout, session).await;
+                    // nobody listening for results is fine, the session still ran
+                    let _ = results.send((address, result));
+                });
+            }
+            // moves every session along, dropping finished ones so the set doesn't grow forever
+            Some(()) = sessions.next(), if !sessions.is_empty() => {}
+        }
+    }
+}
+
+/// How long `serve` waits before accepting again after running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The connection went away before we could accept it, nothing wrong with the listener
+fn lost_connection(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// We, or the whole system, hit the limit on open files, or ran out of memory
+fn out_of_descriptors(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    if matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+        return true;
+    }
+    error.kind() == io::ErrorKind::OutOfMemory
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, NodeType},
+    error::ProtocolError,
+    transport::fix_array,
+};
+#[cfg(test)]
+use rand::{SeedableRng, rngs::StdRng};
+#[cfg(test)]
+use std::collections::HashSet;
+#[cfg(test)]
+use tokio::task::JoinSet;
+
+#[test]
+fn only_broken_listeners_stop_serving() {
+    for kind in [io::ErrorKind::ConnectionAborted, io::ErrorKind::Interrupted] {
+        assert!(lost_connection(&io::Error::from(kind)));
+    }
+    #[cfg(unix)]
+    assert!(out_of_descriptors(&io::Error::from_raw_os_error(
+        libc::EMFILE
+    )));
+    for fatal in [
+        io::Error::from(io::ErrorKind::InvalidInput),
+        io::Error::from(io::ErrorKind::PermissionDenied),
+    ] {
+        assert!(!lost_connection(&fatal) && !out_of_descriptors(&fatal));
+    }
+}
+
+#[tokio::test]
+async fn challenge_over_duplex() {
+    let (mut a, mut b) = tokio::io::duplex(64);
+    let data = fix_array(&["a", "b", "c"]);
+    let data2 = fix_array(&["c", "d", "a"]);
+    let mut leader = challenge::Node::new(&data, NodeType::Leader);
+    let mut follower = challenge::Node::new(&data2, NodeType::Follower);
+
+    let (r1, r2) = tokio::join!(
+        drive_async(&mut a, &mut leader, Role::Leader),
+        drive_async(&mut b, &mut follower, Role::Follower),
+    );
+
+    let common = HashSet::from_iter(fix_array(&["a", "c"]));
+    assert_eq!(r1.unwrap(), common);
+    assert_eq!(r2.unwrap(), common);
+}
+
+#[tokio::test]
+async fn failure_over_duplex() {
+    let (mut a, mut b) = tokio::io::duplex(64);
+    let data = fix_array(&["a"]);
+    let mut n1 = challenge::Node::new(&data, NodeType::Leader);
+    let mut n2 = challenge::Node::new(&data, NodeType::Leader);
+
+    let (r1, r2) = tokio::join!(
+        drive_async(&mut a, &mut n1, Role::Leader),
+        drive_async(&mut b, &mut n2, Role::Follower),
+    );
+
+    let cause = ProtocolError::unexpected("Idle", "Start");
+    assert!(matches!(r2, Err(TransportError::Protocol(e)) if e == cause));
+    assert!(matches!(r1, Err(TransportError::Protocol(e)) if e == ProtocolError::peer(cause)));
+}
+
+#[tokio::test]
+async fn cancelled_by_select() {
+    // the other end never answers
+    let (mut a, _b) = tokio::io::duplex(64);
+    let data = fix_array(&["a"]);
+    let mut leader = challenge::Node::new(&data, NodeType::Leader);
+
+    tokio::select! {
+        _ = drive_async(&mut a, &mut leader, Role::Leader) => panic!("peer never answered"),
+        _ = tokio::time::sleep(Duration::from_millis(20)) => {}
+    }
+
+    assert!(!leader.is_finished());
+    assert_eq!(leader.outcome(), None);
+}
+
+#[tokio::test]
+async fn peer_dropped_mid_session() {
+    let (mut a, b) = tokio::io::duplex(64);
+    let data = fix_array(&["a"]);
+    let mut leader = challenge::Node::new(&data, NodeType::Leader);
+    drop(b);
+
+    let result = drive_async(&mut a, &mut leader, Role::Leader).await;
+    assert!(matches!(
+        result,
+        Err(TransportError::Io(_) | TransportError::Closed)
+    ));
+
+    let session = std::future::pending::<Result<(), TransportError>>();
+    assert!(matches!(
+        with_timeout(Duration::from_millis(10), session).await,
+        Err(TransportError::TimedOut)
+    ));
+}
+
+#[tokio::test]
+async fn serves_concurrent_sessions() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let served = fix_array(&["a", "b", "c", "d"]);
+
+    let (results, mut finished) = mpsc::unbounded_channel();
+    let server = serve(
+        listener,
+        || challenge::Node::with_rng(&served, NodeType::Follower, StdRng::from_os_rng()),
+        Duration::from_secs(5),
+        results,
+    );
+
+    let mut leaders = JoinSet::new();
+    for i in 0..8 {
+        leaders.spawn(async move {
+            let data = fix_array(&["a", &format!("x{i}"), "d"]);
+            let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+            let mut node =
+                challenge::Node::with_rng(&data, NodeType::Leader, StdRng::seed_from_u64(i));
+            drive_async(&mut stream, &mut node, Role::Leader).await
+        });
+    }
+
+    let common = HashSet::from_iter(fix_array(&["a", "d"]));
+    let checked = async {
+        while let Some(result) = leaders.join_next().await {
+            assert_eq!(result.unwrap().unwrap(), common);
+        }
+        for _ in 0..8 {
+            let (_, result) = finished.recv().await.unwrap();
+            assert_eq!(result.unwrap(), common);
+        }
+    };
+    // the server only runs while it's polled, it's dropped once everything checked out
+    tokio::select! {
+        stopped = server => panic!("server stopped: {stopped:?}"),
+        () = checked => {}
+    }
+}
@@ -0,0 +1,112 @@
+//! Blocking Unix domain socket driver, same as the TCP one but for sidecars on the same host.
+//! The leader connects, the follower listens and serves a single peer
+
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    time::Duration,
+};
+
+use crate::protocol::Protocol;
+use crate::transport::{DEFAULT_TIMEOUT, Role, TransportError, drive};
+
+#[allow(unused)]
+pub fn run_leader_unix<P: Protocol>(
+    path: impl AsRef<Path>,
+    node: &mut P,
+) -> Result<P::Output, TransportError> {
+    let stream = UnixStream::connect(path)?;
+    run_unix(stream, node, Role::Leader, DEFAULT_TIMEOUT)
+}
+
+#[allow(unused)]
+pub fn run_follower_unix<P: Protocol>(
+    listener: &UnixListener,
+    node: &mut P,
+) -> Result<P::Output, TransportError> {
+    run_follower_unix_timeout(listener, node, DEFAULT_TIMEOUT)
+}
+
+/// Waits for the next connection on `listener` and runs the protocol with it
+pub fn run_follower_unix_timeout<P: Protocol>(
+    listener: &UnixListener,
+    node: &mut P,
+    timeout: Duration,
+) -> Result<P::Output, TransportError> {
+    let (stream, _) = listener.accept()?;
+    run_unix(stream, node, Role::Follower, timeout)
+}
+
+/// Run over an already connected socket, e.g. one half of `UnixStream::pair`
+pub fn run_unix<P: Protocol>(
+    mut stream: UnixStream,
+    node: &mut P,
+    role: Role,
+    timeout: Duration,
+) -> Result<P::Output, TransportError> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    drive(&mut stream, node, role)
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{
+    challenge::{self, NodeType},
+    simple,
+    transport::fix_array,
+};
+#[cfg(test)]
+use std::{collections::HashSet, fs, thread};
+
+#[test]
+fn challenge_over_named_socket() {
+    let path = std::env::temp_dir().join(format!("treehopper-{}.sock", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let follower = thread::spawn(move || {
+        let data = fix_array(&["b", "c", "d"]);
+        let mut node = challenge::Node::new(&data, NodeType::Follower);
+        run_follower_unix(&listener, &mut node)
+    });
+
+    let data = fix_array(&["a", "b", "c"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+    let result = run_leader_unix(&path, &mut node);
+    fs::remove_file(&path).unwrap();
+
+    let common = HashSet::from_iter(fix_array(&["b", "c"]));
+    assert_eq!(result.unwrap(), common);
+    assert_eq!(follower.join().unwrap().unwrap(), common);
+}
+
+#[test]
+fn simple_over_socket_pair() {
+    let (a, b) = UnixStream::pair().unwrap();
+
+    let follower = thread::spawn(move || {
+        let data = vec![8, 9];
+        let mut node = simple::NodeState::new(&data);
+        run_unix(b, &mut node, Role::Follower, DEFAULT_TIMEOUT)
+    });
+
+    let data = vec![7, 9, 10];
+    let mut node = simple::NodeState::new(&data);
+    let result = run_unix(a, &mut node, Role::Leader, DEFAULT_TIMEOUT);
+
+    assert_eq!(result.unwrap(), vec![9]);
+    assert_eq!(follower.join().unwrap().unwrap(), vec![9]);
+}
+
+#[test]
+fn silent_peer_times_out_unix() {
+    let (a, _b) = UnixStream::pair().unwrap();
+    let data = fix_array(&["a"]);
+    let mut node = challenge::Node::new(&data, NodeType::Leader);
+
+    let result = run_unix(a, &mut node, Role::Leader, Duration::from_millis(50));
+
+    assert!(matches!(result, Err(TransportError::TimedOut)));
+}
@@ -0,0 +1,963 @@
+//! Binary encoding for protocol messages so they can be sent over a socket.
+//!
+//! Every frame is `[version][tag][fields...]`. Fixed size fields (nonces, numbers, flags) are written
+//! as they are in little endian, variable ones (hashes, strings) get a `u32` length prefix first.
+//!
+//! Decoding is strict: a frame must use our `VERSION`, every field has to fit in `MAX_FIELD_LEN` and
+//! there can't be anything left over once the message has been read
+//!
+//! Each protocol has its own range of tags so a message from one can never decode as another's,
+//! and the multiplexer's envelope has its own around whichever message it carries. `Hello` lists
+//! protocols and hashes as a byte string of ids, unknown ones are dropped rather than failing.
+//! A challenge `Checkpoint` uses the same encoding so it can be saved to disk
+
+use std::{error::Error, fmt};
+
+use crate::challenge::{self, ChallengeReponsePair, Checkpoint, NodeType};
+use crate::error::ProtocolError;
+use crate::negotiate::{HashBackend, Hello, HelloAck, NegotiationError, ProtocolKind, Selected};
+use crate::simple;
+use crate::transport::Envelope;
+
+/// Sent first in every frame. A peer built with a different message layout uses another one, and
+/// its frames are refused instead of being misread. `Hello` and `HelloAck` are the exception, they
+/// keep the same layout in every version so peers on different ones can still find out
+pub const VERSION: u8 = 1;
+
+/// Largest hash or string we'll accept, nothing we send comes close
+pub const MAX_FIELD_LEN: usize = 64 * 1024;
+
+/// How many `PeerFailed` causes can be nested before we give up, keeps decoding from recursing forever
+const MAX_DEPTH: usize = 16;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    UnknownTag(u8),
+    /// ran out of bytes in the middle of a message
+    UnexpectedEnd,
+    /// the message was complete but this many bytes were left
+    TrailingBytes(usize),
+    FieldTooLong(usize),
+    /// the bytes were there but don't make a valid value, e.g. a string that isn't utf8
+    InvalidField(&'static str),
+    TooDeep,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wire version {version}")
+            }
+            DecodeError::UnknownTag(tag) => write!(f, "unknown message tag {tag:#04x}"),
+            DecodeError::UnexpectedEnd => write!(f, "message ended early"),
+            DecodeError::TrailingBytes(count) => {
+                write!(f, "{count} bytes left over after message")
+            }
+            DecodeError::FieldTooLong(len) => {
+                write!(f, "field of {len} bytes is over the {MAX_FIELD_LEN} limit")
+            }
+            DecodeError::InvalidField(field) => write!(f, "invalid {field}"),
+            DecodeError::TooDeep => write!(f, "too many nested failures"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Anything that can be put in a frame. Implementors only write and read their own fields,
+/// `encode` and `decode` take care of the version header and leftovers
+pub trait Wire: Sized {
+    fn write(&self, out: &mut Vec<u8>);
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError>;
+
+    /// Only decode frames sent with our `VERSION`
+    const VERSIONED: bool = true;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![VERSION];
+        self.write(&mut out);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut input = Reader::new(bytes);
+        let version = input.u8()?;
+        if Self::VERSIONED && version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let value = Self::read(&mut input)?;
+        match input.remaining() {
+            0 => Ok(value),
+            count => Err(DecodeError::TrailingBytes(count)),
+        }
+    }
+}
+
+/// Cursor over a frame being decoded
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, depth: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidField("bool")),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(u64::from_le_bytes(self.array()?))
+            .map_err(|_| DecodeError::InvalidField("index"))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.u32()? as usize;
+        if len > MAX_FIELD_LEN {
+            return Err(DecodeError::FieldTooLong(len));
+        }
+        self.take(len)
+    }
+
+    /// A byte string of `u32`s
+    fn u32s(&mut self) -> Result<Vec<u32>, DecodeError> {
+        let bytes = self.bytes()?;
+        if bytes.len() % 4 != 0 {
+            return Err(DecodeError::InvalidField("u32s"));
+        }
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect())
+    }
+
+    /// A byte string of bools, one a byte
+    fn bools(&mut self) -> Result<Vec<bool>, DecodeError> {
+        self.bytes()?
+            .iter()
+            .map(|byte| match byte {
+                0 => Ok(false),
+                1 => Ok(true),
+                _ => Err(DecodeError::InvalidField("bool")),
+            })
+            .collect()
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidField("string"))
+    }
+
+    /// Read something that can contain itself, like a failure caused by another failure
+    fn nested<T: Wire>(&mut self) -> Result<T, DecodeError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(DecodeError::TooDeep);
+        }
+        self.depth += 1;
+        let value = T::read(self);
+        self.depth -= 1;
+        value
+    }
+}
+
+fn put_usize(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+impl Wire for ProtocolError {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            ProtocolError::UnexpectedMessage { state, message } => {
+                out.push(0x01);
+                put_bytes(out, state.as_bytes());
+                put_bytes(out, message.as_bytes());
+            }
+            ProtocolError::VerificationFailed { index } => {
+                out.push(0x02);
+                put_usize(out, *index);
+            }
+            ProtocolError::PeerFailed(cause) => {
+                out.push(0x03);
+                cause.write(out);
+            }
+            ProtocolError::RoleMisuse { role, action } => {
+                out.push(0x04);
+                put_bytes(out, role.as_bytes());
+                put_bytes(out, action.as_bytes());
+            }
+            ProtocolError::SessionClosed => out.push(0x05),
+            ProtocolError::OutOfSequence { expected, received } => {
+                out.push(0x06);
+                put_usize(out, *expected);
+                put_usize(out, *received);
+            }
+            ProtocolError::ResumeMismatch => out.push(0x07),
+            ProtocolError::BatchTooLarge { limit, received } => {
+                out.push(0x08);
+                put_usize(out, *limit);
+                put_usize(out, *received);
+            }
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x01 => Ok(ProtocolError::UnexpectedMessage {
+                state: input.string()?,
+                message: input.string()?,
+            }),
+            0x02 => Ok(ProtocolError::VerificationFailed {
+                index: input.usize()?,
+            }),
+            0x03 => Ok(ProtocolError::peer(input.nested()?)),
+            0x04 => Ok(ProtocolError::RoleMisuse {
+                role: input.string()?,
+                action: input.string()?,
+            }),
+            0x05 => Ok(ProtocolError::SessionClosed),
+            0x06 => Ok(ProtocolError::OutOfSequence {
+                expected: input.usize()?,
+                received: input.usize()?,
+            }),
+            0x07 => Ok(ProtocolError::ResumeMismatch),
+            0x08 => Ok(ProtocolError::BatchTooLarge {
+                limit: input.usize()?,
+                received: input.usize()?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Wire for simple::NodeMessage {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            simple::NodeMessage::HasQuery { location, values } => {
+                out.push(0x01);
+                put_usize(out, *location);
+                let bytes: Vec<u8> = values
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect();
+                put_bytes(out, &bytes);
+            }
+            simple::NodeMessage::HasResponse { location, has } => {
+                out.push(0x02);
+                put_usize(out, *location);
+                let bytes: Vec<u8> = has.iter().map(|has| *has as u8).collect();
+                put_bytes(out, &bytes);
+            }
+            simple::NodeMessage::Fail(error) => {
+                out.push(0x03);
+                error.write(out);
+            }
+            simple::NodeMessage::End => out.push(0x04),
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x01 => Ok(simple::NodeMessage::HasQuery {
+                location: input.usize()?,
+                values: input.u32s()?,
+            }),
+            0x02 => Ok(simple::NodeMessage::HasResponse {
+                location: input.usize()?,
+                has: input.bools()?,
+            }),
+            0x03 => Ok(simple::NodeMessage::Fail(input.nested()?)),
+            0x04 => Ok(simple::NodeMessage::End),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Wire for challenge::NodeMessage {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            challenge::NodeMessage::Start { nonce } => {
+                out.push(0x11);
+                out.extend_from_slice(nonce);
+            }
+            challenge::NodeMessage::Initialize { nonce } => {
+                out.push(0x12);
+                out.extend_from_slice(nonce);
+            }
+            challenge::NodeMessage::ChallengeQuery { index, hash } => {
+                out.push(0x13);
+                put_usize(out, *index);
+                put_bytes(out, hash);
+            }
+            challenge::NodeMessage::ChallengeReponse { index, proof } => {
+                out.push(0x14);
+                put_usize(out, *index);
+                match proof {
+                    None => out.push(0),
+                    Some(pair) => {
+                        out.push(1);
+                        out.extend_from_slice(&pair.salt);
+                        put_bytes(out, &pair.hash);
+                    }
+                }
+            }
+            challenge::NodeMessage::Fail(error) => {
+                out.push(0x15);
+                error.write(out);
+            }
+            challenge::NodeMessage::Done => out.push(0x16),
+            challenge::NodeMessage::Resume {
+                session_id,
+                position,
+                transcript,
+            } => {
+                out.push(0x17);
+                out.extend_from_slice(session_id);
+                put_usize(out, *position);
+                out.extend_from_slice(transcript);
+            }
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x11 => Ok(challenge::NodeMessage::Start {
+                nonce: input.array()?,
+            }),
+            0x12 => Ok(challenge::NodeMessage::Initialize {
+                nonce: input.array()?,
+            }),
+            0x13 => Ok(challenge::NodeMessage::ChallengeQuery {
+                index: input.usize()?,
+                hash: input.bytes()?.to_vec(),
+            }),
+            0x14 => Ok(challenge::NodeMessage::ChallengeReponse {
+                index: input.usize()?,
+                proof: match input.bool()? {
+                    false => None,
+                    true => Some(ChallengeReponsePair {
+                        salt: input.array()?,
+                        hash: input.bytes()?.to_vec(),
+                    }),
+                },
+            }),
+            0x15 => Ok(challenge::NodeMessage::Fail(input.nested()?)),
+            0x16 => Ok(challenge::NodeMessage::Done),
+            0x17 => Ok(challenge::NodeMessage::Resume {
+                session_id: input.array()?,
+                position: input.usize()?,
+                transcript: input.array()?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl<M: Wire> Wire for Envelope<M> {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Envelope::Data { session, message } => {
+                out.push(0x21);
+                out.extend_from_slice(&session.to_le_bytes());
+                message.write(out);
+            }
+            Envelope::GoAway => out.push(0x22),
+            Envelope::Refused { session } => {
+                out.push(0x23);
+                out.extend_from_slice(&session.to_le_bytes());
+            }
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x21 => Ok(Envelope::Data {
+                session: input.u64()?,
+                message: M::read(input)?,
+            }),
+            0x22 => Ok(Envelope::GoAway),
+            0x23 => Ok(Envelope::Refused {
+                session: input.u64()?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+fn protocol_id(protocol: ProtocolKind) -> u8 {
+    match protocol {
+        ProtocolKind::Simple => 0x01,
+        ProtocolKind::Challenge => 0x02,
+    }
+}
+
+fn protocol_from_id(id: u8) -> Option<ProtocolKind> {
+    match id {
+        0x01 => Some(ProtocolKind::Simple),
+        0x02 => Some(ProtocolKind::Challenge),
+        _ => None,
+    }
+}
+
+fn hash_id(hash: HashBackend) -> u8 {
+    match hash {
+        HashBackend::Sha256 => 0x01,
+    }
+}
+
+fn hash_from_id(id: u8) -> Option<HashBackend> {
+    match id {
+        0x01 => Some(HashBackend::Sha256),
+        _ => None,
+    }
+}
+
+fn put_selected(out: &mut Vec<u8>, selected: &Selected) {
+    out.push(selected.version);
+    out.push(protocol_id(selected.protocol));
+    out.push(hash_id(selected.hash));
+    out.extend_from_slice(&selected.batch.to_le_bytes());
+}
+
+impl Reader<'_> {
+    /// Unlike in a `Hello` we can't skip an unknown id here, it's what was picked
+    fn selected(&mut self) -> Result<Selected, DecodeError> {
+        Ok(Selected {
+            version: self.u8()?,
+            protocol: protocol_from_id(self.u8()?).ok_or(DecodeError::InvalidField("protocol"))?,
+            hash: hash_from_id(self.u8()?).ok_or(DecodeError::InvalidField("hash"))?,
+            batch: self.u32()?,
+        })
+    }
+}
+
+impl Wire for Hello {
+    const VERSIONED: bool = false;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(0x31);
+        let protocols: Vec<u8> = self.protocols.iter().map(|p| protocol_id(*p)).collect();
+        let hashes: Vec<u8> = self.hashes.iter().map(|h| hash_id(*h)).collect();
+        put_bytes(out, &self.versions);
+        put_bytes(out, &protocols);
+        put_bytes(out, &hashes);
+        out.extend_from_slice(&self.max_batch.to_le_bytes());
+        put_bytes(out, self.normalization.as_bytes());
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x31 => Ok(Hello {
+                versions: input.bytes()?.to_vec(),
+                protocols: input
+                    .bytes()?
+                    .iter()
+                    .filter_map(|id| protocol_from_id(*id))
+                    .collect(),
+                hashes: input
+                    .bytes()?
+                    .iter()
+                    .filter_map(|id| hash_from_id(*id))
+                    .collect(),
+                max_batch: input.u32()?,
+                normalization: input.string()?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Wire for NegotiationError {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            NegotiationError::NoCommonProtocol => out.push(0x01),
+            NegotiationError::NoCommonHash => out.push(0x02),
+            NegotiationError::NoBatchSize => out.push(0x03),
+            NegotiationError::NotOffered(selected) => {
+                out.push(0x04);
+                put_selected(out, selected);
+            }
+            NegotiationError::PeerRejected(cause) => {
+                out.push(0x05);
+                cause.write(out);
+            }
+            NegotiationError::NormalizationMismatch { ours, theirs } => {
+                out.push(0x06);
+                put_bytes(out, ours.as_bytes());
+                put_bytes(out, theirs.as_bytes());
+            }
+            NegotiationError::NoCommonVersion { ours, theirs } => {
+                out.push(0x07);
+                put_bytes(out, ours);
+                put_bytes(out, theirs);
+            }
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x01 => Ok(NegotiationError::NoCommonProtocol),
+            0x02 => Ok(NegotiationError::NoCommonHash),
+            0x03 => Ok(NegotiationError::NoBatchSize),
+            0x04 => Ok(NegotiationError::NotOffered(input.selected()?)),
+            0x05 => Ok(NegotiationError::PeerRejected(Box::new(input.nested()?))),
+            0x06 => Ok(NegotiationError::NormalizationMismatch {
+                ours: input.string()?,
+                theirs: input.string()?,
+            }),
+            0x07 => Ok(NegotiationError::NoCommonVersion {
+                ours: input.bytes()?.to_vec(),
+                theirs: input.bytes()?.to_vec(),
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Wire for HelloAck {
+    const VERSIONED: bool = false;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            HelloAck::Accept(selected) => {
+                out.push(0x32);
+                put_selected(out, selected);
+            }
+            HelloAck::Reject(error) => {
+                out.push(0x33);
+                error.write(out);
+            }
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x32 => Ok(HelloAck::Accept(input.selected()?)),
+            0x33 => Ok(HelloAck::Reject(input.nested()?)),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Wire for Checkpoint {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(0x41);
+        out.push(match self.node_type {
+            NodeType::Leader => 0,
+            NodeType::Follower => 1,
+        });
+        out.extend_from_slice(&self.salt);
+        put_usize(out, self.data_index);
+        out.extend_from_slice(&(self.data_common.len() as u32).to_le_bytes());
+        for value in &self.data_common {
+            put_bytes(out, value.as_bytes());
+        }
+        out.extend_from_slice(&self.transcript);
+        match &self.last_response {
+            None => out.push(0),
+            Some(response) => {
+                out.push(1);
+                response.write(out);
+            }
+        }
+        out.extend_from_slice(&self.previous_transcript);
+        out.extend_from_slice(&self.data_digest);
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, DecodeError> {
+        match input.u8()? {
+            0x41 => Ok(Checkpoint {
+                node_type: match input.u8()? {
+                    0 => NodeType::Leader,
+                    1 => NodeType::Follower,
+                    _ => return Err(DecodeError::InvalidField("node type")),
+                },
+                salt: input.array()?,
+                data_index: input.usize()?,
+                data_common: (0..input.u32()?)
+                    .map(|_| input.string())
+                    .collect::<Result<_, _>>()?,
+                transcript: input.array()?,
+                last_response: match input.bool()? {
+                    false => None,
+                    true => Some(input.nested()?),
+                },
+                previous_transcript: input.array()?,
+                data_digest: input.array()?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+// tests
+
+#[allow(unused)]
+fn round_trip<T: Wire + PartialEq + fmt::Debug>(message: T) {
+    let bytes = message.encode();
+    assert_eq!(bytes[0], VERSION);
+    assert_eq!(T::decode(&bytes), Ok(message));
+}
+
+#[test]
+fn simple_round_trip() {
+    round_trip(simple::NodeMessage::HasQuery {
+        location: 3,
+        values: vec![u32::MAX, 0, 7],
+    });
+    round_trip(simple::NodeMessage::HasQuery {
+        location: 0,
+        values: vec![],
+    });
+    round_trip(simple::NodeMessage::HasResponse {
+        location: usize::MAX,
+        has: vec![true],
+    });
+    round_trip(simple::NodeMessage::HasResponse {
+        location: 0,
+        has: vec![false, true, false],
+    });
+    round_trip(simple::NodeMessage::Fail(ProtocolError::SessionClosed));
+    round_trip(simple::NodeMessage::End);
+}
+
+#[test]
+fn challenge_round_trip() {
+    round_trip(challenge::NodeMessage::Start { nonce: [1; 32] });
+    round_trip(challenge::NodeMessage::Initialize { nonce: [2; 32] });
+    round_trip(challenge::NodeMessage::ChallengeQuery {
+        index: 0,
+        hash: vec![3; 32],
+    });
+    round_trip(challenge::NodeMessage::ChallengeQuery {
+        index: 9,
+        hash: vec![],
+    });
+    round_trip(challenge::NodeMessage::ChallengeReponse {
+        index: 1,
+        proof: None,
+    });
+    round_trip(challenge::NodeMessage::ChallengeReponse {
+        index: usize::MAX,
+        proof: Some(ChallengeReponsePair {
+            salt: [4; 32],
+            hash: vec![5; 32],
+        }),
+    });
+    round_trip(challenge::NodeMessage::Resume {
+        session_id: [6; 32],
+        position: 3,
+        transcript: [7; 32],
+    });
+    round_trip(challenge::NodeMessage::Done);
+}
+
+#[test]
+fn error_round_trip() {
+    let errors = [
+        ProtocolError::unexpected("Idle", "Start"),
+        ProtocolError::VerificationFailed { index: 7 },
+        ProtocolError::peer(ProtocolError::peer(ProtocolError::role(
+            "Follower", "start",
+        ))),
+        ProtocolError::role("Leader", "ünïcode"),
+        ProtocolError::SessionClosed,
+        ProtocolError::OutOfSequence {
+            expected: 2,
+            received: 1,
+        },
+        ProtocolError::ResumeMismatch,
+        ProtocolError::BatchTooLarge {
+            limit: 64,
+            received: 65,
+        },
+    ];
+    for error in errors {
+        round_trip(challenge::NodeMessage::Fail(error.clone()));
+        round_trip(error);
+    }
+}
+
+#[test]
+fn rejects_malformed() {
+    let message = challenge::NodeMessage::ChallengeQuery {
+        index: 4,
+        hash: vec![3; 32],
+    };
+    let bytes = message.encode();
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(
+        challenge::NodeMessage::decode(&trailing),
+        Err(DecodeError::TrailingBytes(1))
+    );
+
+    for len in 0..bytes.len() {
+        assert_eq!(
+            challenge::NodeMessage::decode(&bytes[..len]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    let mut version = bytes.clone();
+    version[0] = VERSION + 1;
+    assert_eq!(
+        challenge::NodeMessage::decode(&version),
+        Err(DecodeError::UnsupportedVersion(VERSION + 1))
+    );
+
+    // tags from the other protocol aren't valid here
+    let simple_bytes = simple::NodeMessage::End.encode();
+    assert_eq!(
+        challenge::NodeMessage::decode(&simple_bytes),
+        Err(DecodeError::UnknownTag(0x04))
+    );
+
+    let mut bad_bool = challenge::NodeMessage::ChallengeReponse {
+        index: 0,
+        proof: None,
+    }
+    .encode();
+    bad_bool[10] = 2;
+    assert_eq!(
+        challenge::NodeMessage::decode(&bad_bool),
+        Err(DecodeError::InvalidField("bool"))
+    );
+
+    // a query's values have to be whole u32s
+    let mut ragged = vec![VERSION, 0x01];
+    put_usize(&mut ragged, 0);
+    put_bytes(&mut ragged, &[1, 2, 3]);
+    assert_eq!(
+        simple::NodeMessage::decode(&ragged),
+        Err(DecodeError::InvalidField("u32s"))
+    );
+}
+
+#[test]
+fn rejects_oversize_fields() {
+    let oversize = MAX_FIELD_LEN + 1;
+    let mut bytes = vec![VERSION, 0x13];
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&(oversize as u32).to_le_bytes());
+    bytes.resize(bytes.len() + oversize, 0);
+    assert_eq!(
+        challenge::NodeMessage::decode(&bytes),
+        Err(DecodeError::FieldTooLong(oversize))
+    );
+
+    // a huge length prefix is refused before we look for the bytes
+    let bytes = [
+        VERSION, 0x13, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff,
+    ];
+    assert_eq!(
+        challenge::NodeMessage::decode(&bytes),
+        Err(DecodeError::FieldTooLong(u32::MAX as usize))
+    );
+
+    let mut bad_utf8 = vec![VERSION, 0x15, 0x04, 1, 0, 0, 0, 0xff];
+    bad_utf8.extend_from_slice(&[0; 4]);
+    assert_eq!(
+        challenge::NodeMessage::decode(&bad_utf8),
+        Err(DecodeError::InvalidField("string"))
+    );
+}
+
+#[test]
+fn rejects_deep_nesting() {
+    let mut error = ProtocolError::SessionClosed;
+    for _ in 0..MAX_DEPTH - 1 {
+        error = ProtocolError::peer(error);
+    }
+    round_trip(challenge::NodeMessage::Fail(error.clone()));
+
+    let too_deep = challenge::NodeMessage::Fail(ProtocolError::peer(error));
+    assert_eq!(
+        challenge::NodeMessage::decode(&too_deep.encode()),
+        Err(DecodeError::TooDeep)
+    );
+}
+
+#[test]
+fn envelope_round_trip() {
+    round_trip(Envelope::Data {
+        session: u64::MAX,
+        message: challenge::NodeMessage::Done,
+    });
+    round_trip(Envelope::Data {
+        session: 3,
+        message: simple::NodeMessage::HasQuery {
+            location: 1,
+            values: vec![2],
+        },
+    });
+    round_trip(Envelope::<simple::NodeMessage>::GoAway);
+    round_trip(Envelope::<simple::NodeMessage>::Refused { session: 7 });
+
+    // a bare message isn't an envelope, or the other way around
+    assert_eq!(
+        Envelope::<simple::NodeMessage>::decode(&simple::NodeMessage::End.encode()),
+        Err(DecodeError::UnknownTag(0x04))
+    );
+    assert_eq!(
+        simple::NodeMessage::decode(&Envelope::<simple::NodeMessage>::GoAway.encode()),
+        Err(DecodeError::UnknownTag(0x22))
+    );
+}
+
+#[test]
+fn negotiation_round_trip() {
+    round_trip(Hello::supported());
+    round_trip(Hello {
+        versions: vec![],
+        protocols: vec![],
+        hashes: vec![],
+        max_batch: 0,
+        normalization: String::new(),
+    });
+    let selected = Selected {
+        version: 7,
+        protocol: ProtocolKind::Challenge,
+        hash: HashBackend::Sha256,
+        batch: 7,
+    };
+    round_trip(HelloAck::Accept(selected));
+    round_trip(HelloAck::Reject(NegotiationError::NoCommonHash));
+    round_trip(HelloAck::Reject(NegotiationError::NoBatchSize));
+    round_trip(HelloAck::Reject(NegotiationError::NoCommonVersion {
+        ours: vec![VERSION],
+        theirs: vec![VERSION + 1, VERSION + 2],
+    }));
+    round_trip(HelloAck::Reject(NegotiationError::NormalizationMismatch {
+        ours: "trim".to_string(),
+        theirs: "none".to_string(),
+    }));
+    round_trip(HelloAck::Reject(NegotiationError::PeerRejected(Box::new(
+        NegotiationError::NotOffered(selected),
+    ))));
+}
+
+#[test]
+fn hello_skips_unknown_ids() {
+    // a newer peer offering protocol 0x7f and hash 0x09 alongside ones we know
+    let mut bytes = vec![VERSION, 0x31];
+    put_bytes(&mut bytes, &[VERSION]);
+    put_bytes(&mut bytes, &[0x7f, 0x01]);
+    put_bytes(&mut bytes, &[0x09, 0x01]);
+    bytes.extend_from_slice(&8u32.to_le_bytes());
+    put_bytes(&mut bytes, b"none");
+    assert_eq!(
+        Hello::decode(&bytes),
+        Ok(Hello {
+            versions: vec![VERSION],
+            protocols: vec![ProtocolKind::Simple],
+            hashes: vec![HashBackend::Sha256],
+            max_batch: 8,
+            normalization: "none".to_string(),
+        })
+    );
+
+    // but an answer has to be something we know
+    let mut bytes = vec![VERSION, 0x32, VERSION, 0x7f, 0x01];
+    bytes.extend_from_slice(&8u32.to_le_bytes());
+    assert_eq!(
+        HelloAck::decode(&bytes),
+        Err(DecodeError::InvalidField("protocol"))
+    );
+}
+
+#[test]
+fn hello_from_another_version() {
+    // a newer peer's hello still decodes, so negotiation can say why it won't work
+    let theirs = Hello {
+        versions: vec![VERSION + 1],
+        ..Hello::supported()
+    };
+    let mut bytes = theirs.encode();
+    bytes[0] = VERSION + 1;
+    assert_eq!(Hello::decode(&bytes), Ok(theirs.clone()));
+
+    let mut bytes = Hello::supported().answer(&theirs).encode();
+    bytes[0] = VERSION + 1;
+    assert!(matches!(
+        HelloAck::decode(&bytes),
+        Ok(HelloAck::Reject(NegotiationError::NoCommonVersion { .. }))
+    ));
+
+    // everything else still has to be ours
+    let mut bytes = simple::NodeMessage::End.encode();
+    bytes[0] = VERSION + 1;
+    assert_eq!(
+        simple::NodeMessage::decode(&bytes),
+        Err(DecodeError::UnsupportedVersion(VERSION + 1))
+    );
+}
+
+#[test]
+fn checkpoint_round_trip() {
+    let checkpoint = Checkpoint {
+        node_type: NodeType::Follower,
+        salt: [1; 32],
+        data_index: 2,
+        data_common: vec!["a".to_string(), "ünïcode".to_string()],
+        transcript: [2; 32],
+        last_response: Some(challenge::NodeMessage::ChallengeReponse {
+            index: 1,
+            proof: None,
+        }),
+        previous_transcript: [3; 32],
+        data_digest: [4; 32],
+    };
+    round_trip(checkpoint.clone());
+    round_trip(Checkpoint {
+        node_type: NodeType::Leader,
+        data_common: vec![],
+        last_response: None,
+        ..checkpoint
+    });
+}
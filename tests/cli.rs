@@ -0,0 +1,119 @@
+//! The binary end to end, two processes finding their intersection over each local transport
+
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+const TREEHOPPER: &str = env!("CARGO_BIN_EXE_treehopper");
+
+/// Same as the one in `cli`'s unit tests, integration tests can't reach it
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(test: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("treehopper-{test}-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn file(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn succeeded(output: Output) -> Output {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[cfg(unix)]
+#[test]
+fn over_a_unix_socket() {
+    let dir = TempDir::new("cli-unix");
+    let ours = dir.file("a.txt", "a\nb\nc\n");
+    let theirs = dir.file("b.txt", "b\nc\nd\n");
+    let socket = dir.0.join("socket");
+
+    let mut server = Command::new(TREEHOPPER)
+        .arg("serve")
+        .arg("--input")
+        .arg(&ours)
+        .arg("--unix")
+        .arg(&socket)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // connecting before the socket is bound would fail
+    let mut listening = String::new();
+    BufReader::new(server.stderr.take().unwrap())
+        .read_line(&mut listening)
+        .unwrap();
+    assert!(listening.starts_with("listening on"), "{listening}");
+
+    let client = Command::new(TREEHOPPER)
+        .arg("connect")
+        .arg("--input")
+        .arg(&theirs)
+        .arg("--unix")
+        .arg(&socket)
+        .output()
+        .unwrap();
+    let client = succeeded(client);
+    let server = succeeded(server.wait_with_output().unwrap());
+
+    assert_eq!(String::from_utf8_lossy(&client.stdout), "b\nc\n");
+    assert_eq!(String::from_utf8_lossy(&server.stdout), "b\nc\n");
+    assert!(!socket.exists());
+}
+
+#[test]
+fn over_stdin_and_stdout() {
+    let dir = TempDir::new("cli-stdio");
+    let ours = dir.file("a.txt", "a\nb\nc\n");
+    let theirs = dir.file("b.txt", "b\nc\nd\n");
+
+    // each one's stdout is the other's stdin
+    let (from_server, to_client) = std::io::pipe().unwrap();
+    let (from_client, to_server) = std::io::pipe().unwrap();
+    let server = Command::new(TREEHOPPER)
+        .arg("serve")
+        .arg("--input")
+        .arg(&ours)
+        .arg("--stdio")
+        .stdin(from_client)
+        .stdout(to_client)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let client = Command::new(TREEHOPPER)
+        .arg("connect")
+        .arg("--input")
+        .arg(&theirs)
+        .arg("--stdio")
+        .stdin(from_server)
+        .stdout(to_server)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let client = succeeded(client.wait_with_output().unwrap());
+    let server = succeeded(server.wait_with_output().unwrap());
+    assert_eq!(String::from_utf8_lossy(&client.stderr), "b\nc\n");
+    assert_eq!(String::from_utf8_lossy(&server.stderr), "b\nc\n");
+}